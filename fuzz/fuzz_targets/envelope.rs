@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use secure_native::envelope::Envelope;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(envelope) = Envelope::decode(data) {
+        assert_eq!(envelope.encode(), data);
+    }
+});
@@ -0,0 +1,549 @@
+//! C header for the iOS exports of `secure-native`
+//!
+//! Reads the Rust sources of an application and declares every function
+//! exported with `#[secure_native::export]` or one of the `export_*!`
+//! macros, every destructor defined with `define_cresult_destructor!`, the
+//! `CResult` struct of each value type and the buffer types and functions
+//! of the library itself. Call it from the application's `build.rs`:
+//!
+//! ```no_run
+//! println!("cargo:rerun-if-changed=src");
+//! let header = secure_native_header::generate(&["src"]).unwrap();
+//! std::fs::write("include/secure_native.h", header).unwrap();
+//! ```
+//!
+//! Items in `#[cfg(test)]` modules are skipped. Types are recognised by
+//! name, so only the `Argument` and `Return` types of `secure-native` can be
+//! declared.
+
+use quote::ToTokens;
+use secure_native_ops::Input;
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    path::{Path, PathBuf},
+};
+use syn::{
+    parse::{Parse, ParseStream},
+    Attribute, FnArg, GenericArgument, Ident, Item, ItemFn, Lit, Meta, NestedMeta, Pat,
+    PathArguments, ReturnType, Signature, Token, Type,
+};
+
+/// Error reading the sources.
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// The source doesn't parse, or an export can't be declared in C
+    Source {
+        path: PathBuf,
+        message: String,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            Error::Source { path, message } => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The header for the exports in `paths`, Rust files or directories searched
+/// for them.
+pub fn generate<P: AsRef<Path>>(paths: &[P]) -> Result<String, Error> {
+    let mut files = Vec::new();
+    for path in paths {
+        find_sources(path.as_ref(), &mut files)?;
+    }
+    let mut header = Header::default();
+    for file in &files {
+        let source = fs::read_to_string(file).map_err(|error| Error::Io {
+            path: file.clone(),
+            error,
+        })?;
+        header
+            .add_source(&source)
+            .map_err(|message| Error::Source {
+                path: file.clone(),
+                message,
+            })?;
+    }
+    Ok(header.to_string())
+}
+
+/// The header for the exports in a single Rust source.
+pub fn generate_from_source(source: &str) -> Result<String, String> {
+    let mut header = Header::default();
+    header.add_source(source)?;
+    Ok(header.to_string())
+}
+
+fn find_sources(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let io_error = |error| Error::Io {
+        path: path.to_path_buf(),
+        error,
+    };
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .map_err(io_error)?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() || entry.extension().is_some_and(|ext| ext == "rs") {
+            find_sources(&entry, files)?;
+        }
+    }
+    Ok(())
+}
+
+const STRING: &str = "const char *";
+const BUFFER: &str = "CBuffer";
+
+/// C type of the FFI argument of an operation's input
+fn input_type(input: Input) -> Result<&'static str, String> {
+    argument(&syn::parse_str(input.argument()).map_err(|e| e.to_string())?)
+}
+
+/// C type of the value in a `CResult`, the `Ext` of a `Return` type
+#[derive(Clone, Copy, PartialEq)]
+enum Value {
+    Void,
+    Bool,
+    String,
+    Buffer,
+    StringArray,
+}
+
+const VALUES: &[Value] = &[
+    Value::Void,
+    Value::Bool,
+    Value::String,
+    Value::Buffer,
+    Value::StringArray,
+];
+
+impl Value {
+    fn c_type(self) -> &'static str {
+        match self {
+            Value::Void => "void *",
+            Value::Bool => "uint8_t *",
+            Value::String => "char *",
+            Value::Buffer => "CBuffer ",
+            Value::StringArray => "CStringArray ",
+        }
+    }
+
+    fn result_name(self) -> &'static str {
+        match self {
+            Value::Void => "CResultVoid",
+            Value::Bool => "CResultBool",
+            Value::String => "CResultString",
+            Value::Buffer => "CResultBuffer",
+            Value::StringArray => "CResultStringArray",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Header {
+    /// Declarations of the exports, in source order
+    declarations: Vec<String>,
+}
+
+impl Header {
+    fn add_source(&mut self, source: &str) -> Result<(), String> {
+        let file = syn::parse_file(source).map_err(|e| e.to_string())?;
+        self.add_items(&file.items)
+    }
+
+    fn add_items(&mut self, items: &[Item]) -> Result<(), String> {
+        for item in items {
+            match item {
+                Item::Fn(function) => {
+                    if let Some(op) = export_op(&function.attrs)? {
+                        self.add_export(&op, &function.sig)?;
+                    }
+                }
+                Item::Macro(item) => {
+                    let name = match item.mac.path.segments.last() {
+                        Some(segment) => segment.ident.to_string(),
+                        None => continue,
+                    };
+                    if name == "define_cresult_destructor" {
+                        let destructor: Destructor =
+                            item.mac.parse_body().map_err(|e| e.to_string())?;
+                        self.declarations.push(format!(
+                            "void {}({} *x);",
+                            destructor.name,
+                            return_value(&destructor.ty)?.result_name()
+                        ));
+                    } else if let Some(kind) = name.strip_prefix("export_") {
+                        let exports: MacroExports =
+                            item.mac.parse_body().map_err(|e| e.to_string())?;
+                        for function in &exports.0 {
+                            self.add_export(kind, &function.sig)?;
+                        }
+                    }
+                }
+                Item::Mod(module) if !is_cfg_test(&module.attrs) => {
+                    if let Some((_, items)) = &module.content {
+                        self.add_items(items)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn add_export(&mut self, op: &str, sig: &Signature) -> Result<(), String> {
+        let inputs =
+            secure_native_ops::inputs(op).ok_or_else(|| format!("Unknown operation `{}`", op))?;
+        let mut params = vec!["unsigned int *err".to_string()];
+        for input in inputs {
+            params.push(format!("{}{}", spaced(input_type(*input)?), input.name()));
+        }
+        for param in sig.inputs.iter().skip(1) {
+            let typed = match param {
+                FnArg::Typed(typed) => typed,
+                FnArg::Receiver(_) => return Err(format!("`{}` is a method", sig.ident)),
+            };
+            let name = match &*typed.pat {
+                Pat::Ident(pat) => pat.ident.to_string(),
+                _ => return Err(format!("`{}` has an unnamed parameter", sig.ident)),
+            };
+            params.push(format!("{}{}", spaced(argument(&typed.ty)?), name));
+        }
+        let value = match &sig.output {
+            ReturnType::Default => Value::Void,
+            ReturnType::Type(_, ty) => return_value(ty)?,
+        };
+        self.declarations.push(format!(
+            "{} *{}({});",
+            value.result_name(),
+            sig.ident,
+            params.join(", ")
+        ));
+        Ok(())
+    }
+}
+
+impl Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "/* Generated by secure-native-header. Do not edit. */")?;
+        writeln!(f)?;
+        writeln!(f, "#ifndef SECURE_NATIVE_H")?;
+        writeln!(f, "#define SECURE_NATIVE_H")?;
+        writeln!(f)?;
+        writeln!(f, "#include <stddef.h>")?;
+        writeln!(f, "#include <stdint.h>")?;
+        writeln!(f)?;
+        writeln!(f, "#ifdef __cplusplus")?;
+        writeln!(f, "extern \"C\" {{")?;
+        writeln!(f, "#endif")?;
+        writeln!(f)?;
+        writeln!(f, "{}", PRELUDE)?;
+        for value in VALUES {
+            writeln!(
+                f,
+                "typedef struct {name} {{\n    {ty}value;\n    int32_t error_code;\n    char *error_msg;\n}} {name};\n",
+                name = value.result_name(),
+                ty = value.c_type()
+            )?;
+        }
+        for declaration in &self.declarations {
+            writeln!(f, "{}", declaration)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "#ifdef __cplusplus")?;
+        writeln!(f, "}}")?;
+        writeln!(f, "#endif")?;
+        writeln!(f)?;
+        writeln!(f, "#endif /* SECURE_NATIVE_H */")
+    }
+}
+
+/// Buffer types and functions defined by `secure_native::ios`
+const PRELUDE: &str = "\
+typedef struct CBuffer {
+    uint8_t *data;
+    size_t len;
+} CBuffer;
+
+typedef struct CStringArray {
+    char **data;
+    size_t len;
+} CStringArray;
+
+void secure_native_buffer_free(CBuffer buf);
+void secure_native_secret_string_free(char *s);
+void secure_native_string_array_free(CStringArray array);
+";
+
+/// `op` of a `#[secure_native::export(..)]` attribute.
+fn export_op(attrs: &[Attribute]) -> Result<Option<String>, String> {
+    for attr in attrs {
+        let segments: Vec<_> = attr
+            .path
+            .segments
+            .iter()
+            .map(|s| s.ident.to_string())
+            .collect();
+        if segments != ["export"] && segments != ["secure_native", "export"] {
+            continue;
+        }
+        let args = match attr.parse_meta().map_err(|e| e.to_string())? {
+            Meta::List(list) => list.nested,
+            _ => return Err("Expected `export(op = \"..\", ..)`".to_string()),
+        };
+        for arg in args {
+            if let NestedMeta::Meta(Meta::NameValue(pair)) = arg {
+                if let (true, Lit::Str(op)) = (pair.path.is_ident("op"), &pair.lit) {
+                    return Ok(Some(op.value()));
+                }
+            }
+        }
+        return Err("Missing `op`".to_string());
+    }
+    Ok(None)
+}
+
+fn is_cfg_test(attrs: &[Attribute]) -> bool {
+    attrs.iter().any(|attr| {
+        attr.path.is_ident("cfg")
+            && match attr.parse_meta() {
+                Ok(Meta::List(list)) => list.nested.iter().any(|nested| match nested {
+                    NestedMeta::Meta(Meta::Path(path)) => path.is_ident("test"),
+                    _ => false,
+                }),
+                _ => false,
+            }
+    })
+}
+
+/// C type of the `Ext` of an `Argument` type
+fn argument(ty: &Type) -> Result<&'static str, String> {
+    let (name, _) = type_name(ty)?;
+    match name.as_str() {
+        "u32" | "u8" => Ok("uint32_t"),
+        "bool" => Ok("uint8_t"),
+        "String" | "SecretString" | "Json" => Ok(STRING),
+        "SecretBytes" => Ok(BUFFER),
+        "Vec" if is_vec_of(ty, "u8") => Ok(BUFFER),
+        _ => Err(unknown(ty)),
+    }
+}
+
+/// Value type of a `Return` type
+fn return_value(ty: &Type) -> Result<Value, String> {
+    if let Type::Tuple(tuple) = ty {
+        if tuple.elems.is_empty() {
+            return Ok(Value::Void);
+        }
+    }
+    let (name, inner) = type_name(ty)?;
+    match name.as_str() {
+        "bool" => Ok(Value::Bool),
+        "String" | "SecretString" | "Json" => Ok(Value::String),
+        "SecretBytes" => Ok(Value::Buffer),
+        "Vec" if is_vec_of(ty, "u8") => Ok(Value::Buffer),
+        "Vec" if is_vec_of(ty, "String") => Ok(Value::StringArray),
+        "Result" | "Option" => match inner {
+            Some(inner) => return_value(inner),
+            None => Err(unknown(ty)),
+        },
+        _ => Err(unknown(ty)),
+    }
+}
+
+/// Last segment of a type path and its first type argument
+fn type_name(ty: &Type) -> Result<(String, Option<&Type>), String> {
+    let segment = match ty {
+        Type::Path(path) => path.path.segments.last(),
+        _ => None,
+    }
+    .ok_or_else(|| unknown(ty))?;
+    let inner = match &segment.arguments {
+        PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        }),
+        _ => None,
+    };
+    Ok((segment.ident.to_string(), inner))
+}
+
+fn is_vec_of(ty: &Type, element: &str) -> bool {
+    match type_name(ty) {
+        Ok((_, Some(inner))) => type_name(inner).is_ok_and(|(name, _)| name == element),
+        _ => false,
+    }
+}
+
+fn unknown(ty: &Type) -> String {
+    format!("`{}` has no known C type", ty.to_token_stream())
+}
+
+/// `ty` followed by a space unless it ends in `*`
+fn spaced(ty: &str) -> String {
+    if ty.ends_with('*') {
+        ty.to_string()
+    } else {
+        format!("{} ", ty)
+    }
+}
+
+/// Body of an `export_*!` invocation
+struct MacroExports(Vec<ItemFn>);
+
+impl Parse for MacroExports {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut functions = Vec::new();
+        while !input.is_empty() {
+            input.parse::<Token![@]>()?;
+            input.parse::<Ident>()?;
+            functions.push(input.parse()?);
+        }
+        Ok(MacroExports(functions))
+    }
+}
+
+/// Arguments of `define_cresult_destructor!`
+struct Destructor {
+    name: Ident,
+    ty: Type,
+}
+
+impl Parse for Destructor {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let ty = input.parse()?;
+        Ok(Destructor { name, ty })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process::Command};
+
+    const SOURCE: &str = r#"
+        export_get! {
+            @Java_io_parity_Seeds_get
+            fn get_seed(seed: Result<SecretString, Error>, index: u32) -> Result<SecretString, Error> {
+                seed
+            }
+        }
+
+        secure_native::export_replace_if_equals! {
+            @Java_io_parity_Seeds_replace
+            fn replace_seed(replaced: Result<bool, Error>) -> Result<bool, Error> {
+                replaced
+            }
+        }
+
+        #[secure_native::export(op = "list", java = "io.parity.Seeds")]
+        fn seed_names(names: Result<Vec<AccountInfo>, Error>, note: Json<Note>) -> Option<Vec<String>> {
+            None
+        }
+
+        #[export(op = "put_bytes", java = "io.parity.Seeds")]
+        fn put_seed(stored: Result<(), Error>, flag: bool, salt: Vec<u8>) -> Result<(), Error> {
+            stored
+        }
+
+        #[export(op = "put_typed", java = "io.parity.Seeds", value = "Tokens")]
+        fn put_tokens(stored: Result<(), Error>) -> Result<(), Error> {
+            stored
+        }
+
+        define_cresult_destructor!(destroy_string_result, Result<String, Error>);
+
+        #[cfg(test)]
+        mod tests {
+            export_wipe! {
+                @Java_io_parity_Seeds_wipe
+                fn wipe_test(remaining: Result<Vec<AccountInfo>, Error>) -> () {}
+            }
+        }
+    "#;
+
+    #[test]
+    fn test_declarations() {
+        let header = generate_from_source(SOURCE).unwrap();
+        for declaration in &[
+            "CResultString *get_seed(unsigned int *err, const char *app, const char *key, uint32_t index);",
+            "CResultBool *replace_seed(unsigned int *err, const char *app, const char *key, CBuffer expected, CBuffer value, uint32_t policy);",
+            "CResultStringArray *seed_names(unsigned int *err, const char *app, const char *note);",
+            "CResultVoid *put_seed(unsigned int *err, const char *app, const char *key, CBuffer value, uint32_t policy, uint8_t flag, CBuffer salt);",
+            "CResultVoid *put_tokens(unsigned int *err, const char *app, const char *key, const char *value, uint32_t schema, uint32_t policy);",
+            "void destroy_string_result(CResultString *x);",
+        ] {
+            assert!(header.contains(declaration), "{}\n{}", declaration, header);
+        }
+        assert!(!header.contains("wipe_test"));
+
+        let error = generate_from_source(
+            "export_get! { @J fn f(x: Result<SecretString, Error>, y: f64) -> () {} }",
+        )
+        .unwrap_err();
+        assert_eq!(error, "`f64` has no known C type");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_header_compiles() {
+        let dir = env::temp_dir().join(format!("secure-native-header-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("secure_native.h"),
+            generate_from_source(SOURCE).unwrap(),
+        )
+        .unwrap();
+        // Including it twice checks the include guard
+        fs::write(
+            dir.join("main.c"),
+            "#include \"secure_native.h\"\n\
+             #include \"secure_native.h\"\n\
+             int main(void) {\n\
+                 CResultString *(*get)(unsigned int *, const char *, const char *, uint32_t) = get_seed;\n\
+                 void (*destroy)(CResultString *) = destroy_string_result;\n\
+                 return get == 0 || destroy == 0;\n\
+             }\n",
+        )
+        .unwrap();
+        let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+        let output = Command::new(cc)
+            .args([
+                "-std=c99",
+                "-Wall",
+                "-Wextra",
+                "-Werror",
+                "-pedantic",
+                "-fsyntax-only",
+            ])
+            .arg(dir.join("main.c"))
+            .output()
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+}
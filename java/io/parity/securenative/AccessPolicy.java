@@ -0,0 +1,28 @@
+package io.parity.securenative;
+
+/**
+ * Bits of the {@code policy} argument taken by the put functions, mirroring
+ * {@code secure_native::AccessPolicy::to_bits}.
+ *
+ * Combine at most one accessibility value with any number of constraints,
+ * and add {@link #OR} to accept any one constraint instead of all of them:
+ * {@code BIOMETRY_ANY | DEVICE_PASSCODE | OR}. Zero requires no
+ * authentication and makes the item readable while the device is unlocked.
+ */
+public final class AccessPolicy {
+    public static final int BIOMETRY_CURRENT_SET = 1 << 0;
+    public static final int BIOMETRY_ANY = 1 << 1;
+    public static final int USER_PRESENCE = 1 << 2;
+    public static final int DEVICE_PASSCODE = 1 << 3;
+    public static final int APPLICATION_PASSWORD = 1 << 4;
+
+    public static final int OR = 1 << 7;
+
+    public static final int WHEN_UNLOCKED_THIS_DEVICE_ONLY = 0 << 8;
+    public static final int WHEN_UNLOCKED = 1 << 8;
+    public static final int AFTER_FIRST_UNLOCK_THIS_DEVICE_ONLY = 2 << 8;
+    public static final int AFTER_FIRST_UNLOCK = 3 << 8;
+    public static final int WHEN_PASSCODE_SET_THIS_DEVICE_ONLY = 4 << 8;
+
+    private AccessPolicy() {}
+}
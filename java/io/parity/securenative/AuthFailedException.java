@@ -0,0 +1,8 @@
+package io.parity.securenative;
+
+/** The user or the supplied credentials failed authentication. */
+public class AuthFailedException extends SecureNativeException {
+    public AuthFailedException(String message) {
+        super(message, AUTH_FAILED);
+    }
+}
@@ -0,0 +1,8 @@
+package io.parity.securenative;
+
+/** An item already exists for the service and account. */
+public class DuplicateItemException extends SecureNativeException {
+    public DuplicateItemException(String message) {
+        super(message, DUPLICATE_ITEM);
+    }
+}
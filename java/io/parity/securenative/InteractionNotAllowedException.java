@@ -0,0 +1,8 @@
+package io.parity.securenative;
+
+/** The item cannot be accessed without user interaction right now. */
+public class InteractionNotAllowedException extends SecureNativeException {
+    public InteractionNotAllowedException(String message) {
+        super(message, INTERACTION_NOT_ALLOWED);
+    }
+}
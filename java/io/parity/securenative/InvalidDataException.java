@@ -0,0 +1,8 @@
+package io.parity.securenative;
+
+/** Stored data could not be decoded. */
+public class InvalidDataException extends SecureNativeException {
+    public InvalidDataException(String message) {
+        super(message, INVALID_DATA);
+    }
+}
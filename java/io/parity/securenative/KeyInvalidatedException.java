@@ -0,0 +1,8 @@
+package io.parity.securenative;
+
+/** The key protecting the item is no longer usable; the item has to be written again. */
+public class KeyInvalidatedException extends SecureNativeException {
+    public KeyInvalidatedException(String message) {
+        super(message, KEY_INVALIDATED);
+    }
+}
@@ -0,0 +1,8 @@
+package io.parity.securenative;
+
+/** No item is stored for the requested service and account. */
+public class NotFoundException extends SecureNativeException {
+    public NotFoundException(String message) {
+        super(message, NOT_FOUND);
+    }
+}
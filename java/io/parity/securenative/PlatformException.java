@@ -0,0 +1,8 @@
+package io.parity.securenative;
+
+/** A platform error which has no more specific kind. */
+public class PlatformException extends SecureNativeException {
+    public PlatformException(String message) {
+        super(message, PLATFORM);
+    }
+}
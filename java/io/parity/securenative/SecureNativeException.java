@@ -0,0 +1,38 @@
+package io.parity.securenative;
+
+/**
+ * Base class of every exception thrown by the secure-native library.
+ *
+ * Subclasses identify the kind of failure; {@link #getCode()} returns the
+ * same stable numeric code that iOS callers receive in
+ * {@code CResult.error_code}. Failures without a more specific kind are
+ * thrown as a plain {@code SecureNativeException} with {@link #OTHER}.
+ */
+public class SecureNativeException extends Exception {
+    public static final int OTHER = 1;
+    public static final int NOT_FOUND = 2;
+    public static final int AUTH_FAILED = 3;
+    public static final int USER_CANCELED = 4;
+    public static final int INTERACTION_NOT_ALLOWED = 5;
+    public static final int KEY_INVALIDATED = 6;
+    public static final int DUPLICATE_ITEM = 7;
+    public static final int INVALID_DATA = 8;
+    public static final int IO = 9;
+    public static final int PLATFORM = 10;
+    public static final int UNSUPPORTED = 11;
+
+    private final int code;
+
+    public SecureNativeException(String message) {
+        this(message, OTHER);
+    }
+
+    protected SecureNativeException(String message, int code) {
+        super(message);
+        this.code = code;
+    }
+
+    public int getCode() {
+        return code;
+    }
+}
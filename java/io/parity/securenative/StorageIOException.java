@@ -0,0 +1,8 @@
+package io.parity.securenative;
+
+/** Reading or writing the underlying storage failed. */
+public class StorageIOException extends SecureNativeException {
+    public StorageIOException(String message) {
+        super(message, IO);
+    }
+}
@@ -0,0 +1,8 @@
+package io.parity.securenative;
+
+/** The request cannot be honoured on this platform. */
+public class UnsupportedException extends SecureNativeException {
+    public UnsupportedException(String message) {
+        super(message, UNSUPPORTED);
+    }
+}
@@ -0,0 +1,8 @@
+package io.parity.securenative;
+
+/** The user dismissed the authentication prompt. */
+public class UserCanceledException extends SecureNativeException {
+    public UserCanceledException(String message) {
+        super(message, USER_CANCELED);
+    }
+}
@@ -0,0 +1,398 @@
+//! Procedural macros for `secure-native`, re-exported by it.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use secure_native_ops::{Input, OPS};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, parse_quote,
+    spanned::Spanned,
+    AttributeArgs, FnArg, Ident, ItemFn, Lit, Meta, NestedMeta, Pat, ReturnType, Token, Type,
+};
+
+/// Export a function consuming the result of a store operation to Java and
+/// to C.
+///
+/// ```ignore
+/// #[secure_native::export(op = "get", java = "io.parity.Seeds")]
+/// fn get_seed(seed: Result<SecretString, Error>, index: u32) -> Result<SecretString, Error> {
+///     seed
+/// }
+/// ```
+///
+/// The first parameter receives the result of the operation, called with
+/// the item names (and value, schema and policy, where it takes them)
+/// passed by the caller; the remaining parameters are passed through. Their
+/// types and the return type can be anything implementing `Argument` and
+/// `Return`.
+///
+/// A module named after the function holds the entry points:
+///
+/// * On Android, `Java_<class>_<method>` for a static native method of
+///   `java`, named after the function unless `method` is given.
+/// * On iOS, `<name>`, returning a `CResult`.
+/// * On Linux, `<name>`, taking Rust types and using the default `FileStore`.
+///
+/// `op` names the `SecureStore` method called: `put`, `get`, `put_bytes`,
+/// `get_bytes`, `contains`, `delete`, `list`, `delete_all`, `wipe`,
+/// `put_if_absent`, `replace_if_equals`, `delete_if_equals`,
+/// `get_attributes`, `put_typed`, `get_typed` or `get_record`. The typed
+/// ones need the `typed` feature. `put_typed` takes its value as JSON, of
+/// the type given as `value`:
+///
+/// ```ignore
+/// #[secure_native::export(op = "put_typed", java = "io.parity.Tokens", value = "Tokens")]
+/// fn put_tokens(stored: Result<(), Error>) -> Result<(), Error> {
+///     stored
+/// }
+/// ```
+///
+/// `get_typed` returns the type of the first parameter's `Result`.
+/// `set_attributes` and the transactions of `apply` can't be exported, as
+/// their inputs have no FFI form.
+#[proc_macro_attribute]
+pub fn export(args: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as AttributeArgs);
+    let function = parse_macro_input!(item as ItemFn);
+    match expand(args, function) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+use Input::*;
+
+/// Names of the entry points' own variables, besides the inputs
+const RESERVED: &[&str] = &["env", "activity", "err", "store", "res", "ret"];
+
+fn ident(input: Input) -> Ident {
+    Ident::new(input.name(), Span::call_site())
+}
+
+/// Type converted from the FFI argument with `Argument`, given the type of
+/// typed values
+fn ffi_type(input: Input, value: &Option<Type>) -> TokenStream2 {
+    let ty: Type = syn::parse_str(input.argument()).expect("Argument types of the inputs parse");
+    match input {
+        Typed => quote!(#ty<#value>),
+        _ => quote!(#ty),
+    }
+}
+
+/// Type of the parameter of the host function
+fn host_type(input: Input, value: &Option<Type>) -> TokenStream2 {
+    match input {
+        Name(_) | Text => quote!(&str),
+        Bytes | Expected => quote!(&[u8]),
+        Typed => quote!(&#value),
+        Schema => quote!(u32),
+        Policy => quote!(&::secure_native::AccessPolicy),
+    }
+}
+
+/// Argument of the `SecureStore` method, given the converted FFI argument
+fn store_arg(input: Input) -> TokenStream2 {
+    let ident = ident(input);
+    match input {
+        Typed => quote!(&#ident.0),
+        Schema => quote!(#ident),
+        _ => quote!(&#ident),
+    }
+}
+
+/// Options of the attribute
+struct Options {
+    op: String,
+    java: String,
+    method: Option<String>,
+    value: Option<String>,
+}
+
+fn parse_options(args: AttributeArgs) -> syn::Result<Options> {
+    let (mut op, mut java, mut method, mut value) = (None, None, None, None);
+    for arg in &args {
+        let pair = match arg {
+            NestedMeta::Meta(Meta::NameValue(pair)) => pair,
+            _ => return Err(syn::Error::new(arg.span(), "Expected `name = \"value\"`")),
+        };
+        let string = match &pair.lit {
+            Lit::Str(string) => string.value(),
+            lit => return Err(syn::Error::new(lit.span(), "Expected a string")),
+        };
+        let slot = if pair.path.is_ident("op") {
+            &mut op
+        } else if pair.path.is_ident("java") {
+            &mut java
+        } else if pair.path.is_ident("method") {
+            &mut method
+        } else if pair.path.is_ident("value") {
+            &mut value
+        } else {
+            return Err(syn::Error::new(
+                pair.path.span(),
+                "Unknown option, expected `op`, `java`, `method` or `value`",
+            ));
+        };
+        *slot = Some(string);
+    }
+    let missing = |name| syn::Error::new(Span::call_site(), format!("Missing `{}`", name));
+    Ok(Options {
+        op: op.ok_or_else(|| missing("op"))?,
+        java: java.ok_or_else(|| missing("java"))?,
+        method,
+        value,
+    })
+}
+
+fn expand(args: AttributeArgs, function: ItemFn) -> syn::Result<TokenStream2> {
+    let options = parse_options(args)?;
+    let method = options
+        .method
+        .unwrap_or_else(|| function.sig.ident.to_string());
+    let java_name = format_ident!("{}", jni_name(&options.java, &method));
+    generate(&options.op, java_name, options.value.as_deref(), function)
+}
+
+/// Export functions consuming the result of the store operation given
+/// first, each preceded by `@` and its JNI symbol. Backs the `export_*!`
+/// macros of `secure-native`, which make the functions public as they
+/// always have.
+#[doc(hidden)]
+#[proc_macro]
+pub fn __export_items(input: TokenStream) -> TokenStream {
+    let items = parse_macro_input!(input as Items);
+    let op = items.op.to_string();
+    let mut tokens = TokenStream2::new();
+    for (java_name, mut function) in items.functions {
+        function.vis = parse_quote!(pub);
+        match generate(&op, java_name, None, function) {
+            Ok(generated) => tokens.extend(generated),
+            Err(e) => return e.to_compile_error().into(),
+        }
+    }
+    tokens.into()
+}
+
+/// Input of `__export_items!`
+struct Items {
+    op: Ident,
+    functions: Vec<(Ident, ItemFn)>,
+}
+
+impl Parse for Items {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let op = input.parse()?;
+        let mut functions = Vec::new();
+        while !input.is_empty() {
+            input.parse::<Token![@]>()?;
+            functions.push((input.parse()?, input.parse()?));
+        }
+        Ok(Items { op, functions })
+    }
+}
+
+/// Entry points of `function` for the store operation `op`, given the JNI
+/// symbol and the type of typed values.
+fn generate(
+    op: &str,
+    java_name: Ident,
+    value: Option<&str>,
+    function: ItemFn,
+) -> syn::Result<TokenStream2> {
+    let inputs = secure_native_ops::inputs(op).ok_or_else(|| {
+        let ops: Vec<_> = OPS.iter().map(|(op, _)| *op).collect();
+        syn::Error::new(
+            Span::call_site(),
+            format!("Unknown `op`, expected one of: {}", ops.join(", ")),
+        )
+    })?;
+    let value = match (inputs.contains(&Typed), value) {
+        (true, Some(value)) => Some(syn::parse_str::<Type>(value)?),
+        (true, None) => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                format!("Missing `value`, the type `{}` stores", op),
+            ))
+        }
+        (false, Some(_)) => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                format!("`{}` takes no `value`", op),
+            ))
+        }
+        (false, None) => None,
+    };
+    let op = Ident::new(op, Span::call_site());
+
+    let name = &function.sig.ident;
+    let vis = &function.vis;
+    let ret = match &function.sig.output {
+        ReturnType::Default => quote!(()),
+        ReturnType::Type(_, ty) => quote!(#ty),
+    };
+    let mut params = function.sig.inputs.iter();
+    if params.next().is_none() {
+        return Err(syn::Error::new(
+            function.sig.span(),
+            "The first parameter receives the result of the operation",
+        ));
+    }
+    let (args, types) = passed_through(params)?;
+
+    let idents: Vec<_> = inputs.iter().map(|input| ident(*input)).collect();
+    for arg in &args {
+        if idents.contains(arg) || RESERVED.iter().any(|name| arg == name) {
+            return Err(syn::Error::new(
+                arg.span(),
+                format!("`{}` is used by the generated entry points", arg),
+            ));
+        }
+    }
+    let ffi_types: Vec<_> = inputs
+        .iter()
+        .map(|input| ffi_type(*input, &value))
+        .collect();
+    let host_types: Vec<_> = inputs
+        .iter()
+        .map(|input| host_type(*input, &value))
+        .collect();
+    let store_args: Vec<_> = inputs.iter().map(|input| store_arg(*input)).collect();
+    let call = quote!(::secure_native::SecureStore::#op(&store, #( #store_args ),*));
+    let ffi_call = if inputs.contains(&Policy) {
+        quote! {
+            ::secure_native::AccessPolicy::from_bits(policy).and_then(|policy| #call)
+        }
+    } else {
+        call
+    };
+    let host_args = idents.clone();
+
+    Ok(quote! {
+        #function
+
+        #[allow(non_snake_case, unused_imports)]
+        #vis mod #name {
+            use super::*;
+
+            #[cfg(target_os = "android")]
+            #[no_mangle]
+            pub extern "C" fn #java_name<'jni>(
+                env: ::secure_native::jni::JNIEnv<'jni>,
+                _: ::secure_native::jni::objects::JClass<'jni>,
+                activity: ::secure_native::jni::objects::JObject<'jni>,
+                #( #idents: <#ffi_types as ::secure_native::Argument<'jni>>::Ext, )*
+                #( #args: <#types as ::secure_native::Argument<'jni>>::Ext ),*
+            ) -> <#ret as ::secure_native::Return<'jni>>::Ext {
+                ::secure_native::android::guard(&env, || {
+                    let store: ::secure_native::android::Keystore =
+                        ::secure_native::SecureStore::open((&env, activity));
+                    #( let #idents: #ffi_types = ::secure_native::Argument::convert(&env, #idents)?; )*
+                    let res = #ffi_call;
+                    let ret = super::#name(res, #( ::secure_native::Argument::convert(&env, #args)? ),*);
+                    ::secure_native::Return::convert(&env, ret)
+                })
+            }
+
+            #[cfg(target_os = "ios")]
+            #[no_mangle]
+            pub extern "C" fn #name(
+                err: *mut ::std::os::raw::c_uint,
+                #( #idents: <#ffi_types as ::secure_native::Argument<'static>>::Ext, )*
+                #( #args: <#types as ::secure_native::Argument<'static>>::Ext ),*
+            ) -> *mut ::secure_native::ios::CResult<<#ret as ::secure_native::Return<'static>>::Ext> {
+                ::secure_native::ios::guard(err, |env| {
+                    let store: ::secure_native::ios::Keychain =
+                        ::secure_native::SecureStore::open(());
+                    #( let #idents: #ffi_types = ::secure_native::Argument::convert(env, #idents)?; )*
+                    let res = #ffi_call;
+                    let ret = super::#name(res, #( ::secure_native::Argument::convert(env, #args)? ),*);
+                    ::secure_native::Return::convert(env, ret)
+                })
+            }
+
+            #[cfg(target_os = "linux")]
+            pub fn #name(#( #idents: #host_types, )* #( #args: #types ),*) -> #ret {
+                let store: ::secure_native::linux::FileStore =
+                    ::secure_native::SecureStore::open(::std::default::Default::default());
+                let res = ::secure_native::SecureStore::#op(&store, #( #host_args ),*);
+                super::#name(res, #( #args ),*)
+            }
+        }
+    })
+}
+
+/// Names and types of the parameters passed through to the function.
+fn passed_through<'a>(
+    params: impl Iterator<Item = &'a FnArg>,
+) -> syn::Result<(Vec<Ident>, Vec<Type>)> {
+    let (mut args, mut types) = (Vec::new(), Vec::new());
+    for param in params {
+        let typed = match param {
+            FnArg::Typed(typed) => typed,
+            FnArg::Receiver(receiver) => {
+                return Err(syn::Error::new(
+                    receiver.span(),
+                    "Methods can't be exported",
+                ))
+            }
+        };
+        match &*typed.pat {
+            Pat::Ident(pat) => args.push(pat.ident.clone()),
+            pat => return Err(syn::Error::new(pat.span(), "Expected a parameter name")),
+        }
+        types.push((*typed.ty).clone());
+    }
+    Ok((args, types))
+}
+
+/// Symbol of the native method `method` of the Java class `class`, given
+/// with dots, such as `io.parity.Seeds`.
+///
+/// See "Resolving Native Method Names" in the JNI specification.
+fn jni_name(class: &str, method: &str) -> String {
+    format!("Java_{}_{}", jni_mangle(class), jni_mangle(method))
+}
+
+fn jni_mangle(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '.' | '/' => out.push('_'),
+            '_' => out.push_str("_1"),
+            ';' => out.push_str("_2"),
+            '[' => out.push_str("_3"),
+            c if c.is_ascii_alphanumeric() => out.push(c),
+            c => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    out.push_str(&format!("_0{:04x}", unit));
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jni_name() {
+        assert_eq!(
+            jni_name("io.parity.Seeds", "getSeed"),
+            "Java_io_parity_Seeds_getSeed"
+        );
+        assert_eq!(
+            jni_name("io.parity.secure_native.Seeds", "get_seed"),
+            "Java_io_parity_secure_1native_Seeds_get_1seed"
+        );
+        assert_eq!(
+            jni_name("io.parity.Outer$Inner", "é"),
+            "Java_io_parity_Outer_00024Inner__000e9"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! Store operations which can be exported across the FFI
+//!
+//! Shared by `secure-native-macros`, which generates the entry points, and
+//! `secure-native-header`, which declares them in C, so the two can't
+//! disagree on what an operation takes.
+
+/// Input of a store operation, received from the caller.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Input {
+    /// Service or account name
+    Name(&'static str),
+    /// Value of a string write
+    Text,
+    /// Value of a byte write
+    Bytes,
+    /// Digest of the expected value of a conditional write
+    Expected,
+    /// Value of a typed write, of the type given to `export` as `value`
+    Typed,
+    /// Schema of a typed value
+    Schema,
+    /// `AccessPolicy` bits
+    Policy,
+}
+
+use Input::*;
+
+impl Input {
+    /// Name of the parameter of the entry points
+    pub fn name(self) -> &'static str {
+        match self {
+            Name(name) => name,
+            Text | Bytes | Typed => "value",
+            Expected => "expected",
+            Schema => "schema",
+            Policy => "policy",
+        }
+    }
+
+    /// Type the FFI argument is converted to with `Argument`. That of `Typed`
+    /// takes the value type as its parameter.
+    pub fn argument(self) -> &'static str {
+        match self {
+            Name(_) => "::std::string::String",
+            Text => "::secure_native::SecretString",
+            Bytes => "::secure_native::SecretBytes",
+            Expected => "::std::vec::Vec<u8>",
+            Typed => "::secure_native::Json",
+            Schema | Policy => "u32",
+        }
+    }
+}
+
+/// Store operations and the inputs they take, in the order they are passed
+/// to the `SecureStore` method of the same name.
+///
+/// `set_attributes` and `apply` are missing: `Attributes` and `Write` have
+/// no FFI form for the caller to pass. The typed operations need the
+/// `typed` feature of `secure-native`.
+pub const OPS: &[(&str, &[Input])] = &[
+    ("put", &[Name("app"), Name("key"), Text, Policy]),
+    ("get", &[Name("app"), Name("key")]),
+    ("put_bytes", &[Name("app"), Name("key"), Bytes, Policy]),
+    ("get_bytes", &[Name("app"), Name("key")]),
+    ("contains", &[Name("app"), Name("key")]),
+    ("delete", &[Name("app"), Name("key")]),
+    ("list", &[Name("app")]),
+    ("delete_all", &[Name("app")]),
+    ("wipe", &[]),
+    ("put_if_absent", &[Name("app"), Name("key"), Bytes, Policy]),
+    (
+        "replace_if_equals",
+        &[Name("app"), Name("key"), Expected, Bytes, Policy],
+    ),
+    ("delete_if_equals", &[Name("app"), Name("key"), Expected]),
+    ("get_attributes", &[Name("app"), Name("key")]),
+    (
+        "put_typed",
+        &[Name("app"), Name("key"), Typed, Schema, Policy],
+    ),
+    ("get_typed", &[Name("app"), Name("key"), Schema]),
+    ("get_record", &[Name("app"), Name("key")]),
+];
+
+/// Inputs of the operation `op`, or `None` if it can't be exported.
+pub fn inputs(op: &str) -> Option<&'static [Input]> {
+    OPS.iter()
+        .find(|(name, _)| *name == op)
+        .map(|(_, inputs)| *inputs)
+}
@@ -1,36 +1,75 @@
-use crate::Argument;
+use super::result::error_from_jni;
+#[cfg(feature = "typed")]
+use crate::Json;
+use crate::{Argument, Error, SecretBytes, SecretString};
 use jni::objects::JString;
-use jni::sys::{jboolean, jint};
+use jni::sys::{jboolean, jbyteArray, jint};
 use jni::JNIEnv;
 
 impl<'a> Argument<'a> for u32 {
     type Ext = jint;
     type Env = JNIEnv<'a>;
-    fn convert(_: &Self::Env, val: Self::Ext) -> Self {
-        val as u32
+    fn convert(_: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        Ok(val as u32)
     }
 }
 
 impl<'a> Argument<'a> for u8 {
     type Ext = jint;
     type Env = JNIEnv<'a>;
-    fn convert(_: &Self::Env, val: Self::Ext) -> Self {
-        val as u8
+    fn convert(_: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        Ok(val as u8)
     }
 }
 
 impl<'a> Argument<'a> for bool {
     type Ext = jboolean;
     type Env = JNIEnv<'a>;
-    fn convert(_: &Self::Env, val: Self::Ext) -> Self {
-        val != 0
+    fn convert(_: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        Ok(val != 0)
     }
 }
 
 impl<'a> Argument<'a> for String {
     type Ext = JString<'a>;
     type Env = JNIEnv<'a>;
-    fn convert(env: &Self::Env, val: Self::Ext) -> Self {
-        env.get_string(val).expect("Invalid java string").into()
+    fn convert(env: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        env.get_string(val).map(Into::into).map_err(error_from_jni)
+    }
+}
+
+#[cfg(feature = "typed")]
+impl<'a, T: serde::de::DeserializeOwned> Argument<'a> for Json<T> {
+    type Ext = JString<'a>;
+    type Env = JNIEnv<'a>;
+    fn convert(env: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        let json: String = Argument::convert(env, val)?;
+        Json::from_json(&json)
+    }
+}
+
+impl<'a> Argument<'a> for Vec<u8> {
+    type Ext = jbyteArray;
+    type Env = JNIEnv<'a>;
+    fn convert(env: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        env.convert_byte_array(val).map_err(error_from_jni)
+    }
+}
+
+impl<'a> Argument<'a> for SecretString {
+    type Ext = JString<'a>;
+    type Env = JNIEnv<'a>;
+    fn convert(env: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        let s: String = Argument::convert(env, val)?;
+        Ok(SecretString::from(s))
+    }
+}
+
+impl<'a> Argument<'a> for SecretBytes {
+    type Ext = jbyteArray;
+    type Env = JNIEnv<'a>;
+    fn convert(env: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        let bytes: Vec<u8> = Argument::convert(env, val)?;
+        Ok(SecretBytes::from(bytes))
     }
 }
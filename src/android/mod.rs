@@ -14,214 +14,1108 @@ use result::*;
 pub use ret::*;
 use util::*;
 
-use jni::objects::{JObject, JString};
-use jni::sys::jboolean;
+use crate::attributes;
+use crate::envelope::{Algorithm, Envelope};
+use crate::lock::{lock_store, share_store};
+use crate::naming;
+use crate::store::{constant_time_eq, last_writes};
+use crate::{
+    lock_item, lock_items, AccessPolicy, AccountInfo, Attributes, Error, ErrorKind, NameMode,
+    SecretBytes, SecureStore, Write,
+};
+
+use jni::objects::JObject;
 use jni::JNIEnv;
 
-use android::content::Context;
+use android::content::{Context, SharedPreferences_Editor};
 use android::security::keystore::KeyProperties;
 use javax::crypto::Cipher;
 use jni_android_sys::*;
+use std::{io, time::SystemTime};
 
 const ANDROID_KEYSTORE_PROVIDER: &'static str = "AndroidKeyStore";
 
+/// Length of the IV the keystore generates for AES-GCM.
+const GCM_IV_LENGTH: usize = 12;
+
+/// Length of an HMAC-SHA256 tag.
+const MAC_LENGTH: usize = 32;
+
+/// `SecureStore` backed by the AndroidKeyStore and the activity's SharedPreferences.
+pub struct Keystore<'a> {
+    env: &'a JNIEnv<'a>,
+    activity: JObject<'a>,
+    names: NameMode,
+}
+
+impl<'a> SecureStore for Keystore<'a> {
+    type Config = (&'a JNIEnv<'a>, JObject<'a>);
+
+    fn open((env, activity): Self::Config) -> Self {
+        Keystore {
+            env,
+            activity,
+            names: NameMode::default(),
+        }
+    }
+
+    fn put_bytes(
+        &self,
+        service: &str,
+        account: &str,
+        value: &[u8],
+        policy: &AccessPolicy,
+    ) -> Result<()> {
+        let _guard = lock_item(service, account);
+        put(
+            self.env,
+            &self.activity,
+            self.names,
+            service,
+            account,
+            value,
+            policy,
+        )
+    }
+
+    fn get_bytes(&self, service: &str, account: &str) -> Result<SecretBytes> {
+        // Reading may migrate a legacy item
+        let _guard = lock_item(service, account);
+        get(self.env, &self.activity, self.names, service, account)
+    }
+
+    fn contains(&self, service: &str, account: &str) -> Result<bool> {
+        contains(self.env, &self.activity, self.names, service, account)
+    }
+
+    fn delete(&self, service: &str, account: &str) -> Result<()> {
+        let _guard = lock_item(service, account);
+        delete(self.env, &self.activity, self.names, service, account)
+    }
+
+    fn get_attributes(&self, service: &str, account: &str) -> Result<Attributes> {
+        get_attributes(self.env, &self.activity, self.names, service, account)
+    }
+
+    fn set_attributes(&self, service: &str, account: &str, attributes: &Attributes) -> Result<()> {
+        attributes::check(attributes)?;
+        let _guard = lock_item(service, account);
+        set_attributes(
+            self.env,
+            &self.activity,
+            self.names,
+            service,
+            account,
+            attributes,
+        )
+    }
+
+    /// See `apply`.
+    fn apply(&self, writes: &[Write]) -> Result<()> {
+        let _guards = lock_items(writes.iter().map(|w| (w.service(), w.account())));
+        apply(self.env, &self.activity, self.names, writes)
+    }
+
+    fn wipe(&self) -> Result<Vec<AccountInfo>> {
+        wipe(self.env, &self.activity)
+    }
+
+    fn list(&self, service: &str) -> Result<Vec<AccountInfo>> {
+        list(self.env, &self.activity, self.names, service)
+    }
+}
+
+impl<'a> Keystore<'a> {
+    /// Choose how item names are stored. Defaults to `NameMode::Plain`.
+    pub fn with_names(self, names: NameMode) -> Self {
+        Keystore { names, ..self }
+    }
+
+    /// Like `put_bytes`, but always encrypts with a newly generated key.
+    pub fn put_rotating_key(
+        &self,
+        service: &str,
+        account: &str,
+        value: &[u8],
+        policy: &AccessPolicy,
+    ) -> Result<()> {
+        let _guard = lock_item(service, account);
+        put_rotating_key(
+            self.env,
+            &self.activity,
+            self.names,
+            service,
+            account,
+            value,
+            policy,
+        )
+    }
+
+    /// See `purge_orphans`.
+    pub fn purge_orphans(&self) -> Result<usize> {
+        purge_orphans(self.env, &self.activity)
+    }
+}
+
+/// Where an item's ciphertext and keystore key live.
+struct Location {
+    preferences: String,
+    key: String,
+    /// Legacy items keep their IV under a second key
+    iv_key: Option<String>,
+    alias: String,
+    /// Key of the item's record in `naming::ATTRIBUTES`
+    attributes_key: String,
+}
+
+impl Location {
+    fn current(service: &str, account: &str) -> Self {
+        Location {
+            preferences: naming::PREFERENCES.to_string(),
+            key: naming::pref_key(service, account),
+            iv_key: None,
+            alias: naming::alias(service, account),
+            attributes_key: naming::pref_key(service, account),
+        }
+    }
+
+    fn legacy(service: &str, account: &str) -> Self {
+        Location {
+            preferences: naming::legacy_preferences(service).to_string(),
+            key: naming::legacy_pref_key(account).to_string(),
+            iv_key: Some(naming::legacy_iv_key(account)),
+            alias: naming::legacy_alias(service, account),
+            attributes_key: naming::legacy_attributes_key(service, account),
+        }
+    }
+
+    /// Location of an item under `NameMode::Hashed`.
+    fn hashed(env: &JNIEnv, service: &str, account: &str) -> Result<Self> {
+        let name = naming::hex(&name_mac(
+            env,
+            &naming::hashed_item_input(service, account),
+        )?);
+        Ok(Location {
+            preferences: naming::PREFERENCES.to_string(),
+            alias: naming::hashed_alias(&name),
+            attributes_key: name.clone(),
+            key: name,
+            iv_key: None,
+        })
+    }
+
+    /// Every location an item may be stored at, starting with the one it is
+    /// written to.
+    fn all(env: &JNIEnv, names: NameMode, service: &str, account: &str) -> Result<Vec<Self>> {
+        let mut locations = Vec::with_capacity(3);
+        if names == NameMode::Hashed {
+            locations.push(Location::hashed(env, service, account)?);
+        }
+        locations.push(Location::current(service, account));
+        locations.push(Location::legacy(service, account));
+        Ok(locations)
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.iv_key.is_some()
+    }
+}
+
+/// Which keystore key `seal` encrypts an item with.
+#[derive(Clone, Copy, PartialEq)]
+enum NewKey {
+    /// Reuse the item's key if it was generated for the same policy and is
+    /// still valid, and generate one otherwise.
+    IfNeeded,
+    /// Always generate a new key.
+    Always,
+    /// Like `IfNeeded`, but fail rather than replace a key which the stored
+    /// value still needs, so nothing is lost if the new value isn't stored.
+    KeepStored,
+}
+
+/// A value read back from the store.
+struct Decrypted {
+    value: SecretBytes,
+    /// For legacy items, whether their key requires user authentication.
+    requires_authentication: Option<bool>,
+}
+
+/// Store an item, encrypting it with the item's existing keystore key when
+/// that key was generated for the same policy and is still valid.
 pub fn put<'a>(
     env: &'a JNIEnv,
-    activity: JObject,
-    service: JString,
-    account: JString,
-    value: JString,
-    with_biometry: jboolean,
+    activity: &JObject,
+    names: NameMode,
+    service: &str,
+    account: &str,
+    value: &[u8],
+    policy: &AccessPolicy,
+) -> Result<()> {
+    store(
+        env,
+        activity,
+        names,
+        service,
+        account,
+        value,
+        policy,
+        NewKey::IfNeeded,
+    )
+}
+
+/// Store an item, always encrypting it with a newly generated keystore key.
+pub fn put_rotating_key<'a>(
+    env: &'a JNIEnv,
+    activity: &JObject,
+    names: NameMode,
+    service: &str,
+    account: &str,
+    value: &[u8],
+    policy: &AccessPolicy,
+) -> Result<()> {
+    store(
+        env,
+        activity,
+        names,
+        service,
+        account,
+        value,
+        policy,
+        NewKey::Always,
+    )
+}
+
+fn store<'a>(
+    env: &'a JNIEnv,
+    activity: &JObject,
+    names: NameMode,
+    service: &str,
+    account: &str,
+    value: &[u8],
+    policy: &AccessPolicy,
+    new_key: NewKey,
 ) -> Result<()> {
-    let service: String = env
-        .get_string(service)
-        .expect(&format!("Getting java string from '{:?}'", *service))
-        .into();
-    let account: String = env
-        .get_string(account)
-        .expect(&format!("Getting java string '{:?}'", *account))
-        .into();
-    let value: String = env
-        .get_string(value)
-        .expect(&format!("Getting java string '{:?}'", *value))
-        .into();
-
-    let alias = format!("{}{}", service, &account);
+    // Keeps `purge_orphans` from taking the new key for an orphan
+    let _store = share_store();
+    let locations = Location::all(env, names, service, account)?;
+    let location = &locations[0];
+    let sealed = seal(env, activity, location, value, policy, new_key)?;
+    let app = java_string(env, &location.preferences)?;
+    let key = java_string(env, &location.key)?;
+    let envelope = java_byte_array(env, &sealed);
+    let encrypted_value = java_base64_encode(env, &envelope)?;
+    let context = java_context(env, activity);
+    let pref = r#try!(resopt!(
+        context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
+    ));
+    let edit = r#try!(resopt!(pref.edit()));
+    let _ = r#try!(resopt!(edit.putString(Some(&*key), Some(&*encrypted_value))));
+    commit(&edit)?;
+
+    // The value is stored, so failing to record the change doesn't fail the
+    // put
+    let _ = touch_attributes(env, activity, &locations);
+    // The new item shadows any older copy, so failing to remove it is harmless
+    for older in &locations[1..] {
+        let _ = remove(env, activity, older);
+    }
+    Ok(())
+}
+
+/// Encrypt `value` for the item at `location`, generating its keystore key
+/// as `new_key` says, and return the encoded envelope.
+fn seal<'a>(
+    env: &'a JNIEnv,
+    activity: &JObject,
+    location: &Location,
+    value: &[u8],
+    policy: &AccessPolicy,
+    new_key: NewKey,
+) -> Result<Vec<u8>> {
     let algorithm = KeyProperties::KEY_ALGORITHM_AES;
     let provider = ANDROID_KEYSTORE_PROVIDER;
-    let block_mode = KeyProperties::BLOCK_MODE_CBC;
-    let padding = KeyProperties::ENCRYPTION_PADDING_PKCS7;
-    let key_size = 128;
+    let block_mode = KeyProperties::BLOCK_MODE_GCM;
+    let padding = KeyProperties::ENCRYPTION_PADDING_NONE;
+    let key_size = 256;
     let transform = format!("{}/{}/{}", algorithm, block_mode, padding);
 
-    let app = java_string(env, &service);
-    let iv_key = java_string(env, &format!("{}iv", &account));
-    let key = java_string(env, &account);
-    let value = java_string(env, &value);
-    let alias = java_string(env, &alias);
-    let algorithm = java_string(env, &algorithm);
-    let provider = java_string(env, &provider);
-    let block_mode = java_string(env, &block_mode);
-    let padding = java_string(env, &padding);
-    let transform = java_string(env, &transform);
+    let alias = java_string(env, &location.alias)?;
+    let algorithm = java_string(env, &algorithm)?;
+    let provider = java_string(env, &provider)?;
+    let block_mode = java_string(env, &block_mode)?;
+    let padding = java_string(env, &padding)?;
+    let transform = java_string(env, &transform)?;
 
     let block_modes = java_string_array(env, 1)?;
-    let _ = stringify_throwable!(block_modes.set(0, Some(&*block_mode)))?;
+    let _ = map_throwable!(block_modes.set(0, Some(&*block_mode)))?;
     let paddings = java_string_array(env, 1)?;
-    let _ = stringify_throwable!(paddings.set(0, Some(&*padding)))?;
+    let _ = map_throwable!(paddings.set(0, Some(&*padding)))?;
 
+    let keystore = java_keystore(env, &provider)?;
+    let _ = keystore.load_LoadStoreParameter(None);
+    let reusable = new_key != NewKey::Always
+        && key_policy(env, activity, &location.alias)? == Some(policy.to_bits());
+    let existing = map_throwable!(keystore.getKey(Some(&*alias), None))?;
+    if existing.is_some() && !reusable && new_key == NewKey::KeepStored {
+        let app = java_string(env, &location.preferences)?;
+        let key = java_string(env, &location.key)?;
+        let context = java_context(env, activity);
+        let pref = r#try!(resopt!(
+            context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
+        ));
+        if map_throwable!(pref.contains(Some(&*key)))? {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Changing the policy of a stored item can't be part of a transaction",
+            ));
+        }
+    }
+    let reused = existing
+        .filter(|_| reusable)
+        .map(|key| java_cipher(env, &transform, Cipher::ENCRYPT_MODE, key, None));
+    let cipher = match reused {
+        Some(Ok(cipher)) => Some(cipher),
+        // Enrolling new biometrics permanently invalidates keys bound to them
+        Some(Err(ref e)) if e.kind() == &ErrorKind::KeyInvalidated => None,
+        Some(Err(e)) => return Err(e),
+        None => None,
+    };
     let keygen = java_key_generator(env, &algorithm, &provider)?;
-    let spec = java_algorithm_parameter_spec(
-        env,
-        &alias,
-        &block_modes,
-        &paddings,
-        key_size,
-        with_biometry != 0,
+    let cipher = match cipher {
+        Some(cipher) => cipher,
+        None => {
+            let spec = java_algorithm_parameter_spec(
+                env,
+                &alias,
+                &block_modes,
+                &paddings,
+                key_size,
+                policy,
+            )?;
+            let _ = map_throwable!(keygen.init_AlgorithmParameterSpec(Some(&*spec)))?;
+            let secret_key = java_generate_key(&keygen)?;
+            set_key_policy(env, activity, &location.alias, policy.to_bits())?;
+            java_cipher(env, &transform, Cipher::ENCRYPT_MODE, secret_key, None)?
+        }
+    };
+    let iv_bytes = r#try!(resopt!(cipher.getIV()));
+    let value_bytes = java_byte_array(env, value);
+    let encrypted_bytes = cipher.doFinal_byte_array(Some(&*value_bytes));
+    java_wipe_byte_array(&value_bytes);
+    let encrypted_bytes = r#try!(resopt!(encrypted_bytes));
+    let envelope = Envelope::from_sealed(
+        Algorithm::AesGcm,
+        &rust_byte_array(&iv_bytes),
+        &rust_byte_array(&encrypted_bytes),
     )?;
-    let _ = stringify_throwable!(keygen.init_AlgorithmParameterSpec(Some(&*spec)))?;
-    let secret_key = java_generate_key(&keygen)?;
+    Ok(envelope.encode())
+}
 
-    let cipher = java_cipher(env, &transform, Cipher::ENCRYPT_MODE, secret_key, None)?;
-    let iv_bytes = r#try!(resopt!(cipher.getIV()));
-    let iv = java_base64_encode(env, &iv_bytes)?;
-    let value_bytes = r#try!(resopt!(value.getBytes()));
-    let encrypted_bytes = r#try!(resopt!(cipher.doFinal_byte_array(Some(&*value_bytes))));
-    let encrypted_value = java_base64_encode(env, &encrypted_bytes)?;
-    let context = java_context(env, &activity);
+/// Apply `writes` with a single commit of the `PREFERENCES` file, so either
+/// every item in it is written or none is.
+///
+/// Values are encrypted before the commit, which may generate keys for new
+/// items; if the commit fails those are left for `purge_orphans`. Replacing
+/// the key of a stored item would lose its value if the commit then failed,
+/// so a put which changes the policy of a stored item fails with
+/// `ErrorKind::Unsupported` and nothing is written. Keys of removed items and
+/// legacy copies, which live elsewhere, are deleted after the commit; if
+/// deleting a legacy copy fails, the error is returned although the commit
+/// has happened.
+pub fn apply<'a>(
+    env: &'a JNIEnv,
+    activity: &JObject,
+    names: NameMode,
+    writes: &[Write],
+) -> Result<()> {
+    let _store = share_store();
+    let mut staged = Vec::new();
+    for write in last_writes(writes) {
+        let locations = Location::all(env, names, write.service(), write.account())?;
+        let sealed = match write {
+            Write::Put { value, policy, .. } => Some(seal(
+                env,
+                activity,
+                &locations[0],
+                value,
+                policy,
+                NewKey::KeepStored,
+            )?),
+            Write::Delete { .. } => None,
+        };
+        staged.push((locations, sealed));
+    }
+
+    let app = java_string(env, &naming::PREFERENCES)?;
+    let context = java_context(env, activity);
     let pref = r#try!(resopt!(
         context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
     ));
     let edit = r#try!(resopt!(pref.edit()));
-    let _ = r#try!(resopt!(edit.putString(Some(&*key), Some(&*encrypted_value))));
-    let _ = r#try!(resopt!(edit.putString(Some(&*iv_key), Some(&*iv))));
+    for (locations, sealed) in &staged {
+        // Every location but the legacy one is in this file
+        let mut current = locations.iter().filter(|location| !location.is_legacy());
+        if let Some(sealed) = sealed {
+            let location = current
+                .next()
+                .ok_or_else(|| Error::new(ErrorKind::Other, "Item has no location"))?;
+            let key = java_string(env, &location.key)?;
+            let envelope = java_byte_array(env, sealed);
+            let encrypted_value = java_base64_encode(env, &envelope)?;
+            let _ = r#try!(resopt!(edit.putString(Some(&*key), Some(&*encrypted_value))));
+        }
+        for older in current {
+            let key = java_string(env, &older.key)?;
+            let _ = r#try!(resopt!(edit.remove(Some(&*key))));
+        }
+    }
+    commit(&edit)?;
 
-    return match edit.commit() {
-        Ok(true) => Ok(()),
-        Ok(false) => Err(format!(
-            "Unknown Android error - failed committing changes to disk."
-        )),
-        Err(e) => Err(format!("{:?}", e.toString().unwrap().unwrap())),
-    };
+    for (locations, sealed) in staged {
+        if sealed.is_some() {
+            let _ = touch_attributes(env, activity, &locations);
+        }
+        let written = if sealed.is_some() { 1 } else { 0 };
+        for older in locations.iter().skip(written) {
+            let removed = remove(env, activity, older);
+            // A put shadows its older copies, but a delete mustn't leave
+            // one behind
+            if sealed.is_none() && older.is_legacy() {
+                removed?;
+            }
+        }
+    }
+    Ok(())
 }
 
 pub fn get<'a>(
     env: &'a JNIEnv,
-    activity: JObject,
-    service: JString,
-    account: JString,
-) -> Result<String> {
-    let service: String = env
-        .get_string(service)
-        .expect(&format!("Getting java string from '{:?}'", *service))
-        .into();
-    let account: String = env
-        .get_string(account)
-        .expect(&format!("Getting java string '{:?}'", *account))
-        .into();
-
-    let alias = format!("{}{}", service, &account);
+    activity: &JObject,
+    names: NameMode,
+    service: &str,
+    account: &str,
+) -> Result<SecretBytes> {
+    for location in Location::all(env, names, service, account)? {
+        let decrypted = match decrypt(env, activity, &location)? {
+            Some(decrypted) => decrypted,
+            None => continue,
+        };
+        if let Some(requires_authentication) = decrypted.requires_authentication {
+            // Move legacy items to the current naming scheme and envelope
+            // format. `with_biometry` was the only protection available for
+            // them. The value has been read, so a failed rewrite must not
+            // fail `get`; it is retried on the next read.
+            let policy = if requires_authentication {
+                AccessPolicy::biometry()
+            } else {
+                AccessPolicy::default()
+            };
+            let _ = put(
+                env,
+                activity,
+                names,
+                service,
+                account,
+                &decrypted.value,
+                &policy,
+            );
+        }
+        return Ok(decrypted.value);
+    }
+    Err(Error::new(
+        ErrorKind::NotFound,
+        &format!("Couldn't find value for key: {}", account),
+    ))
+}
+
+/// HMAC-SHA256 of `input` under the store-level naming key. The key is
+/// generated in the keystore on first use and never leaves it.
+fn name_mac(env: &JNIEnv, input: &[u8]) -> Result<Vec<u8>> {
+    let algorithm = java_string(env, &KeyProperties::KEY_ALGORITHM_HMAC_SHA256)?;
+    let provider = java_string(env, &ANDROID_KEYSTORE_PROVIDER)?;
+    let alias = java_string(env, &naming::NAME_KEY_ALIAS)?;
+
+    let keystore = java_keystore(env, &provider)?;
+    let _ = keystore.load_LoadStoreParameter(None);
+    let existing = map_throwable!(keystore.getKey(Some(&*alias), None))?;
+    let keygen = java_key_generator(env, &algorithm, &provider)?;
+    let key = match existing {
+        Some(key) => key,
+        None => {
+            let spec = java_mac_parameter_spec(env, &alias)?;
+            let _ = map_throwable!(keygen.init_AlgorithmParameterSpec(Some(&*spec)))?;
+            java_generate_key(&keygen)?
+        }
+    };
+    java_mac(env, &algorithm, key, input)
+}
+
+/// Read and decrypt the item at `location`, if there is one.
+fn decrypt<'a>(
+    env: &'a JNIEnv,
+    activity: &JObject,
+    location: &Location,
+) -> Result<Option<Decrypted>> {
     let algorithm = KeyProperties::KEY_ALGORITHM_AES;
     let provider = ANDROID_KEYSTORE_PROVIDER;
-    let block_mode = KeyProperties::BLOCK_MODE_CBC;
-    let padding = KeyProperties::ENCRYPTION_PADDING_PKCS7;
-    let transform = format!("{}/{}/{}", algorithm, block_mode, padding);
+    let gcm_transform = format!(
+        "{}/{}/{}",
+        algorithm,
+        KeyProperties::BLOCK_MODE_GCM,
+        KeyProperties::ENCRYPTION_PADDING_NONE
+    );
+    let cbc_transform = format!(
+        "{}/{}/{}",
+        algorithm,
+        KeyProperties::BLOCK_MODE_CBC,
+        KeyProperties::ENCRYPTION_PADDING_PKCS7
+    );
 
-    let app = java_string(env, &service);
-    let iv_key = java_string(env, &format!("{}iv", &account));
-    let key = java_string(env, &account);
-    let alias = java_string(env, &alias);
-    let provider = java_string(env, &provider);
-    let transform = java_string(env, &transform);
+    let app = java_string(env, &location.preferences)?;
+    let key = java_string(env, &location.key)?;
+    let alias = java_string(env, &location.alias)?;
+    let algorithm = java_string(env, &algorithm)?;
+    let provider = java_string(env, &provider)?;
 
-    let context = java_context(env, &activity);
+    let context = java_context(env, activity);
     let pref = r#try!(resopt!(
         context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
     ));
+    let encrypted_str = match map_throwable!(pref.getString(Some(&*key), None))? {
+        Some(encrypted_str) => encrypted_str,
+        None => return Ok(None),
+    };
+    let iv_str = match &location.iv_key {
+        Some(iv_key) => {
+            let iv_key = java_string(env, iv_key)?;
+            map_throwable!(pref.getString(Some(&*iv_key), None))?
+        }
+        None => None,
+    };
     let keystore = java_keystore(env, &provider)?;
     let _ = keystore.load_LoadStoreParameter(None);
-    let secret_key = r#try!(resopt!(keystore.getKey(Some(&*alias), None)));
-
-    let encrypted_str = r#try!(resopt!(pref.getString(Some(&*key), None)));
-    let iv_str = r#try!(resopt!(pref.getString(Some(&*iv_key), None)));
+    let secret_key = match map_throwable!(keystore.getKey(Some(&*alias), None))? {
+        Some(secret_key) => secret_key,
+        None => {
+            return Err(Error::new(
+                ErrorKind::KeyInvalidated,
+                &format!("Missing keystore key for: {}", location.alias),
+            ))
+        }
+    };
     let encrypted_bytes = java_base64_decode(env, &encrypted_str)?;
-    let iv_bytes = java_base64_decode(env, &iv_str)?;
-    let spec = java_algorithm_parameter_spec_from_bytes(env, &iv_bytes)?;
 
-    return java_cipher(
+    // Items from before the envelope format keep their IV under a second key
+    let (iv_bytes, sealed_bytes, loose) = match &iv_str {
+        Some(iv_str) => (java_base64_decode(env, iv_str)?, encrypted_bytes, true),
+        None => {
+            let envelope = Envelope::decode(&rust_byte_array(&encrypted_bytes))?;
+            let sealed = match envelope.algorithm {
+                Algorithm::AesGcm => envelope.sealed(),
+            };
+            (
+                java_byte_array(env, &envelope.nonce),
+                java_byte_array(env, &sealed),
+                false,
+            )
+        }
+    };
+
+    // Loose items with a 16 byte IV also predate the switch to GCM
+    let cbc = loose && iv_bytes.len() != GCM_IV_LENGTH;
+    let requires_authentication = if location.is_legacy() {
+        java_key_requires_authentication(env, &algorithm, &provider, &secret_key).ok()
+    } else {
+        None
+    };
+    let (transform, spec) = if cbc {
+        (
+            cbc_transform,
+            java_algorithm_parameter_spec_from_bytes(env, &iv_bytes)?,
+        )
+    } else {
+        (gcm_transform, java_gcm_parameter_spec(env, &iv_bytes)?)
+    };
+    let transform = java_string(env, &transform)?;
+
+    let cipher = java_cipher(
         env,
         &transform,
         Cipher::DECRYPT_MODE,
         secret_key,
         Some(&spec),
-    )
-    .and_then(|cipher| {
-        let decrypted_bytes = r#try!(resopt!(cipher.doFinal_byte_array(Some(&*encrypted_bytes))));
-        let decrypted_str = stringify_throwable!(JavaString::new_byte_array(
-            unsafe { jni_glue::Env::from_ptr(env.get_native_interface()) },
-            Some(&*decrypted_bytes)
-        ))?;
-        Ok(format!("{:?}", decrypted_str))
-    });
+    )?;
+    let decrypted_bytes = r#try!(resopt!(cipher.doFinal_byte_array(Some(&*sealed_bytes))));
+    Ok(Some(Decrypted {
+        value: rust_secret_byte_array(&decrypted_bytes),
+        requires_authentication,
+    }))
 }
 
 pub fn contains<'a>(
     env: &'a JNIEnv,
-    activity: JObject,
-    service: JString,
-    account: JString,
+    activity: &JObject,
+    names: NameMode,
+    service: &str,
+    account: &str,
 ) -> Result<bool> {
-    let service: String = env
-        .get_string(service)
-        .expect(&format!("Getting java string from '{:?}'", *service))
-        .into();
-    let account: String = env
-        .get_string(account)
-        .expect(&format!("Getting java string '{:?}'", *account))
-        .into();
-
-    let app = java_string(env, &service);
-    let key = java_string(env, &account);
-
-    let context = java_context(env, &activity);
+    for location in &Location::all(env, names, service, account)? {
+        let app = java_string(env, &location.preferences)?;
+        let key = java_string(env, &location.key)?;
+
+        let context = java_context(env, activity);
+        let pref = r#try!(resopt!(
+            context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
+        ));
+        if map_throwable!(pref.contains(Some(&*key)))? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+pub fn delete<'a>(
+    env: &'a JNIEnv,
+    activity: &JObject,
+    names: NameMode,
+    service: &str,
+    account: &str,
+) -> Result<()> {
+    let _store = share_store();
+    for location in &Location::all(env, names, service, account)? {
+        remove(env, activity, location)?;
+    }
+    Ok(())
+}
+
+/// Attributes of an item, from the record beside it. Items stored before
+/// attributes existed have none until they are next written.
+pub fn get_attributes<'a>(
+    env: &'a JNIEnv,
+    activity: &JObject,
+    names: NameMode,
+    service: &str,
+    account: &str,
+) -> Result<Attributes> {
+    for location in &Location::all(env, names, service, account)? {
+        if is_stored(env, activity, location)? {
+            return Ok(read_attributes(env, activity, location)?.unwrap_or_default());
+        }
+    }
+    Err(Error::new(
+        ErrorKind::NotFound,
+        &format!("Couldn't find value for key: {}", account),
+    ))
+}
+
+pub fn set_attributes<'a>(
+    env: &'a JNIEnv,
+    activity: &JObject,
+    names: NameMode,
+    service: &str,
+    account: &str,
+    attributes: &Attributes,
+) -> Result<()> {
+    for location in &Location::all(env, names, service, account)? {
+        if is_stored(env, activity, location)? {
+            let updated = read_attributes(env, activity, location)?
+                .unwrap_or_default()
+                .replaced_by(attributes)
+                .touched(SystemTime::now());
+            return write_attributes(env, activity, location, &updated);
+        }
+    }
+    Err(Error::new(
+        ErrorKind::NotFound,
+        &format!("Couldn't find value for key: {}", account),
+    ))
+}
+
+/// Record that the item at the first of `locations` has just been written,
+/// carrying over the attributes of any copy at the others.
+fn touch_attributes<'a>(env: &'a JNIEnv, activity: &JObject, locations: &[Location]) -> Result<()> {
+    let mut previous = None;
+    for location in locations {
+        // A record which fails verification is replaced
+        if let Ok(Some(attributes)) = read_attributes(env, activity, location) {
+            previous = Some(attributes);
+            break;
+        }
+    }
+    let touched = previous.unwrap_or_default().touched(SystemTime::now());
+    write_attributes(env, activity, &locations[0], &touched)
+}
+
+/// Whether an item is stored at `location`.
+fn is_stored<'a>(env: &'a JNIEnv, activity: &JObject, location: &Location) -> Result<bool> {
+    let app = java_string(env, &location.preferences)?;
+    let key = java_string(env, &location.key)?;
+    let context = java_context(env, activity);
     let pref = r#try!(resopt!(
         context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
     ));
-    stringify_throwable!(pref.contains(Some(&*key)))
+    map_throwable!(pref.contains(Some(&*key)))
 }
 
-pub fn delete<'a>(
+/// The attribute record of the item at `location`, if it has one.
+///
+/// Records are stored in the clear with a MAC under the library's keystore
+/// HMAC key, so reading them needs neither decryption nor authentication.
+/// Fails with `ErrorKind::InvalidData` if the record has been tampered with.
+fn read_attributes<'a>(
     env: &'a JNIEnv,
-    activity: JObject,
-    service: JString,
-    account: JString,
+    activity: &JObject,
+    location: &Location,
+) -> Result<Option<Attributes>> {
+    let app = java_string(env, &naming::ATTRIBUTES)?;
+    let key = java_string(env, &location.attributes_key)?;
+    let context = java_context(env, activity);
+    let pref = r#try!(resopt!(
+        context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
+    ));
+    let stored = match map_throwable!(pref.getString(Some(&*key), None))? {
+        Some(stored) => rust_byte_array(&java_base64_decode(env, &stored)?),
+        None => return Ok(None),
+    };
+    let mac_len = stored.len().checked_sub(MAC_LENGTH);
+    let (record, mac) = match mac_len {
+        Some(len) => stored.split_at(len),
+        None => return Err(tampered(location)),
+    };
+    let expected = name_mac(
+        env,
+        &naming::attributes_mac_input(&location.attributes_key, record),
+    )?;
+    if !constant_time_eq(&expected, mac) {
+        return Err(tampered(location));
+    }
+    attributes::decode(record).map(Some)
+}
+
+fn tampered(location: &Location) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        &format!(
+            "Attributes of {} failed verification",
+            location.attributes_key
+        ),
+    )
+}
+
+fn write_attributes<'a>(
+    env: &'a JNIEnv,
+    activity: &JObject,
+    location: &Location,
+    attributes: &Attributes,
 ) -> Result<()> {
-    let service: String = env
-        .get_string(service)
-        .expect(&format!("Getting java string from '{:?}'", *service))
-        .into();
-    let account: String = env
-        .get_string(account)
-        .expect(&format!("Getting java string '{:?}'", *account))
-        .into();
-
-    let app = java_string(env, &service);
-    let iv_key = java_string(env, &format!("{}iv", &account));
-    let key = java_string(env, &account);
-
-    let context = java_context(env, &activity);
+    let mut stored = attributes::encode(attributes);
+    let mac = name_mac(
+        env,
+        &naming::attributes_mac_input(&location.attributes_key, &stored),
+    )?;
+    stored.extend_from_slice(&mac);
+
+    let app = java_string(env, &naming::ATTRIBUTES)?;
+    let key = java_string(env, &location.attributes_key)?;
+    let stored = java_byte_array(env, &stored);
+    let stored = java_base64_encode(env, &stored)?;
+    let context = java_context(env, activity);
+    let pref = r#try!(resopt!(
+        context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
+    ));
+    let edit = r#try!(resopt!(pref.edit()));
+    let _ = r#try!(resopt!(edit.putString(Some(&*key), Some(&*stored))));
+    commit(&edit)
+}
+
+fn remove_attributes<'a>(env: &'a JNIEnv, activity: &JObject, location: &Location) -> Result<()> {
+    let app = java_string(env, &naming::ATTRIBUTES)?;
+    let key = java_string(env, &location.attributes_key)?;
+    let context = java_context(env, activity);
+    let pref = r#try!(resopt!(
+        context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
+    ));
+    if map_throwable!(pref.contains(Some(&*key)))? {
+        let edit = r#try!(resopt!(pref.edit()));
+        let _ = r#try!(resopt!(edit.remove(Some(&*key))));
+        commit(&edit)?;
+    }
+    Ok(())
+}
+
+/// Every item stored under `service`, including legacy items.
+///
+/// Hashed names can't be mapped back to accounts, so this fails with
+/// `ErrorKind::Unsupported` under `NameMode::Hashed`.
+pub fn list<'a>(
+    env: &'a JNIEnv,
+    activity: &JObject,
+    names: NameMode,
+    service: &str,
+) -> Result<Vec<AccountInfo>> {
+    if names == NameMode::Hashed {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Items with hashed names can't be listed",
+        ));
+    }
+    let provider = java_string(env, &ANDROID_KEYSTORE_PROVIDER)?;
+    let keystore = java_keystore(env, &provider)?;
+    let _ = keystore.load_LoadStoreParameter(None);
+    let aliases = java_keystore_aliases(env, &keystore)?;
+    let keys = preference_keys(env, activity, naming::PREFERENCES)?;
+    let legacy_keys = preference_keys(env, activity, naming::legacy_preferences(service))?;
+    Ok(naming::accounts(service, &keys, &legacy_keys, &aliases)
+        .into_iter()
+        .map(|account| AccountInfo {
+            service: service.to_string(),
+            account: account.to_string(),
+        })
+        .collect())
+}
+
+/// Delete every item the library has stored, under every naming scheme, and
+/// every keystore key of the library, returning the items which could not be
+/// removed.
+///
+/// Legacy items are looked for in every SharedPreferences file of the app
+/// (see `naming::legacy_accounts`), and only their entries are removed from
+/// those files. A legacy key whose entry is already gone can't be told apart
+/// from the app's own keys, and is left. Keys which can't be mapped back to
+/// an item, those of hashed names and the naming key, fail the wipe if they
+/// can't be deleted, once everything else has been tried.
+pub fn wipe<'a>(env: &'a JNIEnv, activity: &JObject) -> Result<Vec<AccountInfo>> {
+    let _store = lock_store();
+    let provider = java_string(env, &ANDROID_KEYSTORE_PROVIDER)?;
+    let keystore = java_keystore(env, &provider)?;
+    let _ = keystore.load_LoadStoreParameter(None);
+    let aliases = java_keystore_aliases(env, &keystore)?;
+    let delete_entry = |alias: &str| -> Result<()> {
+        let alias = java_string(env, &alias)?;
+        map_throwable!(keystore.deleteEntry(Some(&*alias)))
+    };
+    let mut remaining = Vec::new();
+    let mut failure = None;
+
+    let context = java_context(env, activity);
+    for file in shared_preferences_files(env, activity)? {
+        if naming::is_library_file(&file) {
+            continue;
+        }
+        let keys = preference_keys(env, activity, &file)?;
+        let accounts = naming::legacy_accounts(&file, &keys, &aliases);
+        if accounts.is_empty() {
+            continue;
+        }
+        let remove_entries = || -> Result<()> {
+            let app = java_string(env, &file)?;
+            let pref = r#try!(resopt!(
+                context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
+            ));
+            let edit = r#try!(resopt!(pref.edit()));
+            for account in &accounts {
+                for key in &[account.to_string(), naming::legacy_iv_key(account)] {
+                    let key = java_string(env, key)?;
+                    let _ = r#try!(resopt!(edit.remove(Some(&*key))));
+                }
+            }
+            commit(&edit)
+        };
+        let removed = remove_entries();
+        for account in accounts {
+            let alias = naming::legacy_alias(&file, account);
+            let deleted = if aliases.contains(&alias) {
+                delete_entry(&alias)
+            } else {
+                Ok(())
+            };
+            if removed.is_err() || deleted.is_err() {
+                remaining.push(AccountInfo {
+                    service: file.clone(),
+                    account: account.to_string(),
+                });
+            }
+        }
+    }
+
+    for file in &[
+        naming::PREFERENCES,
+        naming::KEY_POLICIES,
+        naming::ATTRIBUTES,
+    ] {
+        let app = java_string(env, file)?;
+        let pref = r#try!(resopt!(
+            context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
+        ));
+        let edit = r#try!(resopt!(pref.edit()));
+        let _ = r#try!(resopt!(edit.clear()));
+        commit(&edit)?;
+    }
+
+    for alias in &aliases {
+        let name = match alias.strip_prefix(naming::ALIAS_PREFIX) {
+            Some(name) => name,
+            None => continue,
+        };
+        if let Err(e) = delete_entry(alias) {
+            match naming::parse_pref_key(name) {
+                Some((service, account)) => remaining.push(AccountInfo {
+                    service: service.to_string(),
+                    account: account.to_string(),
+                }),
+                None => failure = failure.or(Some(e)),
+            }
+        }
+    }
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(remaining),
+    }
+}
+
+/// Every key of a SharedPreferences file, which is empty if it doesn't exist.
+fn preference_keys<'a>(env: &'a JNIEnv, activity: &JObject, file: &str) -> Result<Vec<String>> {
+    let app = java_string(env, &file)?;
+    let context = java_context(env, activity);
+    let pref = r#try!(resopt!(
+        context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
+    ));
+    java_preference_keys(env, &pref)
+}
+
+/// Remove the preference entries of the item at `location`.
+fn remove<'a>(env: &'a JNIEnv, activity: &JObject, location: &Location) -> Result<()> {
+    let app = java_string(env, &location.preferences)?;
+    let key = java_string(env, &location.key)?;
+
+    let context = java_context(env, activity);
+    let pref = r#try!(resopt!(
+        context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
+    ));
+    // Committing to a file which doesn't exist creates it, and legacy files
+    // are named after the service
+    let stored = map_throwable!(pref.contains(Some(&*key)))?;
+    if stored {
+        let edit = r#try!(resopt!(pref.edit()));
+        let _ = r#try!(resopt!(edit.remove(Some(&*key))));
+        if let Some(iv_key) = &location.iv_key {
+            let iv_key = java_string(env, iv_key)?;
+            let _ = r#try!(resopt!(edit.remove(Some(&*iv_key))));
+        }
+        commit(&edit)?;
+    }
+
+    // Legacy aliases are plain concatenations which may equal the alias of
+    // another item, so only delete them along with their entry
+    if stored || !location.is_legacy() {
+        delete_key(env, activity, &location.alias)?;
+        remove_attributes(env, activity, location)?;
+    }
+    Ok(())
+}
+
+/// Delete a keystore key and the record of its policy, if they exist.
+fn delete_key<'a>(env: &'a JNIEnv, activity: &JObject, alias: &str) -> Result<()> {
+    let provider = java_string(env, &ANDROID_KEYSTORE_PROVIDER)?;
+    let java_alias = java_string(env, &alias)?;
+
+    let keystore = java_keystore(env, &provider)?;
+    let _ = keystore.load_LoadStoreParameter(None);
+    if map_throwable!(keystore.containsAlias(Some(&*java_alias)))? {
+        let _ = map_throwable!(keystore.deleteEntry(Some(&*java_alias)))?;
+    }
+
+    let app = java_string(env, &naming::KEY_POLICIES)?;
+    let context = java_context(env, activity);
     let pref = r#try!(resopt!(
         context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
     ));
+    if map_throwable!(pref.contains(Some(&*java_alias)))? {
+        let edit = r#try!(resopt!(pref.edit()));
+        let _ = r#try!(resopt!(edit.remove(Some(&*java_alias))));
+        commit(&edit)?;
+    }
+    Ok(())
+}
 
+/// Bits of the policy the key at `alias` was generated for. The keystore
+/// can't report the whole policy back, so it is recorded alongside.
+fn key_policy<'a>(env: &'a JNIEnv, activity: &JObject, alias: &str) -> Result<Option<u32>> {
+    let app = java_string(env, &naming::KEY_POLICIES)?;
+    let alias = java_string(env, &alias)?;
+
+    let context = java_context(env, activity);
+    let pref = r#try!(resopt!(
+        context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
+    ));
+    if map_throwable!(pref.contains(Some(&*alias)))? {
+        Ok(Some(map_throwable!(pref.getInt(Some(&*alias), 0))? as u32))
+    } else {
+        Ok(None)
+    }
+}
+
+fn set_key_policy<'a>(env: &'a JNIEnv, activity: &JObject, alias: &str, bits: u32) -> Result<()> {
+    let app = java_string(env, &naming::KEY_POLICIES)?;
+    let alias = java_string(env, &alias)?;
+
+    let context = java_context(env, activity);
+    let pref = r#try!(resopt!(
+        context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
+    ));
     let edit = r#try!(resopt!(pref.edit()));
-    let _ = r#try!(resopt!(edit.remove(Some(&*key))));
-    let _ = r#try!(resopt!(edit.remove(Some(&*iv_key))));
-    return match edit.commit() {
+    let _ = r#try!(resopt!(edit.putInt(Some(&*alias), bits as i32)));
+    commit(&edit)
+}
+
+/// Delete keystore keys whose item is gone and items whose key is gone,
+/// returning how many were deleted.
+///
+/// Either can be left behind by an interrupted `put` or `delete`, or by
+/// clearing the app's data. Only names under the current scheme are
+/// considered; other keystore aliases may belong to someone else. Items whose
+/// key is gone can never be decrypted again. Writes in progress, which may
+/// have generated a key without storing its item yet, are waited for.
+pub fn purge_orphans<'a>(env: &'a JNIEnv, activity: &JObject) -> Result<usize> {
+    let _store = lock_store();
+    let app = java_string(env, &naming::PREFERENCES)?;
+    let provider = java_string(env, &ANDROID_KEYSTORE_PROVIDER)?;
+
+    let keystore = java_keystore(env, &provider)?;
+    let _ = keystore.load_LoadStoreParameter(None);
+    let aliases = java_keystore_aliases(env, &keystore)?;
+
+    let context = java_context(env, activity);
+    let pref = r#try!(resopt!(
+        context.getSharedPreferences(Some(&*app), Context::MODE_PRIVATE)
+    ));
+    let keys = java_preference_keys(env, &pref)?;
+
+    let (orphan_aliases, orphan_keys) = naming::orphans(&aliases, &keys);
+    for alias in &orphan_aliases {
+        delete_key(env, activity, alias)?;
+    }
+    if !orphan_keys.is_empty() {
+        let edit = r#try!(resopt!(pref.edit()));
+        for key in &orphan_keys {
+            let key = java_string(env, key)?;
+            let _ = r#try!(resopt!(edit.remove(Some(&*key))));
+        }
+        commit(&edit)?;
+    }
+    Ok(orphan_aliases.len() + orphan_keys.len())
+}
+
+fn commit(edit: &SharedPreferences_Editor) -> Result<()> {
+    match edit.commit() {
         Ok(true) => Ok(()),
-        Ok(false) => Err(format!(
-            "Unknown Android error - failed committing changes to disk."
+        Ok(false) => Err(Error::new(
+            ErrorKind::Io {
+                kind: io::ErrorKind::Other,
+            },
+            "Unknown Android error - failed committing changes to disk.",
         )),
-        Err(e) => Err(format!("{:?}", e.toString().unwrap().unwrap())),
-    };
+        Err(e) => Err(error_from_throwable(e)),
+    }
 }
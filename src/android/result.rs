@@ -1,7 +1,9 @@
+use crate::{Error, ErrorKind};
 use jni_android_sys::java::lang::{Object, Throwable};
 use jni_glue::Local;
+use std::io;
 
-pub type Result<T> = std::result::Result<T, String>;
+pub type Result<T> = std::result::Result<T, Error>;
 
 pub struct ResultOption<T, E>(std::result::Result<Option<T>, E>);
 
@@ -15,22 +17,65 @@ impl<'env, T> Into<Result<T>> for ResultOption<T, Local<'env, Throwable>> {
     fn into(self) -> Result<T> {
         match self.0 {
             Ok(Some(y)) => Ok(y),
-            Ok(None) => Err(format!(
-                "Java function returned NULL - {}:{}",
-                file!(),
-                line!()
+            Ok(None) => Err(Error::new(
+                ErrorKind::Other,
+                &format!("Java function returned NULL - {}:{}", file!(), line!()),
             )),
-            Err(e) => Err(format!("{:?}", e.getMessage().unwrap().unwrap())),
-            //            Ok(None) => Err(format!("Java function returned unexpected null - {}:{}", file!(), line!())),
-            //            Err(e) => {
-            //                let e = unsafe { std::mem::transmute::<Local<'_, Throwable>, Local<'_, Object>>(e) };
-            //                let class = e.getClass().unwrap().unwrap();
-            //                return Err(format!("{:?}", class.getCanonicalName().unwrap().unwrap()));
-            //            }
+            Err(e) => Err(error_from_throwable(e)),
         }
     }
 }
 
+/// Map a Java exception onto an `Error`, picking the kind from its class.
+pub fn error_from_throwable(e: Local<'_, Throwable>) -> Error {
+    let message = match e.getMessage() {
+        Ok(Some(message)) => format!("{:?}", message),
+        _ => String::new(),
+    };
+    let e = unsafe { std::mem::transmute::<Local<'_, Throwable>, Local<'_, Object>>(e) };
+    let class = match e.getClass() {
+        Ok(Some(class)) => match class.getName() {
+            Ok(Some(name)) => format!("{:?}", name),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    };
+    Error::new(kind_from_class(&class), &format!("{}: {}", class, message))
+}
+
+fn kind_from_class(class: &str) -> ErrorKind {
+    if class.contains("KeyPermanentlyInvalidatedException")
+        || class.contains("UnrecoverableKeyException")
+    {
+        ErrorKind::KeyInvalidated
+    } else if class.contains("UserNotAuthenticatedException") {
+        ErrorKind::AuthFailed
+    } else if class.contains("OperationCanceledException") {
+        ErrorKind::UserCanceled
+    } else if class.contains("AEADBadTagException")
+        || class.contains("BadPaddingException")
+        || class.contains("IllegalBlockSizeException")
+        || class.contains("IllegalArgumentException")
+    {
+        ErrorKind::InvalidData
+    } else if class.contains("FileNotFoundException") {
+        ErrorKind::Io {
+            kind: io::ErrorKind::NotFound,
+        }
+    } else if class.contains("IOException") {
+        ErrorKind::Io {
+            kind: io::ErrorKind::Other,
+        }
+    } else {
+        ErrorKind::Other
+    }
+}
+
+/// Map errors raised by the `jni` crate itself.
+pub fn error_from_jni(e: jni::errors::Error) -> Error {
+    Error::new(ErrorKind::Other, &e)
+}
+
 #[macro_export]
 macro_rules! resopt {
     ( $x:expr ) => {
@@ -39,15 +84,8 @@ macro_rules! resopt {
 }
 
 #[macro_export]
-macro_rules! stringify_throwable {
+macro_rules! map_throwable {
     ( $x:expr ) => {
-        $x.map_err(|e| format!("{:?}", e.getMessage().unwrap().unwrap()))
-        //        $x.map_err(|e| {
-        //            use jni_android_sys::java::lang::{Throwable, Object};
-        //            use jni_glue::Local;
-        //            let e = unsafe { std::mem::transmute::<Local<'_, Throwable>, Local<'_, Object>>(e) };
-        //            let class = e.getClass().unwrap().unwrap();
-        //            return format!("{:?}", class.getCanonicalName().unwrap().unwrap());
-        //        })
+        $x.map_err(error_from_throwable)
     };
 }
@@ -1,105 +1,155 @@
-use crate::Return;
-use jni::sys::{jboolean, jstring, JNI_FALSE};
+use super::result::error_from_jni;
+#[cfg(feature = "typed")]
+use crate::Json;
+use crate::{Error, ErrorKind, Return, SecretBytes, SecretString};
+use jni::objects::{JObject, JString};
+use jni::sys::{jboolean, jbyteArray, jobject, jobjectArray, jsize, jstring, JNI_FALSE};
 use jni::JNIEnv;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
 
 impl<'a> Return<'a> for () {
     type Ext = jboolean;
     type Env = JNIEnv<'a>;
-    fn convert(_: &Self::Env, _val: Self) -> Self::Ext {
-        JNI_FALSE
+    fn convert(_: &Self::Env, _val: Self) -> Result<Self::Ext, Error> {
+        Ok(JNI_FALSE)
     }
 }
 
 impl<'a> Return<'a> for bool {
     type Ext = jboolean;
     type Env = JNIEnv<'a>;
-    fn convert(_: &Self::Env, val: Self) -> Self::Ext {
-        val as jboolean
+    fn convert(_: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        Ok(val as jboolean)
     }
 }
 
 impl<'a> Return<'a> for String {
     type Ext = jstring;
     type Env = JNIEnv<'a>;
-    fn convert(env: &Self::Env, val: Self) -> Self::Ext {
+    fn convert(env: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
         env.new_string(val)
-            .expect("Could not create java string")
-            .into_inner()
+            .map(JString::into_inner)
+            .map_err(error_from_jni)
     }
 }
 
-impl<'a, Inner: Return<'a, Env = JNIEnv<'a>> + Default> Return<'a> for Option<Inner> {
-    type Ext = Inner::Ext;
-    type Env = Inner::Env;
+#[cfg(feature = "typed")]
+impl<'a, T: serde::Serialize> Return<'a> for Json<T> {
+    type Ext = jstring;
+    type Env = JNIEnv<'a>;
+    fn convert(env: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        Return::convert(env, val.to_json()?)
+    }
+}
 
-    fn convert(env: &Self::Env, val: Self) -> Self::Ext {
-        use jni::objects::JThrowable;
-        match val {
-            Some(inner) => Return::convert(env, inner),
-            None => {
-                // !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
-                // !!!!                                                              !!!!
-                // !!!! RETURN VALUE HAS TO BE CREATED BEFORE THROWING THE EXCEPTION !!!!
-                // !!!!                                                              !!!!
-                // !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
-                let ret = Return::convert(env, Inner::default());
-                let class = env
-                    .find_class("java/lang/Exception")
-                    .expect("Must have the Exception class; qed");
-                let exception: JThrowable<'a> = env
-                    .new_object(class, "()V", &[])
-                    .expect("Must be able to instantiate the Exception; qed")
-                    .into();
-                // !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
-                // !!!!                                                        !!!!
-                // !!!! WE CAN NO LONGER INTERACT WITH JNIENV AFTER THIS POINT !!!!
-                // !!!!                                                        !!!!
-                // !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
-                env.throw(exception)
-                    .expect("Must be able to throw the Exception; qed");
-                ret
-            }
-        }
+/// Java strings can't be wiped, so prefer returning secrets as bytes.
+impl<'a> Return<'a> for SecretString {
+    type Ext = jstring;
+    type Env = JNIEnv<'a>;
+    fn convert(env: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        env.new_string(val.as_str())
+            .map(JString::into_inner)
+            .map_err(error_from_jni)
     }
+}
 
-    fn convert_without_exception(env: &Self::Env, val: Self) -> Self::Ext {
-        match val {
-            Some(inner) => Return::convert_without_exception(env, inner),
-            None => Return::convert_without_exception(env, Inner::default()),
-        }
+impl<'a> Return<'a> for SecretBytes {
+    type Ext = jbyteArray;
+    type Env = JNIEnv<'a>;
+    fn convert(env: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        env.byte_array_from_slice(&val).map_err(error_from_jni)
     }
 }
 
-impl<'a, Inner: Return<'a, Env = JNIEnv<'a>> + Default> Return<'a> for Result<Inner, String> {
-    type Ext = Inner::Ext;
-    type Env = Inner::Env;
+impl<'a> Return<'a> for Vec<u8> {
+    type Ext = jbyteArray;
+    type Env = JNIEnv<'a>;
+    fn convert(env: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        env.byte_array_from_slice(&val).map_err(error_from_jni)
+    }
+}
 
-    fn convert(env: &Self::Env, val: Self) -> Self::Ext {
-        match val {
-            Ok(inner) => Return::convert(env, inner),
-            Err(exception) => {
-                // !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
-                // !!!!                                                              !!!!
-                // !!!! RETURN VALUE HAS TO BE CREATED BEFORE THROWING THE EXCEPTION !!!!
-                // !!!!                                                              !!!!
-                // !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
-                let ret = Return::convert_without_exception(env, Inner::default());
-                // !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
-                // !!!!                                                        !!!!
-                // !!!! WE CAN NO LONGER INTERACT WITH JNIENV AFTER THIS POINT !!!!
-                // !!!!                                                        !!!!
-                // !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
-                env.throw(exception)
-                    .expect("Must be able to throw the Exception; qed");
-                ret
-            }
+impl<'a> Return<'a> for Vec<String> {
+    type Ext = jobjectArray;
+    type Env = JNIEnv<'a>;
+    fn convert(env: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        let class = env.find_class("java/lang/String").map_err(error_from_jni)?;
+        let array = env
+            .new_object_array(val.len() as jsize, class, JObject::null())
+            .map_err(error_from_jni)?;
+        for (index, s) in val.into_iter().enumerate() {
+            let s = env.new_string(s).map_err(error_from_jni)?;
+            env.set_object_array_element(array, index as jsize, s.into())
+                .map_err(error_from_jni)?;
         }
+        Ok(array)
     }
+}
 
-    fn convert_without_exception(env: &Self::Env, val: Self) -> Self::Ext {
-        match val {
-            Ok(inner) => Return::convert(env, inner),
-            Err(_) => Return::convert_without_exception(env, Inner::default()),
-        }
+/// Values returned to Java by a failed export, alongside the exception.
+pub trait Null {
+    fn null() -> Self;
+}
+
+impl Null for jboolean {
+    fn null() -> Self {
+        JNI_FALSE
+    }
+}
+
+impl Null for jobject {
+    fn null() -> Self {
+        ptr::null_mut()
+    }
+}
+
+/// Run the body of an export, throwing its error as a Java exception.
+///
+/// Panics are caught and thrown as `SecureNativeException`, as unwinding
+/// into the JVM would abort the application.
+pub fn guard<T: Null, F: FnOnce() -> Result<T, Error>>(env: &JNIEnv, export: F) -> T {
+    let error = match panic::catch_unwind(AssertUnwindSafe(export)) {
+        Ok(Ok(ret)) => return ret,
+        Ok(Err(error)) => error,
+        Err(panic) => Error::from_panic(panic),
+    };
+    throw_error(env, &error);
+    T::null()
+}
+
+/// Java class thrown for each `ErrorKind`, all subclasses of
+/// `io.parity.securenative.SecureNativeException`.
+fn exception_class(kind: &ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::NotFound => "io/parity/securenative/NotFoundException",
+        ErrorKind::AuthFailed => "io/parity/securenative/AuthFailedException",
+        ErrorKind::UserCanceled => "io/parity/securenative/UserCanceledException",
+        ErrorKind::InteractionNotAllowed => "io/parity/securenative/InteractionNotAllowedException",
+        ErrorKind::KeyInvalidated => "io/parity/securenative/KeyInvalidatedException",
+        ErrorKind::DuplicateItem => "io/parity/securenative/DuplicateItemException",
+        ErrorKind::InvalidData => "io/parity/securenative/InvalidDataException",
+        ErrorKind::Unsupported => "io/parity/securenative/UnsupportedException",
+        ErrorKind::Io { .. } => "io/parity/securenative/StorageIOException",
+        ErrorKind::Platform { .. } => "io/parity/securenative/PlatformException",
+        ErrorKind::Other => "io/parity/securenative/SecureNativeException",
+    }
+}
+
+/// Throw `error` as the matching `SecureNativeException` subclass.
+///
+/// Falls back to `java.lang.Exception` if the exception classes from `java/`
+/// are not bundled with the application.
+fn throw_error(env: &JNIEnv, error: &Error) {
+    // A failed JNI call may have left its own exception pending
+    let _ = env.exception_clear();
+    let message = error.to_string();
+    if env
+        .throw_new(exception_class(error.kind()), &message)
+        .is_err()
+    {
+        // Looking up the class left a NoClassDefFoundError pending
+        let _ = env.exception_clear();
+        let _ = env.throw_new("java/lang/Exception", message);
     }
 }
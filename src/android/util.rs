@@ -1,37 +1,52 @@
 use super::result::*;
+use crate::{AccessPolicy, Accessibility, Combinator, Constraint, Error, ErrorKind, SecretBytes};
 
 use jni::objects::JObject;
 use jni::strings::JNIString;
 use jni::sys::jsize;
 use jni::JNIEnv;
-use jni_glue::{ByteArray, Local, ObjectArray};
+use jni_glue::{AsValidJObjectAndEnv, ByteArray, Local, ObjectArray, PrimitiveArray};
+use zeroize::Zeroize;
 
-use android::content::{Context, Intent};
+use android::content::{Context, Intent, SharedPreferences};
 use android::security::keystore::{
-    KeyGenParameterSpec, KeyGenParameterSpec_Builder, KeyProperties,
+    KeyGenParameterSpec, KeyGenParameterSpec_Builder, KeyInfo, KeyProperties,
 };
 use android::util::Base64;
-use java::lang::{CharSequence, Throwable};
-use java::security::spec::AlgorithmParameterSpec;
+use java::lang::{CharSequence, Object, Throwable};
+use java::security::spec::{AlgorithmParameterSpec, KeySpec};
 use java::security::{Key, KeyStore};
-use javax::crypto::spec::IvParameterSpec;
-use javax::crypto::{Cipher, KeyGenerator, SecretKey};
+use javax::crypto::spec::{GCMParameterSpec, IvParameterSpec};
+use javax::crypto::{Cipher, KeyGenerator, Mac, SecretKey, SecretKeyFactory};
 use jni_android_sys::*;
+use std::{fs, io, path::Path};
 
 pub type JavaString = java::lang::String;
 
-pub fn java_string<'a, S>(env: &'a JNIEnv, s: &S) -> Local<'a, JavaString>
+/// Seconds a key stays usable after the user authenticates, for policies
+/// that accept the device passcode. Before API 30 a key can only accept the
+/// passcode through a validity window, not on every use.
+const CREDENTIAL_VALIDITY_SECONDS: i32 = 5;
+
+/// Length in bits of the GCM authentication tag.
+pub const GCM_TAG_LENGTH: i32 = 128;
+
+/// First API level with `KeyGenParameterSpec.Builder.setUnlockedDeviceRequired`.
+const API_UNLOCKED_DEVICE_REQUIRED: i32 = 28;
+
+pub fn java_string<'a, S>(env: &'a JNIEnv, s: &S) -> Result<Local<'a, JavaString>>
 where
     S: Into<JNIString> + std::fmt::Debug + std::convert::AsRef<str>,
 {
-    unsafe {
-        Local::from_env_object(
-            env.get_native_interface(),
-            env.new_string(s)
-                .expect(&format!("Creating java string for '{:?}'", s))
-                .into_inner(),
-        )
-    }
+    let string = env.new_string(s).map_err(error_from_jni)?;
+    Ok(unsafe { Local::from_env_object(env.get_native_interface(), string.into_inner()) })
+}
+
+pub fn rust_string(env: &JNIEnv, s: &JavaString) -> Result<String> {
+    let object = s.as_valid_jobject_and_env().object;
+    env.get_string(object.into())
+        .map(Into::into)
+        .map_err(error_from_jni)
 }
 
 pub fn java_string_array<'a>(
@@ -39,16 +54,19 @@ pub fn java_string_array<'a>(
     size: jsize,
 ) -> Result<Local<'a, ObjectArray<JavaString, Throwable>>> {
     unsafe {
-        let class = env
-            .find_class("java/lang/String")
-            .map_err(|e| e.description().to_string())?;
+        let class = env.find_class("java/lang/String").map_err(error_from_jni)?;
         let object = env
             .new_object_array(size, class, JObject::null())
-            .map_err(|e| e.description().to_string())?;
-        let exception = env
-            .exception_occurred()
-            .map_err(|e| e.description().to_string())?;
-        assert!(exception.is_null()); // Only sane exception here is an OOM exception
+            .map_err(error_from_jni)?;
+        let exception = env.exception_occurred().map_err(error_from_jni)?;
+        if !exception.is_null() {
+            // Only sane exception here is an OOM exception
+            let _ = env.exception_clear();
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Creating java string array failed",
+            ));
+        }
         Ok(Local::from_env_object(env.get_native_interface(), object))
     }
 }
@@ -71,10 +89,10 @@ pub fn java_algorithm_parameter_spec<'a>(
     block_mode: &'a ObjectArray<JavaString, Throwable>,
     padding: &'a ObjectArray<JavaString, Throwable>,
     key_size: i32,
-    with_biometry: bool,
+    policy: &AccessPolicy,
 ) -> Result<Local<'a, AlgorithmParameterSpec>> {
     let x: Local<'a, KeyGenParameterSpec_Builder> =
-        stringify_throwable!(KeyGenParameterSpec_Builder::new(
+        map_throwable!(KeyGenParameterSpec_Builder::new(
             unsafe { jni_glue::Env::from_ptr(env.get_native_interface()) },
             alias,
             KeyProperties::PURPOSE_ENCRYPT | KeyProperties::PURPOSE_DECRYPT,
@@ -83,12 +101,7 @@ pub fn java_algorithm_parameter_spec<'a>(
     r#try!(resopt!(x.setBlockModes(Some(&*block_mode))));
     r#try!(resopt!(x.setEncryptionPaddings(Some(&*padding))));
     r#try!(resopt!(x.setRandomizedEncryptionRequired(true))); // indistinguishability under chosen-plaintext attack (IND-CPA)
-    if with_biometry {
-        r#try!(resopt!(x.setUserAuthenticationRequired(true))); // requires biometric auth before every key use; at least one fingerprint must be enrolled
-    } else {
-        r#try!(resopt!(x.setUserAuthenticationRequired(false)));
-    }
-    //    r#try!(resopt!(x.setInvalidatedByBiometricEnrollment(false))); // defaults to true
+    apply_access_policy(env, &x, policy)?;
     let built = r#try!(resopt!(x.build()));
     Ok(unsafe {
         std::mem::transmute::<Local<'_, KeyGenParameterSpec>, Local<'_, AlgorithmParameterSpec>>(
@@ -97,11 +110,102 @@ pub fn java_algorithm_parameter_spec<'a>(
     })
 }
 
+/// Parameters of the keystore key used to hash item names. It needs no user
+/// authentication: names are hashed before every operation, including
+/// `contains`.
+pub fn java_mac_parameter_spec<'a>(
+    env: &'a JNIEnv,
+    alias: &'a JavaString,
+) -> Result<Local<'a, AlgorithmParameterSpec>> {
+    let x: Local<'a, KeyGenParameterSpec_Builder> =
+        map_throwable!(KeyGenParameterSpec_Builder::new(
+            unsafe { jni_glue::Env::from_ptr(env.get_native_interface()) },
+            alias,
+            KeyProperties::PURPOSE_SIGN,
+        ))?;
+    let built = r#try!(resopt!(x.build()));
+    Ok(unsafe {
+        std::mem::transmute::<Local<'_, KeyGenParameterSpec>, Local<'_, AlgorithmParameterSpec>>(
+            built,
+        )
+    })
+}
+
+/// Map an `AccessPolicy` onto the key's authentication parameters.
+///
+/// Biometric constraints require authentication before every key use. A
+/// policy that accepts the passcode instead uses a short validity window,
+/// which also accepts biometry. Requiring both biometry and the passcode, or
+/// an application password, has no Android equivalent.
+fn apply_access_policy(
+    env: &JNIEnv,
+    builder: &KeyGenParameterSpec_Builder,
+    policy: &AccessPolicy,
+) -> Result<()> {
+    let unsupported = |what: &str| {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            &format!("{} is not available on Android", what),
+        ))
+    };
+    let (mut biometry_any, mut biometry_current_set, mut passcode) = (false, false, false);
+    for constraint in policy.constraints() {
+        match constraint {
+            Constraint::BiometryAny => biometry_any = true,
+            Constraint::BiometryCurrentSet => biometry_current_set = true,
+            Constraint::UserPresence | Constraint::DevicePasscode => passcode = true,
+            Constraint::ApplicationPassword => return unsupported("An application password"),
+        }
+    }
+    let biometry = biometry_any || biometry_current_set;
+    if biometry && passcode && policy.combinator() == Combinator::And {
+        return unsupported("Requiring both biometry and the device passcode");
+    }
+
+    if !biometry && !passcode {
+        r#try!(resopt!(builder.setUserAuthenticationRequired(false)));
+    } else {
+        r#try!(resopt!(builder.setUserAuthenticationRequired(true)));
+        if passcode {
+            r#try!(resopt!(builder
+                .setUserAuthenticationValidityDurationSeconds(
+                    CREDENTIAL_VALIDITY_SECONDS
+                )));
+        } else {
+            // requires biometric auth before every key use; at least one fingerprint must be enrolled
+            r#try!(resopt!(
+                builder.setUserAuthenticationValidityDurationSeconds(-1)
+            ));
+        }
+        r#try!(resopt!(builder.setInvalidatedByBiometricEnrollment(
+            biometry_current_set || !biometry_any
+        )));
+    }
+
+    let unlocked = match policy.accessibility() {
+        Accessibility::WhenUnlocked
+        | Accessibility::WhenUnlockedThisDeviceOnly
+        | Accessibility::WhenPasscodeSetThisDeviceOnly => true,
+        Accessibility::AfterFirstUnlock | Accessibility::AfterFirstUnlockThisDeviceOnly => false,
+    };
+    if unlocked && sdk_int(env)? >= API_UNLOCKED_DEVICE_REQUIRED {
+        r#try!(resopt!(builder.setUnlockedDeviceRequired(true)));
+    }
+    Ok(())
+}
+
+/// `android.os.Build.VERSION.SDK_INT` of the running device.
+pub fn sdk_int(env: &JNIEnv) -> Result<i32> {
+    env.get_static_field("android/os/Build$VERSION", "SDK_INT", "I")
+        .and_then(|value| value.i())
+        .map_err(error_from_jni)
+}
+
 pub fn java_algorithm_parameter_spec_from_bytes<'a>(
     env: &'a JNIEnv,
     iv: &'a ByteArray,
 ) -> Result<Local<'a, AlgorithmParameterSpec>> {
-    let spec = stringify_throwable!(IvParameterSpec::new_byte_array(
+    let spec = map_throwable!(IvParameterSpec::new_byte_array(
         unsafe { jni_glue::Env::from_ptr(env.get_native_interface()) },
         Some(iv)
     ))?;
@@ -110,6 +214,43 @@ pub fn java_algorithm_parameter_spec_from_bytes<'a>(
     })
 }
 
+pub fn java_gcm_parameter_spec<'a>(
+    env: &'a JNIEnv,
+    iv: &'a ByteArray,
+) -> Result<Local<'a, AlgorithmParameterSpec>> {
+    let spec = map_throwable!(GCMParameterSpec::new_int_byte_array(
+        unsafe { jni_glue::Env::from_ptr(env.get_native_interface()) },
+        GCM_TAG_LENGTH,
+        Some(iv)
+    ))?;
+    Ok(unsafe {
+        std::mem::transmute::<Local<'_, GCMParameterSpec>, Local<'_, AlgorithmParameterSpec>>(spec)
+    })
+}
+
+/// Whether a keystore key was generated with `setUserAuthenticationRequired(true)`.
+pub fn java_key_requires_authentication<'a>(
+    env: &'a JNIEnv,
+    algorithm: &'a JavaString,
+    provider: &'a JavaString,
+    key: &'a Key,
+) -> Result<bool> {
+    let factory = r#try!(resopt!(SecretKeyFactory::getInstance_String_String(
+        unsafe { jni_glue::Env::from_ptr(env.get_native_interface()) },
+        algorithm,
+        provider
+    )));
+    let class = env
+        .find_class("android/security/keystore/KeyInfo")
+        .map_err(error_from_jni)?;
+    let class: Local<'a, java::lang::Class> =
+        unsafe { Local::from_env_object(env.get_native_interface(), class.into_inner()) };
+    let key = unsafe { std::mem::transmute::<&Key, &SecretKey>(key) };
+    let spec = r#try!(resopt!(factory.getKeySpec(Some(key), Some(&*class))));
+    let info = unsafe { std::mem::transmute::<Local<'_, KeySpec>, Local<'_, KeyInfo>>(spec) };
+    map_throwable!(info.isUserAuthenticationRequired())
+}
+
 pub fn java_generate_key<'a>(keygen: &'a KeyGenerator) -> Result<Local<'a, Key>> {
     let key = r#try!(resopt!(keygen.generateKey()));
     Ok(unsafe { std::mem::transmute::<Local<'_, SecretKey>, Local<'_, Key>>(key) })
@@ -126,14 +267,27 @@ pub fn java_cipher<'a>(
         unsafe { jni_glue::Env::from_ptr(env.get_native_interface()) },
         transform
     )));
-    let _ = stringify_throwable!(cipher.init_int_Key_AlgorithmParameterSpec(
-        mode,
-        Some(&*secret_key),
-        spec
-    ))?;
+    let _ =
+        map_throwable!(cipher.init_int_Key_AlgorithmParameterSpec(mode, Some(&*secret_key), spec))?;
     Ok(cipher)
 }
 
+pub fn java_mac<'a>(
+    env: &'a JNIEnv,
+    algorithm: &'a JavaString,
+    key: Local<'a, Key>,
+    bytes: &[u8],
+) -> Result<Vec<u8>> {
+    let mac = r#try!(resopt!(Mac::getInstance_String(
+        unsafe { jni_glue::Env::from_ptr(env.get_native_interface()) },
+        algorithm
+    )));
+    let _ = map_throwable!(mac.init_Key(Some(&*key)))?;
+    let input = java_byte_array(env, bytes);
+    let output = r#try!(resopt!(mac.doFinal_byte_array(Some(&*input))));
+    Ok(rust_byte_array(&output))
+}
+
 pub fn java_base64_encode<'a>(
     env: &'a JNIEnv,
     bytes: &'a ByteArray,
@@ -163,3 +317,98 @@ pub fn java_keystore<'a>(env: &'a JNIEnv, provider: &'a JavaString) -> Result<Lo
         provider
     ))
 }
+
+/// Every alias in a loaded keystore.
+pub fn java_keystore_aliases(env: &JNIEnv, keystore: &KeyStore) -> Result<Vec<String>> {
+    let aliases = r#try!(resopt!(keystore.aliases()));
+    let mut result = Vec::new();
+    while map_throwable!(aliases.hasMoreElements())? {
+        let alias = r#try!(resopt!(aliases.nextElement()));
+        let alias =
+            unsafe { std::mem::transmute::<Local<'_, Object>, Local<'_, JavaString>>(alias) };
+        result.push(rust_string(env, &alias)?);
+    }
+    Ok(result)
+}
+
+/// Names of the app's SharedPreferences files, found in its `shared_prefs`
+/// directory.
+pub fn shared_preferences_files(env: &JNIEnv, activity: &JObject) -> Result<Vec<String>> {
+    let info = env
+        .call_method(
+            *activity,
+            "getApplicationInfo",
+            "()Landroid/content/pm/ApplicationInfo;",
+            &[],
+        )
+        .and_then(|info| info.l())
+        .map_err(error_from_jni)?;
+    let data_dir: String = env
+        .get_field(info, "dataDir", "Ljava/lang/String;")
+        .and_then(|dir| dir.l())
+        .and_then(|dir| env.get_string(dir.into()))
+        .map(Into::into)
+        .map_err(error_from_jni)?;
+    let dir = Path::new(&data_dir).join("shared_prefs");
+    let io_error = |e: io::Error| {
+        Error::new(
+            ErrorKind::Io { kind: e.kind() },
+            &format!("Listing {}: {}", dir.display(), e),
+        )
+    };
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(io_error(e)),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let name = entry.map_err(io_error)?.file_name();
+        if let Some(name) = name.to_str().and_then(|name| name.strip_suffix(".xml")) {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Every key in a SharedPreferences file.
+pub fn java_preference_keys(env: &JNIEnv, pref: &SharedPreferences) -> Result<Vec<String>> {
+    let all = r#try!(resopt!(pref.getAll()));
+    let keys = r#try!(resopt!(all.keySet()));
+    let keys = r#try!(resopt!(keys.iterator()));
+    let mut result = Vec::new();
+    while map_throwable!(keys.hasNext())? {
+        let key = r#try!(resopt!(keys.next()));
+        let key = unsafe { std::mem::transmute::<Local<'_, Object>, Local<'_, JavaString>>(key) };
+        result.push(rust_string(env, &key)?);
+    }
+    Ok(result)
+}
+
+pub fn java_byte_array<'a>(env: &'a JNIEnv, bytes: &[u8]) -> Local<'a, ByteArray> {
+    let bytes = unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const i8, bytes.len()) };
+    ByteArray::from(
+        unsafe { jni_glue::Env::from_ptr(env.get_native_interface()) },
+        bytes,
+    )
+}
+
+pub fn rust_byte_array(bytes: &ByteArray) -> Vec<u8> {
+    bytes.as_vec().into_iter().map(|b| b as u8).collect()
+}
+
+/// Copy a Java byte array holding a secret, then overwrite it and the
+/// intermediate copy with zeros.
+pub fn rust_secret_byte_array(bytes: &ByteArray) -> SecretBytes {
+    let mut raw = bytes.as_vec();
+    let secret = SecretBytes::from(raw.iter().map(|&b| b as u8).collect::<Vec<u8>>());
+    raw.zeroize();
+    java_wipe_byte_array(bytes);
+    secret
+}
+
+/// Overwrite a Java byte array with zeros.
+pub fn java_wipe_byte_array(bytes: &ByteArray) {
+    bytes.set_region(0, &vec![0; bytes.len()]);
+}
@@ -0,0 +1,320 @@
+//! Non-secret metadata of items
+//!
+//! Every item carries a label, a description, creation and modification
+//! times and a small map of custom attributes. They are stored beside the
+//! value rather than with it, so reading them never decrypts the value or
+//! asks the user to authenticate. They are not secret: don't put anything in
+//! them that the value protects.
+//!
+//! Backends without a native place for them store a record:
+//!
+//! ```text
+//! version: u8 | created: u64 | modified: u64 | label | description | custom
+//! custom: count: u32 | (key | value) *
+//! ```
+//!
+//! Times are milliseconds since the Unix epoch, with 0 for unknown. Integers
+//! are little-endian and fields are prefixed with their length as a `u32`.
+//! Backends which store the custom map on its own use the `custom` encoding
+//! prefixed with the version. Parsing never panics on malformed input.
+
+use crate::{Error, ErrorKind};
+
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Current record format version.
+pub const VERSION: u8 = 1;
+
+/// Largest encoded size of the custom attributes of an item, in bytes.
+pub const MAX_CUSTOM_LEN: usize = 1024;
+
+/// Metadata of an item.
+///
+/// Empty labels and descriptions are the same as none.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Attributes {
+    /// User-visible name of the item
+    pub label: Option<String>,
+    /// User-visible explanation of what the item is
+    pub description: Option<String>,
+    /// When the item was first stored. Set by the store.
+    pub created: Option<SystemTime>,
+    /// When the item's value or attributes last changed. Set by the store.
+    pub modified: Option<SystemTime>,
+    /// Application-defined attributes
+    pub custom: BTreeMap<String, String>,
+}
+
+impl Attributes {
+    /// The attributes of an item whose value or attributes change at `now`.
+    pub fn touched(self, now: SystemTime) -> Self {
+        Attributes {
+            created: self.created.or(Some(now)),
+            modified: Some(now),
+            ..self
+        }
+    }
+
+    /// The label, description and custom attributes of `other`, keeping the
+    /// times of `self`.
+    pub fn replaced_by(self, other: &Attributes) -> Self {
+        Attributes {
+            label: other.label.clone().filter(|label| !label.is_empty()),
+            description: other
+                .description
+                .clone()
+                .filter(|description| !description.is_empty()),
+            custom: other.custom.clone(),
+            ..self
+        }
+    }
+}
+
+/// Check that the attributes can be stored.
+///
+/// Fails with `ErrorKind::InvalidData` if the custom attributes are larger
+/// than `MAX_CUSTOM_LEN` once encoded.
+pub fn check(attributes: &Attributes) -> Result<(), Error> {
+    let len = encode_custom(&attributes.custom).len();
+    if len > MAX_CUSTOM_LEN {
+        return Err(invalid(&format!(
+            "Custom attributes take {} bytes, more than {}",
+            len, MAX_CUSTOM_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// Serialize `attributes` as a record in the current format version.
+pub fn encode(attributes: &Attributes) -> Vec<u8> {
+    let mut out = vec![VERSION];
+    out.extend_from_slice(&millis(attributes.created).to_le_bytes());
+    out.extend_from_slice(&millis(attributes.modified).to_le_bytes());
+    write_field(
+        &mut out,
+        attributes.label.as_deref().unwrap_or("").as_bytes(),
+    );
+    write_field(
+        &mut out,
+        attributes.description.as_deref().unwrap_or("").as_bytes(),
+    );
+    write_custom(&mut out, &attributes.custom);
+    out
+}
+
+/// Parse a record.
+///
+/// Fails with `ErrorKind::InvalidData` on unknown versions and on truncated
+/// input.
+pub fn decode(mut bytes: &[u8]) -> Result<Attributes, Error> {
+    read_version(&mut bytes)?;
+    let created = time(read_u64(&mut bytes)?);
+    let modified = time(read_u64(&mut bytes)?);
+    let label = Some(read_string(&mut bytes)?).filter(|label| !label.is_empty());
+    let description = Some(read_string(&mut bytes)?).filter(|d| !d.is_empty());
+    let custom = read_custom(&mut bytes)?;
+    finish(bytes)?;
+    Ok(Attributes {
+        label,
+        description,
+        created,
+        modified,
+        custom,
+    })
+}
+
+/// Serialize custom attributes on their own, in the current format version.
+pub fn encode_custom(custom: &BTreeMap<String, String>) -> Vec<u8> {
+    let mut out = vec![VERSION];
+    write_custom(&mut out, custom);
+    out
+}
+
+/// Parse custom attributes serialized by `encode_custom`.
+pub fn decode_custom(mut bytes: &[u8]) -> Result<BTreeMap<String, String>, Error> {
+    read_version(&mut bytes)?;
+    let custom = read_custom(&mut bytes)?;
+    finish(bytes)?;
+    Ok(custom)
+}
+
+fn millis(time: Option<SystemTime>) -> u64 {
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+fn time(millis: u64) -> Option<SystemTime> {
+    if millis == 0 {
+        None
+    } else {
+        UNIX_EPOCH.checked_add(Duration::from_millis(millis))
+    }
+}
+
+fn write_field(out: &mut Vec<u8>, field: &[u8]) {
+    out.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    out.extend_from_slice(field);
+}
+
+fn write_custom(out: &mut Vec<u8>, custom: &BTreeMap<String, String>) {
+    out.extend_from_slice(&(custom.len() as u32).to_le_bytes());
+    for (key, value) in custom {
+        write_field(out, key.as_bytes());
+        write_field(out, value.as_bytes());
+    }
+}
+
+fn read_version(bytes: &mut &[u8]) -> Result<(), Error> {
+    match bytes.split_first() {
+        Some((&VERSION, rest)) => {
+            *bytes = rest;
+            Ok(())
+        }
+        Some((version, _)) => Err(invalid(&format!(
+            "Unsupported attributes version: {}",
+            version
+        ))),
+        None => Err(invalid("Truncated attributes")),
+    }
+}
+
+fn read_custom(bytes: &mut &[u8]) -> Result<BTreeMap<String, String>, Error> {
+    let count = read_u32(bytes)?;
+    let mut custom = BTreeMap::new();
+    for _ in 0..count {
+        let key = read_string(bytes)?;
+        let value = read_string(bytes)?;
+        custom.insert(key, value);
+    }
+    Ok(custom)
+}
+
+fn finish(bytes: &[u8]) -> Result<(), Error> {
+    if bytes.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid("Trailing data in attributes"))
+    }
+}
+
+fn read_u32(bytes: &mut &[u8]) -> Result<u32, Error> {
+    Ok(u32::from_le_bytes(read_array(bytes)?))
+}
+
+fn read_u64(bytes: &mut &[u8]) -> Result<u64, Error> {
+    Ok(u64::from_le_bytes(read_array(bytes)?))
+}
+
+fn read_array<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N], Error> {
+    if bytes.len() < N {
+        return Err(invalid("Truncated attributes"));
+    }
+    let (head, rest) = bytes.split_at(N);
+    *bytes = rest;
+    Ok(head.try_into().unwrap())
+}
+
+fn read_field<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+    let len = read_u32(bytes)? as usize;
+    if bytes.len() < len {
+        return Err(invalid("Truncated attributes"));
+    }
+    let (field, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(field)
+}
+
+fn read_string(bytes: &mut &[u8]) -> Result<String, Error> {
+    String::from_utf8(read_field(bytes)?.to_vec()).map_err(|e| invalid(&e))
+}
+
+fn invalid<D: ToString + ?Sized>(description: &D) -> Error {
+    Error::new(ErrorKind::InvalidData, description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes() -> Attributes {
+        let mut custom = BTreeMap::new();
+        custom.insert("kind".to_string(), "oauth".to_string());
+        custom.insert("é".to_string(), String::new());
+        Attributes {
+            label: Some("Token".to_string()),
+            description: None,
+            created: Some(UNIX_EPOCH + Duration::from_millis(1_600_000_000_000)),
+            modified: Some(UNIX_EPOCH + Duration::from_millis(1_600_000_000_123)),
+            custom,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(decode(&encode(&attributes())).unwrap(), attributes());
+        assert_eq!(
+            decode(&encode(&Attributes::default())).unwrap(),
+            Attributes::default()
+        );
+        let custom = attributes().custom;
+        assert_eq!(decode_custom(&encode_custom(&custom)).unwrap(), custom);
+    }
+
+    #[test]
+    fn test_malformed_input_is_rejected() {
+        let bytes = encode(&attributes());
+        for len in 0..bytes.len() {
+            let error = decode(&bytes[..len]).unwrap_err();
+            assert_eq!(error.kind(), &ErrorKind::InvalidData);
+        }
+        let mut unknown = bytes.clone();
+        unknown[0] = 2;
+        assert_eq!(
+            decode(&unknown).unwrap_err().kind(),
+            &ErrorKind::InvalidData
+        );
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert_eq!(
+            decode(&trailing).unwrap_err().kind(),
+            &ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_times_and_replacement() {
+        let now = UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+        let touched = Attributes::default().touched(now);
+        assert_eq!((touched.created, touched.modified), (Some(now), Some(now)));
+        let later = now + Duration::from_secs(1);
+        let replaced = touched.touched(later).replaced_by(&Attributes {
+            label: Some(String::new()),
+            description: Some("refresh token".to_string()),
+            created: None,
+            modified: None,
+            custom: attributes().custom,
+        });
+        assert_eq!(replaced.created, Some(now));
+        assert_eq!(replaced.modified, Some(later));
+        assert_eq!(replaced.label, None);
+        assert_eq!(replaced.description.as_deref(), Some("refresh token"));
+        assert_eq!(replaced.custom, attributes().custom);
+    }
+
+    #[test]
+    fn test_large_custom_attributes_are_rejected() {
+        let mut attributes = Attributes::default();
+        check(&attributes).unwrap();
+        attributes
+            .custom
+            .insert("big".to_string(), "x".repeat(MAX_CUSTOM_LEN));
+        assert_eq!(
+            check(&attributes).unwrap_err().kind(),
+            &ErrorKind::InvalidData
+        );
+    }
+}
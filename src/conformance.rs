@@ -0,0 +1,394 @@
+//! Behaviour shared by all `SecureStore` backends
+//!
+//! Each check panics on the first violation, so the suite can be called from
+//! a `#[test]` on the host or from an instrumented test on a device:
+//!
+//! ```ignore
+//! secure_native::conformance::run(&store);
+//! ```
+//!
+//! Checks only touch services prefixed with `SERVICE` and remove what they
+//! write, except `wipe_removes_everything`, which is not part of `run`.
+
+use crate::{digest, AccessPolicy, Attributes, ErrorKind, SecureStore};
+
+pub const SERVICE: &str = "secure-native-conformance";
+
+/// Run every check against `store`.
+pub fn run<S: SecureStore>(store: &S) {
+    put_then_get(store);
+    put_overwrites(store);
+    binary_values_round_trip(store);
+    get_missing_is_error(store);
+    delete_missing_is_idempotent(store);
+    contains_follows_put_and_delete(store);
+    pairs_are_isolated(store);
+    list_follows_put_and_delete(store);
+    delete_all_removes_one_service(store);
+    conditional_writes(store);
+    transaction_applies_every_write(store);
+    attributes_follow_writes(store);
+    #[cfg(feature = "typed")]
+    typed_values_round_trip(store);
+}
+
+/// A stored value reads back unchanged.
+pub fn put_then_get<S: SecureStore>(store: &S) {
+    let service = service("put-then-get");
+    store
+        .put(&service, "account", "value", &AccessPolicy::default())
+        .unwrap();
+    assert_eq!(&*store.get(&service, "account").unwrap(), "value");
+    store.delete(&service, "account").unwrap();
+}
+
+/// A second put replaces the first value.
+pub fn put_overwrites<S: SecureStore>(store: &S) {
+    let service = service("put-overwrites");
+    store
+        .put(&service, "account", "first", &AccessPolicy::default())
+        .unwrap();
+    store
+        .put(&service, "account", "second", &AccessPolicy::default())
+        .unwrap();
+    assert_eq!(&*store.get(&service, "account").unwrap(), "second");
+    store.delete(&service, "account").unwrap();
+}
+
+/// Arbitrary bytes, including invalid UTF-8 and NULs, read back unchanged.
+pub fn binary_values_round_trip<S: SecureStore>(store: &S) {
+    let service = service("binary");
+    let seed: Vec<u8> = (0..32u8).map(|i| i.wrapping_mul(151) ^ 0x80).collect();
+    store
+        .put_bytes(&service, "seed", &seed, &AccessPolicy::default())
+        .unwrap();
+    assert_eq!(&*store.get_bytes(&service, "seed").unwrap(), seed);
+    store
+        .put_bytes(&service, "empty", &[], &AccessPolicy::default())
+        .unwrap();
+    assert_eq!(
+        &*store.get_bytes(&service, "empty").unwrap(),
+        Vec::<u8>::new()
+    );
+    store.delete(&service, "seed").unwrap();
+    store.delete(&service, "empty").unwrap();
+}
+
+/// Reading a missing item fails with `ErrorKind::NotFound` instead of
+/// returning an empty value.
+pub fn get_missing_is_error<S: SecureStore>(store: &S) {
+    let service = service("get-missing");
+    store.delete(&service, "account").unwrap();
+    let error = store.get_bytes(&service, "account").err().unwrap();
+    assert_eq!(error.kind(), &ErrorKind::NotFound);
+}
+
+/// Deleting a missing item succeeds, as does deleting twice.
+pub fn delete_missing_is_idempotent<S: SecureStore>(store: &S) {
+    let service = service("delete-missing");
+    store.delete(&service, "account").unwrap();
+    store
+        .put(&service, "account", "value", &AccessPolicy::default())
+        .unwrap();
+    store.delete(&service, "account").unwrap();
+    store.delete(&service, "account").unwrap();
+    assert!(!store.contains(&service, "account").unwrap());
+}
+
+/// `contains` reports exactly the items that have been put and not deleted.
+pub fn contains_follows_put_and_delete<S: SecureStore>(store: &S) {
+    let service = service("contains");
+    store.delete(&service, "account").unwrap();
+    assert!(!store.contains(&service, "account").unwrap());
+    store
+        .put(&service, "account", "value", &AccessPolicy::default())
+        .unwrap();
+    assert!(store.contains(&service, "account").unwrap());
+    store.delete(&service, "account").unwrap();
+    assert!(!store.contains(&service, "account").unwrap());
+}
+
+/// Writes to one service/account pair never affect another, including pairs
+/// whose concatenations are equal.
+pub fn pairs_are_isolated<S: SecureStore>(store: &S) {
+    let a = service("isolated-a");
+    let b = service("isolated-b");
+    let pairs = [
+        (a.as_str(), "account"),
+        (b.as_str(), "account"),
+        (a.as_str(), "other"),
+        (a.as_str(), "accountiv"),
+        (&a[..a.len() - 1], "aaccount"),
+    ];
+    for (i, &(service, account)) in pairs.iter().enumerate() {
+        store
+            .put(service, account, &i.to_string(), &AccessPolicy::default())
+            .unwrap();
+    }
+    for (i, &(service, account)) in pairs.iter().enumerate() {
+        assert_eq!(&*store.get(service, account).unwrap(), i.to_string());
+    }
+    store.delete(&a, "account").unwrap();
+    assert!(store.contains(&b, "account").unwrap());
+    assert!(store.contains(&a, "other").unwrap());
+    assert!(store.contains(&a, "accountiv").unwrap());
+    for &(service, account) in pairs.iter() {
+        store.delete(service, account).unwrap();
+    }
+}
+
+/// `list` reports the accounts of exactly one service, in order.
+pub fn list_follows_put_and_delete<S: SecureStore>(store: &S) {
+    let service = service("list");
+    let other = format!("{}-other", service);
+    let accounts = |service: &str| -> Vec<String> {
+        let items = store.list(service).unwrap();
+        assert!(items.iter().all(|item| item.service == service));
+        items.into_iter().map(|item| item.account).collect()
+    };
+    for account in &["b", "a", "aiv"] {
+        store
+            .put(&service, account, "value", &AccessPolicy::default())
+            .unwrap();
+    }
+    store
+        .put(&other, "c", "value", &AccessPolicy::default())
+        .unwrap();
+    assert_eq!(accounts(&service), vec!["a", "aiv", "b"]);
+    store.delete(&service, "a").unwrap();
+    assert_eq!(accounts(&service), vec!["aiv", "b"]);
+    for account in &["b", "aiv"] {
+        store.delete(&service, account).unwrap();
+    }
+    store.delete(&other, "c").unwrap();
+    assert!(accounts(&service).is_empty());
+}
+
+/// `delete_all` removes every account of one service and nothing else.
+pub fn delete_all_removes_one_service<S: SecureStore>(store: &S) {
+    let service = service("delete-all");
+    let other = format!("{}-other", service);
+    for account in &["a", "b"] {
+        store
+            .put(&service, account, "value", &AccessPolicy::default())
+            .unwrap();
+    }
+    store
+        .put(&other, "a", "value", &AccessPolicy::default())
+        .unwrap();
+    assert!(store.delete_all(&service).unwrap().is_empty());
+    assert!(store.list(&service).unwrap().is_empty());
+    assert!(store.contains(&other, "a").unwrap());
+    assert!(store.delete_all(&service).unwrap().is_empty());
+    store.delete(&other, "a").unwrap();
+}
+
+/// Conditional writes only apply when their condition holds.
+pub fn conditional_writes<S: SecureStore>(store: &S) {
+    let service = service("conditional");
+    let policy = AccessPolicy::default();
+    store.delete(&service, "account").unwrap();
+    assert!(!store
+        .replace_if_equals(&service, "account", &digest(b""), b"new", &policy)
+        .unwrap());
+    assert!(!store
+        .delete_if_equals(&service, "account", &digest(b""))
+        .unwrap());
+    assert!(store
+        .put_if_absent(&service, "account", b"first", &policy)
+        .unwrap());
+    assert!(!store
+        .put_if_absent(&service, "account", b"second", &policy)
+        .unwrap());
+    assert_eq!(&*store.get(&service, "account").unwrap(), "first");
+
+    assert!(!store
+        .replace_if_equals(&service, "account", &digest(b"stale"), b"new", &policy)
+        .unwrap());
+    assert!(!store
+        .replace_if_equals(&service, "account", b"first", b"new", &policy)
+        .unwrap());
+    assert_eq!(&*store.get(&service, "account").unwrap(), "first");
+    assert!(store
+        .replace_if_equals(&service, "account", &digest(b"first"), b"second", &policy)
+        .unwrap());
+    assert_eq!(&*store.get(&service, "account").unwrap(), "second");
+
+    assert!(!store
+        .delete_if_equals(&service, "account", &digest(b"first"))
+        .unwrap());
+    assert!(store.contains(&service, "account").unwrap());
+    assert!(store
+        .delete_if_equals(&service, "account", &digest(b"second"))
+        .unwrap());
+    assert!(!store.contains(&service, "account").unwrap());
+}
+
+/// A committed transaction applies its writes in order.
+pub fn transaction_applies_every_write<S: SecureStore>(store: &S) {
+    let service = service("transaction");
+    let policy = AccessPolicy::default();
+    store.put(&service, "kept", "old", &policy).unwrap();
+    store.put(&service, "deleted", "old", &policy).unwrap();
+    store.delete(&service, "added").unwrap();
+    store.delete(&service, "flipped").unwrap();
+
+    let transaction = store
+        .transaction()
+        .put(&service, "kept", "new", &policy)
+        .delete(&service, "deleted")
+        .put(&service, "added", "new", &policy)
+        .put(&service, "flipped", "first", &policy)
+        .delete(&service, "flipped")
+        .put(&service, "flipped", "last", &policy);
+    assert_eq!(transaction.writes().len(), 6);
+    transaction.commit().unwrap();
+
+    assert_eq!(&*store.get(&service, "kept").unwrap(), "new");
+    assert!(!store.contains(&service, "deleted").unwrap());
+    assert_eq!(&*store.get(&service, "added").unwrap(), "new");
+    assert_eq!(&*store.get(&service, "flipped").unwrap(), "last");
+
+    store
+        .transaction()
+        .put(&service, "kept", "newer", &policy)
+        .delete(&service, "kept")
+        .commit()
+        .unwrap();
+    assert!(!store.contains(&service, "kept").unwrap());
+    store.transaction().commit().unwrap();
+    store.delete(&service, "added").unwrap();
+    store.delete(&service, "flipped").unwrap();
+}
+
+/// Attributes are kept when the value is replaced and go with the item.
+pub fn attributes_follow_writes<S: SecureStore>(store: &S) {
+    let service = service("attributes");
+    let policy = AccessPolicy::default();
+    store.delete(&service, "account").unwrap();
+    assert_eq!(
+        store
+            .get_attributes(&service, "account")
+            .unwrap_err()
+            .kind(),
+        &ErrorKind::NotFound
+    );
+    assert_eq!(
+        store
+            .set_attributes(&service, "account", &Attributes::default())
+            .unwrap_err()
+            .kind(),
+        &ErrorKind::NotFound
+    );
+
+    store.put(&service, "account", "value", &policy).unwrap();
+    let stored = store.get_attributes(&service, "account").unwrap();
+    assert!(stored.created.is_some() && stored.modified.is_some());
+    assert_eq!(stored.label, None);
+    assert!(stored.custom.is_empty());
+
+    let mut attributes = Attributes {
+        label: Some("Label".to_string()),
+        description: Some("Description".to_string()),
+        ..Attributes::default()
+    };
+    attributes
+        .custom
+        .insert("kind".to_string(), "test".to_string());
+    store
+        .set_attributes(&service, "account", &attributes)
+        .unwrap();
+    let labelled = store.get_attributes(&service, "account").unwrap();
+    assert_eq!(labelled.label, attributes.label);
+    assert_eq!(labelled.description, attributes.description);
+    assert_eq!(labelled.custom, attributes.custom);
+    assert!(labelled.modified >= stored.modified);
+    assert_eq!(&*store.get(&service, "account").unwrap(), "value");
+
+    store.put(&service, "account", "new", &policy).unwrap();
+    let replaced = store.get_attributes(&service, "account").unwrap();
+    assert_eq!(replaced.label, attributes.label);
+    assert_eq!(replaced.custom, attributes.custom);
+    assert!(replaced.modified >= labelled.modified);
+
+    store.delete(&service, "account").unwrap();
+    store.put(&service, "account", "value", &policy).unwrap();
+    assert_eq!(
+        store.get_attributes(&service, "account").unwrap().label,
+        None
+    );
+    store.delete(&service, "account").unwrap();
+}
+
+/// An item replaced through a store with `NameMode::Hashed` keeps the
+/// attributes it was given through a store of the same backend with
+/// `NameMode::Plain`. Not part of `run`, as it needs both stores.
+pub fn attributes_follow_renaming<S: SecureStore>(plain: &S, hashed: &S) {
+    let service = service("renaming");
+    let policy = AccessPolicy::default();
+    hashed.delete(&service, "account").unwrap();
+    plain.put(&service, "account", "value", &policy).unwrap();
+    let mut attributes = Attributes {
+        label: Some("Label".to_string()),
+        description: Some("Description".to_string()),
+        ..Attributes::default()
+    };
+    attributes
+        .custom
+        .insert("kind".to_string(), "test".to_string());
+    plain
+        .set_attributes(&service, "account", &attributes)
+        .unwrap();
+
+    hashed.put(&service, "account", "new", &policy).unwrap();
+    let renamed = hashed.get_attributes(&service, "account").unwrap();
+    assert_eq!(renamed.label, attributes.label);
+    assert_eq!(renamed.description, attributes.description);
+    assert_eq!(renamed.custom, attributes.custom);
+    assert_eq!(&*hashed.get(&service, "account").unwrap(), "new");
+    hashed.delete(&service, "account").unwrap();
+}
+
+/// `wipe` removes every item. This destroys everything in the store, so it
+/// must only be run against a store created for the test.
+pub fn wipe_removes_everything<S: SecureStore>(store: &S) {
+    let a = service("wipe-a");
+    let b = service("wipe-b");
+    store
+        .put(&a, "account", "value", &AccessPolicy::default())
+        .unwrap();
+    store
+        .put(&b, "account", "value", &AccessPolicy::default())
+        .unwrap();
+    assert!(store.wipe().unwrap().is_empty());
+    assert!(!store.contains(&a, "account").unwrap());
+    assert!(!store.contains(&b, "account").unwrap());
+    assert!(store.list(&a).unwrap().is_empty());
+    assert!(store.wipe().unwrap().is_empty());
+}
+
+/// A typed value reads back under its schema only.
+#[cfg(feature = "typed")]
+pub fn typed_values_round_trip<S: SecureStore>(store: &S) {
+    let service = service("typed");
+    let value = (42u32, "forty-two".to_string(), vec![4u8, 2]);
+    store
+        .put_typed(&service, "account", &value, 2, &AccessPolicy::default())
+        .unwrap();
+    assert_eq!(
+        store
+            .get_typed::<(u32, String, Vec<u8>)>(&service, "account", 2)
+            .unwrap(),
+        value
+    );
+    assert_eq!(store.get_record(&service, "account").unwrap().schema(), 2);
+    let error = store
+        .get_typed::<(u32, String, Vec<u8>)>(&service, "account", 1)
+        .unwrap_err();
+    assert_eq!(error.kind(), &ErrorKind::InvalidData);
+    store.delete(&service, "account").unwrap();
+}
+
+fn service(name: &str) -> String {
+    format!("{}.{}", SERVICE, name)
+}
@@ -0,0 +1,200 @@
+//! Self-describing container for encrypted values
+//!
+//! Backends which encrypt values themselves (currently Android) store each
+//! item as a single envelope:
+//!
+//! ```text
+//! version: u8 | algorithm: u8 | nonce length: u8 | nonce | ciphertext | tag
+//! ```
+//!
+//! The tag length is implied by the algorithm. Parsing is pure Rust so the
+//! format can be tested and fuzzed on the host; it never panics on malformed
+//! input.
+
+use crate::{Error, ErrorKind};
+
+/// Current envelope format version.
+pub const VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 3;
+
+/// Cipher used to produce an envelope.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    /// AES-GCM with a 128-bit tag.
+    AesGcm,
+}
+
+impl Algorithm {
+    fn id(self) -> u8 {
+        match self {
+            Algorithm::AesGcm => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Algorithm::AesGcm),
+            _ => None,
+        }
+    }
+
+    /// Length of the authentication tag in bytes
+    pub fn tag_len(self) -> usize {
+        match self {
+            Algorithm::AesGcm => 16,
+        }
+    }
+
+    /// Length of the nonce in bytes
+    pub fn nonce_len(self) -> usize {
+        match self {
+            Algorithm::AesGcm => 12,
+        }
+    }
+}
+
+/// An encrypted value with everything needed to decrypt it, except the key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub algorithm: Algorithm,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub tag: Vec<u8>,
+}
+
+impl Envelope {
+    /// Build an envelope from a cipher's output, which has the tag appended
+    /// to the ciphertext as with Java's `Cipher.doFinal`.
+    pub fn from_sealed(algorithm: Algorithm, nonce: &[u8], sealed: &[u8]) -> Result<Self, Error> {
+        if nonce.len() != algorithm.nonce_len() {
+            return Err(invalid(&format!("Invalid nonce length: {}", nonce.len())));
+        }
+        if sealed.len() < algorithm.tag_len() {
+            return Err(invalid("Sealed value is shorter than its tag"));
+        }
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - algorithm.tag_len());
+        Ok(Envelope {
+            algorithm,
+            nonce: nonce.to_vec(),
+            ciphertext: ciphertext.to_vec(),
+            tag: tag.to_vec(),
+        })
+    }
+
+    /// Ciphertext with the tag appended, as expected by Java's `Cipher.doFinal`.
+    pub fn sealed(&self) -> Vec<u8> {
+        let mut sealed = Vec::with_capacity(self.ciphertext.len() + self.tag.len());
+        sealed.extend_from_slice(&self.ciphertext);
+        sealed.extend_from_slice(&self.tag);
+        sealed
+    }
+
+    /// Serialize the envelope in the current format version.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            HEADER_LEN + self.nonce.len() + self.ciphertext.len() + self.tag.len(),
+        );
+        bytes.push(VERSION);
+        bytes.push(self.algorithm.id());
+        bytes.push(self.nonce.len() as u8);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes.extend_from_slice(&self.tag);
+        bytes
+    }
+
+    /// Parse an envelope.
+    ///
+    /// Fails with `ErrorKind::InvalidData` on unknown versions or algorithms
+    /// and on truncated input.
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < HEADER_LEN {
+            return Err(invalid("Truncated envelope header"));
+        }
+        if bytes[0] != VERSION {
+            return Err(invalid(&format!(
+                "Unsupported envelope version: {}",
+                bytes[0]
+            )));
+        }
+        let algorithm = Algorithm::from_id(bytes[1])
+            .ok_or_else(|| invalid(&format!("Unknown envelope algorithm: {}", bytes[1])))?;
+        let nonce_len = bytes[2] as usize;
+        if nonce_len != algorithm.nonce_len() {
+            return Err(invalid(&format!("Invalid nonce length: {}", nonce_len)));
+        }
+        let rest = &bytes[HEADER_LEN..];
+        if rest.len() < nonce_len + algorithm.tag_len() {
+            return Err(invalid("Truncated envelope body"));
+        }
+        let (nonce, sealed) = rest.split_at(nonce_len);
+        Envelope::from_sealed(algorithm, nonce, sealed)
+    }
+}
+
+fn invalid(description: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::arbitrary_inputs;
+
+    fn envelope() -> Envelope {
+        Envelope {
+            algorithm: Algorithm::AesGcm,
+            nonce: (0..12).collect(),
+            ciphertext: b"ciphertext".to_vec(),
+            tag: (100..116).collect(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let envelope = envelope();
+        assert_eq!(Envelope::decode(&envelope.encode()).unwrap(), envelope);
+
+        let empty = Envelope {
+            ciphertext: Vec::new(),
+            ..envelope
+        };
+        assert_eq!(Envelope::decode(&empty.encode()).unwrap(), empty);
+    }
+
+    #[test]
+    fn test_sealed_splits_tag() {
+        let envelope = envelope();
+        let sealed = envelope.sealed();
+        let parsed = Envelope::from_sealed(Algorithm::AesGcm, &envelope.nonce, &sealed).unwrap();
+        assert_eq!(parsed, envelope);
+    }
+
+    #[test]
+    fn test_truncated_input_is_rejected() {
+        let bytes = envelope().encode();
+        let minimum = HEADER_LEN + 12 + 16;
+        for len in 0..minimum {
+            let error = Envelope::decode(&bytes[..len]).unwrap_err();
+            assert_eq!(error.kind(), &ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_unknown_header_is_rejected() {
+        for (index, value) in &[(0, 0), (0, 2), (1, 0), (1, 2), (2, 16)] {
+            let mut bytes = envelope().encode();
+            bytes[*index] = *value;
+            let error = Envelope::decode(&bytes).unwrap_err();
+            assert_eq!(error.kind(), &ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_arbitrary_input_does_not_panic() {
+        for bytes in arbitrary_inputs(&[VERSION, Algorithm::AesGcm.id()]) {
+            let _ = Envelope::decode(&bytes);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Error types
+
+use failure::{Backtrace, Fail};
+use std::{
+    any::Any,
+    fmt::{self, Display},
+    io,
+};
+
+/// Error type.
+///
+/// Every backend reports failures through this type so callers can branch
+/// on `kind()` regardless of the platform the error came from.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    backtrace: Backtrace,
+    description: String,
+}
+
+impl Error {
+    /// Create a new error of the given kind with the given description
+    pub fn new<D>(kind: ErrorKind, description: &D) -> Self
+    where
+        D: ToString + ?Sized,
+    {
+        Error {
+            kind,
+            backtrace: Backtrace::new(),
+            description: description.to_string(),
+        }
+    }
+
+    /// Get the `ErrorKind` for this error
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Get the platform description of this error
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// Get the stable numeric code reported across the FFI boundary
+    pub fn code(&self) -> i32 {
+        self.kind.code()
+    }
+
+    /// Error reported by an export in place of a panic it caught
+    pub(crate) fn from_panic(panic: Box<dyn Any + Send>) -> Self {
+        let message = match panic.downcast::<String>() {
+            Ok(message) => *message,
+            Err(panic) => match panic.downcast::<&'static str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "unknown cause".to_string(),
+            },
+        };
+        Error::new(ErrorKind::Other, &format!("Panicked: {}", message))
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", &self.description, &self.kind)
+    }
+}
+
+impl Fail for Error {
+    fn cause(&self) -> Option<&dyn Fail> {
+        None
+    }
+
+    fn backtrace(&self) -> Option<&Backtrace> {
+        Some(&self.backtrace)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::new(ErrorKind::Io { kind: error.kind() }, &error)
+    }
+}
+
+/// Kinds of errors, common to all platforms.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// No item is stored for the requested service and account.
+    NotFound,
+
+    /// The user or the supplied credentials failed authentication.
+    AuthFailed,
+
+    /// The user dismissed the authentication prompt.
+    UserCanceled,
+
+    /// The item cannot be accessed without user interaction, which is not
+    /// possible right now (e.g. the device is locked).
+    InteractionNotAllowed,
+
+    /// The key protecting the item is no longer usable, for example because
+    /// biometric enrollment changed. The item has to be written again.
+    KeyInvalidated,
+
+    /// An item already exists for the service and account.
+    DuplicateItem,
+
+    /// Stored data could not be decoded.
+    InvalidData,
+
+    /// The request cannot be honoured on this platform, for example an
+    /// access constraint the platform has no equivalent for.
+    Unsupported,
+
+    /// Input/output errors.
+    Io {
+        /// `std::io::ErrorKind` value representing the I/O error
+        kind: io::ErrorKind,
+    },
+
+    /// Platform error codes we can't otherwise decode, such as an iOS
+    /// `OSStatus` or an errno value.
+    Platform {
+        /// Raw platform error code
+        code: i64,
+    },
+
+    /// Failures which carry no code, such as unexpected Java exceptions.
+    Other,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::NotFound => write!(f, "item not found"),
+            ErrorKind::AuthFailed => write!(f, "authentication failed"),
+            ErrorKind::UserCanceled => write!(f, "user canceled"),
+            ErrorKind::InteractionNotAllowed => write!(f, "interaction not allowed"),
+            ErrorKind::KeyInvalidated => write!(f, "key invalidated"),
+            ErrorKind::DuplicateItem => write!(f, "duplicate item"),
+            ErrorKind::InvalidData => write!(f, "invalid data"),
+            ErrorKind::Unsupported => write!(f, "unsupported"),
+            ErrorKind::Io { kind } => write!(f, "I/O error ({:?})", kind),
+            ErrorKind::Platform { code } => write!(f, "platform error (code: {})", code),
+            ErrorKind::Other => write!(f, "other error"),
+        }
+    }
+}
+
+impl Fail for ErrorKind {}
+
+impl ErrorKind {
+    /// Stable numeric code for this kind of error.
+    ///
+    /// These values are part of the FFI contract: they are reported through
+    /// `CResult::error_code` on iOS and `SecureNativeException.code` on
+    /// Android. Zero means success and is never returned here. Existing
+    /// values must not be renumbered.
+    pub fn code(&self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::NotFound => 2,
+            ErrorKind::AuthFailed => 3,
+            ErrorKind::UserCanceled => 4,
+            ErrorKind::InteractionNotAllowed => 5,
+            ErrorKind::KeyInvalidated => 6,
+            ErrorKind::DuplicateItem => 7,
+            ErrorKind::InvalidData => 8,
+            ErrorKind::Io { .. } => 9,
+            ErrorKind::Platform { .. } => 10,
+            ErrorKind::Unsupported => 11,
+        }
+    }
+}
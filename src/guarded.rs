@@ -0,0 +1,300 @@
+//! Page-protected memory for secrets held in the process
+//!
+//! A `GuardedBytes` gets a mapping of its own:
+//!
+//! ```text
+//! guard page | data pages | guard page
+//! ```
+//!
+//! The data pages are locked into RAM with `mlock`, so they are never
+//! swapped, and left out of core dumps with `MADV_DONTDUMP`. Outside of
+//! `GuardedBytes::read` and `GuardedBytes::write` they are `PROT_NONE`, like
+//! the guard pages, so a stray read or an overflow faults instead of leaking
+//! the secret. The bytes end where the data pages do, so running off their
+//! end lands in the guard page.
+
+use crate::{Error, ErrorKind, SecretBytes};
+
+use libc::{c_void, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_NONE, PROT_READ, PROT_WRITE};
+use std::{cell::Cell, io, ptr, slice};
+use zeroize::Zeroize;
+
+/// Secret bytes in locked, guarded pages (see the module documentation).
+///
+/// Allocating takes at least three pages and a system call for every
+/// access, so keep it for long-lived secrets such as seeds.
+pub struct GuardedBytes {
+    /// Start of the mapping, the leading guard page
+    map: *mut u8,
+    map_len: usize,
+    len: usize,
+    /// Live `read` calls, which only protect the pages again once the
+    /// outermost one returns
+    readers: Cell<usize>,
+}
+
+// The pages are only reached through `&self` and `&mut self`. `readers`
+// keeps it from being `Sync`.
+unsafe impl Send for GuardedBytes {}
+
+impl GuardedBytes {
+    /// `len` zero bytes.
+    ///
+    /// Fails with `ErrorKind::Io` if the pages can't be mapped or locked,
+    /// for example once `RLIMIT_MEMLOCK` is reached.
+    pub fn new(len: usize) -> Result<Self, Error> {
+        let page = page_size();
+        let data_len = data_len(len, page);
+        let map_len = data_len + 2 * page;
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                PROT_NONE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if map == MAP_FAILED {
+            return Err(context(
+                io::Error::last_os_error(),
+                "Mapping guarded memory",
+            ));
+        }
+        // Dropping it from here on unmaps the pages
+        let bytes = GuardedBytes {
+            map: map as *mut u8,
+            map_len,
+            len,
+            readers: Cell::new(0),
+        };
+        let data = bytes.data_pages();
+        // Pages can only be locked while accessible, as locking faults them in
+        bytes
+            .protect(PROT_READ | PROT_WRITE)
+            .map_err(|e| context(e, "Unprotecting guarded memory"))?;
+        if unsafe { libc::madvise(data, data_len, libc::MADV_DONTDUMP) } != 0 {
+            return Err(context(
+                io::Error::last_os_error(),
+                "Excluding guarded memory from core dumps",
+            ));
+        }
+        if unsafe { libc::mlock(data, data_len) } != 0 {
+            return Err(context(
+                io::Error::last_os_error(),
+                "Locking guarded memory",
+            ));
+        }
+        bytes
+            .protect(PROT_NONE)
+            .map_err(|e| context(e, "Protecting guarded memory"))?;
+        Ok(bytes)
+    }
+
+    /// A copy of `bytes`.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, Error> {
+        let mut guarded = GuardedBytes::new(bytes.len())?;
+        guarded.write(|data| data.copy_from_slice(bytes))?;
+        Ok(guarded)
+    }
+
+    /// Move `secret` into guarded memory, wiping its previous buffer.
+    pub fn from_secret(secret: SecretBytes) -> Result<Self, Error> {
+        GuardedBytes::from_slice(&secret)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Call `f` with the bytes, which are readable until it returns.
+    ///
+    /// Fails with `ErrorKind::Io` if the pages can't be made readable, which
+    /// only happens when the kernel is out of memory.
+    pub fn read<T, F: FnOnce(&[u8]) -> T>(&self, f: F) -> Result<T, Error> {
+        let readers = self.readers.get();
+        if readers == 0 {
+            self.protect(PROT_READ)
+                .map_err(|e| context(e, "Unprotecting guarded memory"))?;
+        }
+        self.readers.set(readers + 1);
+        // Protect the pages again even if `f` panics
+        let _reader = Reader(self);
+        Ok(f(unsafe { slice::from_raw_parts(self.data(), self.len) }))
+    }
+
+    /// Call `f` with the bytes, which are writable until it returns.
+    ///
+    /// Fails like `read`.
+    pub fn write<T, F: FnOnce(&mut [u8]) -> T>(&mut self, f: F) -> Result<T, Error> {
+        self.protect(PROT_READ | PROT_WRITE)
+            .map_err(|e| context(e, "Unprotecting guarded memory"))?;
+        self.readers.set(1);
+        let _reader = Reader(self);
+        Ok(f(unsafe {
+            slice::from_raw_parts_mut(self.data(), self.len)
+        }))
+    }
+
+    fn data_pages(&self) -> *mut c_void {
+        unsafe { self.map.add(page_size()) as *mut c_void }
+    }
+
+    fn data(&self) -> *mut u8 {
+        unsafe { self.map.add(self.map_len - page_size() - self.len) }
+    }
+
+    /// Only fails when the kernel is out of memory for splitting the mapping.
+    fn protect(&self, protection: libc::c_int) -> io::Result<()> {
+        let len = data_len(self.len, page_size());
+        if unsafe { libc::mprotect(self.data_pages(), len, protection) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// Marks the end of an access, protecting the pages again after the last.
+struct Reader<'a>(&'a GuardedBytes);
+
+impl Drop for Reader<'_> {
+    fn drop(&mut self) {
+        let readers = self.0.readers.get() - 1;
+        self.0.readers.set(readers);
+        // Panicking here could abort an unwinding thread. The pages stay
+        // accessible until the next access protects them again, and are
+        // wiped when dropped either way.
+        if readers == 0 {
+            let _ = self.0.protect(PROT_NONE);
+        }
+    }
+}
+
+impl Drop for GuardedBytes {
+    fn drop(&mut self) {
+        let data_len = data_len(self.len, page_size());
+        unsafe {
+            if libc::mprotect(self.data_pages(), data_len, PROT_READ | PROT_WRITE) == 0 {
+                slice::from_raw_parts_mut(self.data_pages() as *mut u8, data_len).zeroize();
+            }
+            libc::munlock(self.data_pages(), data_len);
+            libc::munmap(self.map as *mut c_void, self.map_len);
+        }
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Length of the data pages holding `len` bytes, at least one page.
+fn data_len(len: usize, page: usize) -> usize {
+    len.max(1).div_ceil(page) * page
+}
+
+fn context(error: io::Error, context: &str) -> Error {
+    Error::new(
+        ErrorKind::Io { kind: error.kind() },
+        &format!("{}: {}", context, error),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// `VmFlags` of the mapping containing `address`, from `/proc/self/smaps`,
+    /// along with its permissions.
+    fn mapping(address: usize) -> (String, Vec<String>) {
+        let smaps = fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut perms = None;
+        for line in smaps.lines() {
+            let mut fields = line.split_whitespace();
+            let first = fields.next().unwrap_or("");
+            if let Some((start, end)) = first.split_once('-') {
+                if let (Ok(start), Ok(end)) = (
+                    usize::from_str_radix(start, 16),
+                    usize::from_str_radix(end, 16),
+                ) {
+                    perms = if start <= address && address < end {
+                        fields.next().map(str::to_string)
+                    } else {
+                        None
+                    };
+                    continue;
+                }
+            }
+            if first == "VmFlags:" {
+                if let Some(perms) = perms.take() {
+                    return (perms, fields.map(str::to_string).collect());
+                }
+            }
+        }
+        panic!("No mapping contains {:#x}", address);
+    }
+
+    /// Whether reading the byte at `address` kills a child process with
+    /// `SIGSEGV`.
+    fn faults(address: *const u8) -> bool {
+        unsafe {
+            match libc::fork() {
+                0 => {
+                    ptr::read_volatile(address);
+                    libc::_exit(0);
+                }
+                pid => {
+                    let mut status = 0;
+                    assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+                    libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSEGV
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut bytes = GuardedBytes::from_slice(b"seed phrase").unwrap();
+        assert_eq!(bytes.len(), 11);
+        bytes
+            .read(|data| {
+                // Nested reads keep the pages readable
+                bytes
+                    .read(|inner| assert_eq!(inner, b"seed phrase"))
+                    .unwrap();
+                assert_eq!(data, b"seed phrase");
+            })
+            .unwrap();
+        bytes.write(|data| data[0] = b'S').unwrap();
+        bytes.read(|data| assert_eq!(data, b"Seed phrase")).unwrap();
+        let secret = GuardedBytes::from_secret(SecretBytes::from(&b"x"[..])).unwrap();
+        secret.read(|data| assert_eq!(data, b"x")).unwrap();
+        assert!(GuardedBytes::new(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pages_are_locked_and_protected() {
+        let page = page_size();
+        let bytes = GuardedBytes::from_slice(&[7; 100]).unwrap();
+        let data = bytes.data() as usize;
+
+        let (perms, flags) = mapping(data);
+        assert_eq!(perms, "---p");
+        assert!(flags.iter().any(|flag| flag == "lo"), "{:?}", flags);
+        assert!(flags.iter().any(|flag| flag == "dd"), "{:?}", flags);
+        bytes.read(|_| assert_eq!(mapping(data).0, "r--p")).unwrap();
+        assert_eq!(mapping(data).0, "---p");
+
+        // Guard pages on both sides, and no access outside of `read`
+        assert!(faults((data + 100) as *const u8));
+        assert!(faults((bytes.map as usize + page - 1) as *const u8));
+        assert!(faults(data as *const u8));
+        assert!(!bytes.read(|data| faults(data.as_ptr())).unwrap());
+    }
+}
@@ -1,36 +1,94 @@
-use crate::Argument;
+use super::CBuffer;
+#[cfg(feature = "typed")]
+use crate::Json;
+use crate::{Argument, Error, ErrorKind, SecretBytes, SecretString};
 use ffi_support::FfiStr;
 use libc::c_char;
 use std::cell::Cell;
 
+/// The text of a string argument.
+///
+/// Fails with `ErrorKind::InvalidData` if it is NULL or not UTF-8.
+pub fn str_argument<'a>(s: &FfiStr<'a>) -> Result<&'a str, Error> {
+    s.as_opt_str().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "String argument is NULL or not valid UTF-8",
+        )
+    })
+}
+
 impl Argument<'static> for u32 {
     type Ext = u32;
     type Env = Cell<u32>;
-    fn convert(_: &Self::Env, val: Self::Ext) -> Self {
-        val
+    fn convert(_: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        Ok(val)
     }
 }
 
 impl Argument<'static> for u8 {
     type Ext = u32;
     type Env = Cell<u32>;
-    fn convert(_: &Self::Env, val: Self::Ext) -> Self {
-        val as u8
+    fn convert(_: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        Ok(val as u8)
     }
 }
 
 impl Argument<'static> for bool {
     type Ext = u8;
     type Env = Cell<u32>;
-    fn convert(_: &Self::Env, val: Self::Ext) -> Self {
-        val != 0
+    fn convert(_: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        Ok(val != 0)
     }
 }
 
 impl Argument<'static> for String {
     type Ext = *const c_char;
     type Env = Cell<u32>;
-    fn convert(_: &Self::Env, val: Self::Ext) -> Self {
-        unsafe { FfiStr::from_raw(val) }.into_string()
+    fn convert(_: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        str_argument(&unsafe { FfiStr::from_raw(val) }).map(str::to_string)
+    }
+}
+
+#[cfg(feature = "typed")]
+impl<T: serde::de::DeserializeOwned> Argument<'static> for Json<T> {
+    type Ext = *const c_char;
+    type Env = Cell<u32>;
+    fn convert(_: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        Json::from_json(str_argument(&unsafe { FfiStr::from_raw(val) })?)
+    }
+}
+
+impl Argument<'static> for Vec<u8> {
+    type Ext = CBuffer;
+    type Env = Cell<u32>;
+    fn convert(_: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        if val.data.is_null() {
+            Ok(Vec::new())
+        } else {
+            Ok(unsafe { std::slice::from_raw_parts(val.data, val.len) }.to_vec())
+        }
+    }
+}
+
+impl Argument<'static> for SecretString {
+    type Ext = *const c_char;
+    type Env = Cell<u32>;
+    fn convert(_: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        str_argument(&unsafe { FfiStr::from_raw(val) }).map(SecretString::from)
+    }
+}
+
+impl Argument<'static> for SecretBytes {
+    type Ext = CBuffer;
+    type Env = Cell<u32>;
+    fn convert(_: &Self::Env, val: Self::Ext) -> Result<Self, Error> {
+        if val.data.is_null() {
+            Ok(SecretBytes::default())
+        } else {
+            Ok(SecretBytes::from(unsafe {
+                std::slice::from_raw_parts(val.data, val.len)
+            }))
+        }
     }
 }
@@ -40,6 +40,10 @@ pub const errSecDataNotAvailable: OSStatus = -25316;
 /// <https://developer.apple.com/documentation/security/errsecdatanotmodifiable>
 pub const errSecDataNotModifiable: OSStatus = -25317;
 
+/// User canceled the operation.
+/// <https://developer.apple.com/documentation/security/errsecusercanceled>
+pub const errSecUserCanceled: OSStatus = -128;
+
 /// Callback with the same name already exists.
 /// <https://developer.apple.com/documentation/security/errsecduplicatecallback>
 pub const errSecDuplicateCallback: OSStatus = -25297;
@@ -155,6 +159,7 @@ pub struct Error {
     kind: ErrorKind,
     backtrace: Backtrace,
     description: String,
+    code: i64,
 }
 
 impl Error {
@@ -167,6 +172,7 @@ impl Error {
             kind,
             backtrace: Backtrace::new(),
             description: description.to_string(),
+            code: 0,
         }
     }
 
@@ -180,7 +186,10 @@ impl Error {
                 CFString::wrap_under_create_rule(SecCopyErrorMessageString(status, ptr::null()))
             };
 
-            Some(Error::new(kind, &description))
+            Some(Error {
+                code: i64::from(status),
+                ..Error::new(kind, &description)
+            })
         }
     }
 
@@ -213,6 +222,7 @@ impl From<CFErrorRef> for Error {
     fn from(error_ref: CFErrorRef) -> Error {
         let kind = ErrorKind::from(error_ref);
         let backtrace = Backtrace::new();
+        let code = unsafe { CFErrorGetCode(error_ref) } as i64;
         let description =
             unsafe { CFString::wrap_under_create_rule(CFErrorCopyDescription(error_ref)) }
                 .to_string();
@@ -226,10 +236,33 @@ impl From<CFErrorRef> for Error {
             kind,
             backtrace,
             description,
+            code,
         }
     }
 }
 
+impl From<Error> for crate::Error {
+    fn from(error: Error) -> crate::Error {
+        use crate::ErrorKind as Kind;
+        let kind = match error.kind {
+            ErrorKind::ItemNotFound => Kind::NotFound,
+            ErrorKind::AuthFailed => Kind::AuthFailed,
+            ErrorKind::UserCanceled => Kind::UserCanceled,
+            ErrorKind::InteractionNotAllowed => Kind::InteractionNotAllowed,
+            ErrorKind::DuplicateItem => Kind::DuplicateItem,
+            ErrorKind::Io { kind } => Kind::Io { kind },
+            ErrorKind::Errno { code } => Kind::Io {
+                kind: io::Error::from_raw_os_error(i32::from(code)).kind(),
+            },
+            ErrorKind::CFError { code, .. } | ErrorKind::OSError { code } => {
+                Kind::Platform { code }
+            }
+            _ => Kind::Platform { code: error.code },
+        };
+        crate::Error::new(kind, &error)
+    }
+}
+
 /// Kinds of errors.
 #[derive(Clone, Debug, Fail, PartialEq)]
 pub enum ErrorKind {
@@ -275,6 +308,13 @@ pub enum ErrorKind {
     #[fail(display = "data not modifiable")]
     DataNotModifiable,
 
+    /// User canceled the operation.
+    ///
+    /// Wrapper for the `errSecUserCanceled` status code. See:
+    /// <https://developer.apple.com/documentation/security/errsecusercanceled>
+    #[fail(display = "user canceled")]
+    UserCanceled,
+
     /// Callback with the same name already exists.
     ///
     /// Wrapper for the `errSecDuplicateCallback` status code. See:
@@ -517,6 +557,7 @@ impl From<OSStatus> for ErrorKind {
             errSecDataTooLarge => ErrorKind::DataTooLarge,
             errSecDataNotAvailable => ErrorKind::DataNotAvailable,
             errSecDataNotModifiable => ErrorKind::DataNotModifiable,
+            errSecUserCanceled => ErrorKind::UserCanceled,
             errSecDuplicateCallback => ErrorKind::DuplicateCallback,
             errSecDuplicateItem => ErrorKind::DuplicateItem,
             errSecDuplicateKeychain => ErrorKind::DuplicateKeychain,
@@ -1,5 +1,5 @@
 use core_foundation::{
-    base::{CFAllocatorRef, CFOptionFlags, CFTypeID, CFTypeRef, OSStatus, TCFType},
+    base::{CFAllocatorRef, CFIndex, CFOptionFlags, CFTypeID, CFTypeRef, OSStatus, TCFType},
     data::CFDataRef,
     dictionary::CFDictionaryRef,
     error::CFErrorRef,
@@ -16,9 +16,12 @@ pub(crate) static kSecAccessControlUserPresence: CFOptionFlags = 1u32 << 0;
 pub(crate) static kSecAccessControlBiometryAny: CFOptionFlags = 1u32 << 1;
 pub(crate) static kSecAccessControlBiometryCurrentSet: CFOptionFlags = 1u32 << 3;
 pub(crate) static kSecAccessControlDevicePasscode: CFOptionFlags = 1u32 << 4;
+pub(crate) static kSecAccessControlOr: CFOptionFlags = 1u32 << 14;
+pub(crate) static kSecAccessControlAnd: CFOptionFlags = 1u32 << 15;
 pub(crate) static kSecAccessControlApplicationPassword: CFOptionFlags = 1u32 << 31;
 
 pub(crate) type SecAccessControlRef = CFTypeRef;
+pub(crate) type SecRandomRef = *const c_void;
 
 #[link(name = "Security", kind = "framework")]
 extern "C" {
@@ -34,6 +37,10 @@ extern "C" {
     pub(crate) static kSecAttrAccessibleAlwaysThisDeviceOnly: CFStringRef;
     pub(crate) static kSecAttrAccessibleAlways: CFStringRef;
     pub(crate) static kSecAttrAccount: CFStringRef;
+    pub(crate) static kSecAttrCreationDate: CFStringRef;
+    pub(crate) static kSecAttrDescription: CFStringRef;
+    pub(crate) static kSecAttrGeneric: CFStringRef;
+    pub(crate) static kSecAttrModificationDate: CFStringRef;
     pub(crate) static kSecAttrApplicationLabel: CFStringRef;
     pub(crate) static kSecAttrApplicationTag: CFStringRef;
     pub(crate) static kSecAttrIsPermanent: CFStringRef;
@@ -184,6 +191,7 @@ extern "C" {
     pub(crate) static kSecUseKeychain: CFStringRef;
     pub(crate) static kSecUseOperationPrompt: CFStringRef;
     pub(crate) static kSecValueData: CFStringRef;
+    pub(crate) static kSecRandomDefault: SecRandomRef;
 
     pub(crate) fn SecAccessControlCreateWithFlags(
         allocator: CFAllocatorRef,
@@ -200,4 +208,17 @@ extern "C" {
     pub(crate) fn SecItemUpdate(query: CFDictionaryRef, attributes: CFDictionaryRef) -> OSStatus;
     pub(crate) fn SecItemCopyMatching(query: CFDictionaryRef, result: *mut CFTypeRef) -> OSStatus;
     pub(crate) fn SecItemDelete(query: CFDictionaryRef) -> OSStatus;
+    pub(crate) fn SecRandomCopyBytes(rnd: SecRandomRef, count: usize, bytes: *mut c_void) -> i32;
+}
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    pub(crate) static kCFAllocatorNull: CFAllocatorRef;
+
+    pub(crate) fn CFDataCreateWithBytesNoCopy(
+        allocator: CFAllocatorRef,
+        bytes: *const u8,
+        length: CFIndex,
+        bytes_deallocator: CFAllocatorRef,
+    ) -> CFDataRef;
 }
@@ -8,17 +8,38 @@ use error::*;
 use ffi::*;
 pub use ret::*;
 
+use crate::attributes;
+use crate::journal;
+use crate::naming;
+use crate::store::last_writes;
+use crate::{
+    lock_item, lock_items, AccessPolicy, Accessibility, AccountInfo, Attributes, Combinator,
+    Constraint, ErrorKind as Kind, NameMode, SecretBytes, SecureStore, Write,
+};
+
 use core_foundation::{
-    base::{kCFAllocatorDefault, CFOptionFlags, CFTypeID, CFTypeRef, FromVoid, TCFType, ToVoid},
+    array::*,
+    base::{
+        kCFAllocatorDefault, CFIndex, CFOptionFlags, CFTypeID, CFTypeRef, FromVoid, TCFType, ToVoid,
+    },
     boolean::*,
     data::*,
+    date::*,
     declare_TCFType,
     dictionary::*,
     error::CFErrorRef,
     impl_TCFType,
     string::*,
 };
-use std::{ffi::*, os::raw::c_char, os::unix::ffi::OsStrExt, path::Path, ptr};
+use std::{
+    ffi::*,
+    os::raw::c_char,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    ptr,
+    sync::Once,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 declare_TCFType!(SecAccessControl, SecAccessControlRef);
 impl_TCFType!(
@@ -27,126 +48,490 @@ impl_TCFType!(
     SecAccessControlGetTypeID
 );
 
-pub fn put(service: &str, account: &str, value: &str, with_biometry: bool) -> Result<(), String> {
-    let mut error: CFErrorRef = ptr::null_mut();
-    let access = unsafe {
-        SecAccessControlCreateWithFlags(
-            kCFAllocatorDefault,
-            CFString::wrap_under_get_rule(kSecAttrAccessibleWhenUnlockedThisDeviceOnly.into())
-                .as_CFTypeRef(),
-            kSecAccessControlBiometryCurrentSet,
-            //            kSecAccessControlApplicationPassword,
-            &mut error,
+/// `SecureStore` backed by the iOS keychain.
+pub struct Keychain {
+    names: NameMode,
+}
+
+impl Keychain {
+    /// Choose how item names are stored. Defaults to `NameMode::Plain`.
+    pub fn with_names(self, names: NameMode) -> Self {
+        Keychain { names }
+    }
+
+    /// `kSecAttrService` of every item stored for `service`, starting with
+    /// the one items are written to.
+    fn service_names(&self, service: &str) -> Result<Vec<String>, crate::Error> {
+        match self.names {
+            NameMode::Plain => Ok(vec![service.to_string()]),
+            NameMode::Hashed => {
+                let secret = name_key()?;
+                let hashed = naming::keyed_hash(&secret, &naming::hashed_service_input(service));
+                Ok(vec![hashed, service.to_string()])
+            }
+        }
+    }
+
+    /// `kSecAttrService` and `kSecAttrAccount` of every item the pair may be
+    /// stored as, starting with the one it is written to.
+    fn item_names(
+        &self,
+        service: &str,
+        account: &str,
+    ) -> Result<Vec<(String, String)>, crate::Error> {
+        let plain = (service.to_string(), account.to_string());
+        match self.names {
+            NameMode::Plain => Ok(vec![plain]),
+            NameMode::Hashed => {
+                let secret = name_key()?;
+                let hashed = (
+                    naming::keyed_hash(&secret, &naming::hashed_service_input(service)),
+                    naming::keyed_hash(&secret, &naming::hashed_item_input(service, account)),
+                );
+                Ok(vec![hashed, plain])
+            }
+        }
+    }
+}
+
+impl SecureStore for Keychain {
+    type Config = ();
+
+    /// Finishes a transaction interrupted in an earlier run, the first time a
+    /// keychain is opened.
+    fn open(_: Self::Config) -> Self {
+        static RECOVERY: Once = Once::new();
+        RECOVERY.call_once(|| {
+            let _journal = lock_item(naming::JOURNAL_SERVICE, naming::JOURNAL_ACCOUNT);
+            // A journal which can't be replayed now is replayed by the next
+            // transaction, and one which can't be read is left for it to
+            // discard and report
+            if let Ok(stored) = get(naming::JOURNAL_SERVICE, naming::JOURNAL_ACCOUNT) {
+                if let Ok(writes) = journal::decode(&stored) {
+                    let _ = replay(&writes)
+                        .and_then(|()| delete(naming::JOURNAL_SERVICE, naming::JOURNAL_ACCOUNT));
+                }
+            }
+        });
+        Keychain {
+            names: NameMode::default(),
+        }
+    }
+
+    fn put_bytes(
+        &self,
+        service: &str,
+        account: &str,
+        value: &[u8],
+        policy: &AccessPolicy,
+    ) -> Result<(), crate::Error> {
+        let _guard = lock_item(service, account);
+        let mut names = self.item_names(service, account)?.into_iter();
+        let (item_service, item_account) = names.next().ok_or_else(no_name)?;
+        let older: Vec<_> = names.collect();
+        // An item moving to its hashed name takes the attributes of its
+        // plainly named copy along
+        let carried = match attributes(&item_service, &item_account) {
+            Err(ref e) if e.kind() == &Kind::NotFound => older
+                .iter()
+                .find_map(|(service, account)| attributes(service, account).ok()),
+            _ => None,
+        };
+        put(&item_service, &item_account, value, policy)?;
+        if let Some(carried) = carried {
+            set_attributes(&item_service, &item_account, &carried)?;
+        }
+        // The new item shadows any plainly named copy, so failing to remove
+        // it is harmless
+        for (item_service, item_account) in older {
+            let _ = delete(&item_service, &item_account);
+        }
+        Ok(())
+    }
+
+    fn get_bytes(&self, service: &str, account: &str) -> Result<SecretBytes, crate::Error> {
+        for (item_service, item_account) in self.item_names(service, account)? {
+            match get(&item_service, &item_account) {
+                Err(ref e) if e.kind() == &Kind::NotFound => continue,
+                result => return result,
+            }
+        }
+        Err(crate::Error::new(
+            Kind::NotFound,
+            &format!("Couldn't find value for key: {}", account),
+        ))
+    }
+
+    fn contains(&self, service: &str, account: &str) -> Result<bool, crate::Error> {
+        for (item_service, item_account) in self.item_names(service, account)? {
+            if contains(&item_service, &item_account)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn delete(&self, service: &str, account: &str) -> Result<(), crate::Error> {
+        let _guard = lock_item(service, account);
+        for (item_service, item_account) in self.item_names(service, account)? {
+            delete(&item_service, &item_account)?;
+        }
+        Ok(())
+    }
+
+    /// The keychain can't commit several items at once, so the writes are
+    /// first stored as a journal item, which is replayed if applying them is
+    /// interrupted. The journal holds the values until then, as readable as
+    /// the least readable of them, so puts requiring user authentication fail
+    /// with `ErrorKind::Unsupported` and nothing is written.
+    ///
+    /// If applying the writes fails part way, the journal stays and the rest
+    /// of them are applied before the next transaction, or when the app is
+    /// next launched. A journal which can't be read back is discarded by the
+    /// next transaction, which fails with `ErrorKind::InvalidData`.
+    fn apply(&self, writes: &[Write]) -> Result<(), crate::Error> {
+        let _journal = lock_item(naming::JOURNAL_SERVICE, naming::JOURNAL_ACCOUNT);
+        recover()?;
+        let writes = last_writes(writes);
+        let _guards = lock_items(writes.iter().map(|w| (w.service(), w.account())));
+        let mut stored = Vec::new();
+        for write in writes {
+            let mut names = self
+                .item_names(write.service(), write.account())?
+                .into_iter();
+            if let Write::Put { value, policy, .. } = write {
+                let (service, account) = names.next().ok_or_else(no_name)?;
+                stored.push(Write::Put {
+                    service,
+                    account,
+                    value: value.clone(),
+                    policy: policy.clone(),
+                });
+            }
+            stored.extend(names.map(|(service, account)| Write::Delete { service, account }));
+        }
+        if stored.is_empty() {
+            return Ok(());
+        }
+        put(
+            naming::JOURNAL_SERVICE,
+            naming::JOURNAL_ACCOUNT,
+            &journal::encode(&stored),
+            &journal::policy(&stored)?,
+        )?;
+        replay(&stored)?;
+        delete(naming::JOURNAL_SERVICE, naming::JOURNAL_ACCOUNT)
+    }
+
+    /// Attributes are kept in the keychain's own: `kSecAttrLabel`,
+    /// `kSecAttrDescription`, `kSecAttrCreationDate`,
+    /// `kSecAttrModificationDate`, and `kSecAttrGeneric` for custom ones.
+    fn get_attributes(&self, service: &str, account: &str) -> Result<Attributes, crate::Error> {
+        for (item_service, item_account) in self.item_names(service, account)? {
+            match attributes(&item_service, &item_account) {
+                Err(ref e) if e.kind() == &Kind::NotFound => continue,
+                result => return result,
+            }
+        }
+        Err(crate::Error::new(
+            Kind::NotFound,
+            &format!("Couldn't find value for key: {}", account),
+        ))
+    }
+
+    fn set_attributes(
+        &self,
+        service: &str,
+        account: &str,
+        attributes: &Attributes,
+    ) -> Result<(), crate::Error> {
+        attributes::check(attributes)?;
+        let _guard = lock_item(service, account);
+        for (item_service, item_account) in self.item_names(service, account)? {
+            match set_attributes(&item_service, &item_account, attributes) {
+                Err(ref e) if e.kind() == &Kind::NotFound => continue,
+                result => return result,
+            }
+        }
+        Err(crate::Error::new(
+            Kind::NotFound,
+            &format!("Couldn't find value for key: {}", account),
+        ))
+    }
+
+    /// Items with hashed names are reported under their hashed account.
+    fn delete_all(&self, service: &str) -> Result<Vec<AccountInfo>, crate::Error> {
+        let mut remaining = Vec::new();
+        for item_service in self.service_names(service)? {
+            remaining.extend(
+                delete_all(&item_service)?
+                    .into_iter()
+                    .map(|account| AccountInfo {
+                        service: service.to_string(),
+                        account,
+                    }),
+            );
+        }
+        Ok(remaining)
+    }
+
+    /// Deletes every generic password the app can reach, including items
+    /// written by anything else in the app. Items with hashed names are
+    /// reported under their hashed service and account.
+    fn wipe(&self) -> Result<Vec<AccountInfo>, crate::Error> {
+        wipe()
+    }
+
+    /// Hashed names can't be mapped back to accounts, so this fails with
+    /// `ErrorKind::Unsupported` under `NameMode::Hashed`.
+    fn list(&self, service: &str) -> Result<Vec<AccountInfo>, crate::Error> {
+        if self.names == NameMode::Hashed {
+            return Err(crate::Error::new(
+                Kind::Unsupported,
+                "Items with hashed names can't be listed",
+            ));
+        }
+        Ok(list(service)?
+            .into_iter()
+            .filter(|account| !naming::is_internal(service, account))
+            .map(|account| AccountInfo {
+                service: service.to_string(),
+                account,
+            })
+            .collect())
+    }
+}
+
+/// `item_names` always returns at least the name an item is written to.
+fn no_name() -> crate::Error {
+    crate::Error::new(Kind::Other, "Item has no name")
+}
+
+/// Apply the writes of a journal left by an interrupted transaction, then
+/// remove it (see `journal::recover`). The caller holds the journal's lock.
+fn recover() -> Result<(), crate::Error> {
+    let stored = match get(naming::JOURNAL_SERVICE, naming::JOURNAL_ACCOUNT) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == &Kind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    journal::recover(&stored, replay, || {
+        delete(naming::JOURNAL_SERVICE, naming::JOURNAL_ACCOUNT)
+    })
+}
+
+/// Apply journaled writes. Puts overwrite and deletes ignore missing items,
+/// so writes applied before an interruption can be applied again.
+fn replay(stored: &[Write]) -> Result<(), crate::Error> {
+    for write in stored {
+        match write {
+            Write::Put {
+                service,
+                account,
+                value,
+                policy,
+            } => put(service, account, value, policy)?,
+            Write::Delete { service, account } => delete(service, account)?,
+        }
+    }
+    Ok(())
+}
+
+/// Secret used to hash item names, generated on first use. It never leaves
+/// the device and is readable while the device is locked, like `contains`.
+fn name_key() -> Result<SecretBytes, crate::Error> {
+    match get(naming::NAME_KEY_SERVICE, naming::NAME_KEY_ACCOUNT) {
+        Err(ref e) if e.kind() == &Kind::NotFound => {}
+        result => return result,
+    }
+    let mut secret = vec![0u8; naming::NAME_KEY_LENGTH];
+    let status = unsafe {
+        SecRandomCopyBytes(
+            kSecRandomDefault,
+            secret.len(),
+            secret.as_mut_ptr() as *mut c_void,
         )
     };
-    if !error.is_null() {
-        Err(format!("{}", Error::from(error)))
+    let secret = SecretBytes::from(secret);
+    if status != 0 {
+        return Err(crate::Error::new(
+            Kind::Other,
+            "Couldn't generate the secret used to hash names",
+        ));
+    }
+    let attrs = unsafe {
+        CFDictionary::from_CFType_pairs(&[
+            (
+                CFString::wrap_under_get_rule(kSecClass.into()).as_CFType(),
+                CFString::wrap_under_get_rule(kSecClassGenericPassword.into()).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecAttrService.into()).as_CFType(),
+                CFString::from(naming::NAME_KEY_SERVICE).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecAttrAccount.into()).as_CFType(),
+                CFString::from(naming::NAME_KEY_ACCOUNT).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecValueData.into()).as_CFType(),
+                borrowed_data(&secret).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecAttrAccessible.into()).as_CFType(),
+                CFString::wrap_under_get_rule(kSecAttrAccessibleAfterFirstUnlockThisDeviceOnly)
+                    .as_CFType(),
+            ),
+        ])
+    };
+    let mut result: CFTypeRef = ptr::null_mut();
+    let status = unsafe { SecItemAdd(attrs.as_concrete_TypeRef(), &mut result) };
+    if status == errSecDuplicateItem {
+        // Created concurrently; use the stored secret so every name agrees
+        get(naming::NAME_KEY_SERVICE, naming::NAME_KEY_ACCOUNT)
+    } else if let Some(e) = Error::maybe_from_OSStatus(status) {
+        Err(e.into())
+    } else {
+        Ok(secret)
+    }
+}
+
+/// `CFData` pointing at `bytes` instead of holding a copy, so that no copy of
+/// a secret is left in memory the library can't wipe. It must be released
+/// before `bytes` is.
+unsafe fn borrowed_data(bytes: &[u8]) -> CFData {
+    CFData::wrap_under_create_rule(CFDataCreateWithBytesNoCopy(
+        kCFAllocatorDefault,
+        bytes.as_ptr(),
+        bytes.len() as CFIndex,
+        kCFAllocatorNull,
+    ))
+}
+
+pub fn put(
+    service: &str,
+    account: &str,
+    value: &[u8],
+    policy: &AccessPolicy,
+) -> Result<(), crate::Error> {
+    let accessible =
+        unsafe { CFString::wrap_under_get_rule(accessibility(policy.accessibility())) };
+    let protection = if policy.constraints().is_empty() {
+        (
+            unsafe { CFString::wrap_under_get_rule(kSecAttrAccessible.into()) }.as_CFType(),
+            accessible.as_CFType(),
+        )
     } else {
+        let mut error: CFErrorRef = ptr::null_mut();
+        let access = unsafe {
+            SecAccessControlCreateWithFlags(
+                kCFAllocatorDefault,
+                accessible.as_CFTypeRef(),
+                access_control_flags(policy),
+                &mut error,
+            )
+        };
+        if !error.is_null() {
+            return Err(Error::from(error).into());
+        }
+        (
+            unsafe { CFString::wrap_under_get_rule(kSecAttrAccessControl.into()) }.as_CFType(),
+            unsafe { SecAccessControl::wrap_under_create_rule(access.into()) }.as_CFType(),
+        )
+    };
+    let data = unsafe {
+        (
+            CFString::wrap_under_get_rule(kSecValueData.into()).as_CFType(),
+            borrowed_data(value).as_CFType(),
+        )
+    };
+    let identity = unsafe {
+        [
+            (
+                CFString::wrap_under_get_rule(kSecClass.into()).as_CFType(),
+                CFString::wrap_under_get_rule(kSecClassGenericPassword.into()).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecAttrService.into()).as_CFType(),
+                CFString::from(service).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecAttrAccount.into()).as_CFType(),
+                CFString::from(account).as_CFType(),
+            ),
+        ]
+    };
+    // Add first and update if the item exists, so two puts racing on a new
+    // item can't both decide to add it
+    loop {
+        let mut attrs = identity.to_vec();
+        attrs.push(data.clone());
+        attrs.push(protection.clone());
+        let attrs = CFDictionary::from_CFType_pairs(&attrs);
         let mut result: CFTypeRef = ptr::null_mut();
-        contains(service, account).and_then(|contained| {
-            unsafe {
-                let attrs = if with_biometry {
-                    if contained {
-                        CFDictionary::from_CFType_pairs(&[
-                            (
-                                CFString::wrap_under_get_rule(kSecValueData.into()).as_CFType(),
-                                CFData::from_buffer(value.as_bytes()).as_CFType(),
-                            ),
-                            (
-                                CFString::wrap_under_get_rule(kSecAttrAccessControl.into())
-                                    .as_CFType(),
-                                SecAccessControl::wrap_under_get_rule(access.into()).as_CFType(),
-                            ),
-                        ])
-                    } else {
-                        CFDictionary::from_CFType_pairs(&[
-                            (
-                                CFString::wrap_under_get_rule(kSecClass.into()).as_CFType(),
-                                CFString::wrap_under_get_rule(kSecClassGenericPassword.into())
-                                    .as_CFType(),
-                            ),
-                            (
-                                CFString::wrap_under_get_rule(kSecAttrService.into()).as_CFType(),
-                                CFString::from(service).as_CFType(),
-                            ),
-                            (
-                                CFString::wrap_under_get_rule(kSecAttrAccount.into()).as_CFType(),
-                                CFString::from(account).as_CFType(),
-                            ),
-                            (
-                                CFString::wrap_under_get_rule(kSecValueData.into()).as_CFType(),
-                                CFData::from_buffer(value.as_bytes()).as_CFType(),
-                            ),
-                            (
-                                CFString::wrap_under_get_rule(kSecAttrAccessControl.into())
-                                    .as_CFType(),
-                                SecAccessControl::wrap_under_get_rule(access.into()).as_CFType(),
-                            ),
-                        ])
-                    }
-                } else {
-                    if contained {
-                        CFDictionary::from_CFType_pairs(&[(
-                            CFString::wrap_under_get_rule(kSecValueData.into()).as_CFType(),
-                            CFData::from_buffer(value.as_bytes()).as_CFType(),
-                        )])
-                    } else {
-                        CFDictionary::from_CFType_pairs(&[
-                            (
-                                CFString::wrap_under_get_rule(kSecClass.into()).as_CFType(),
-                                CFString::wrap_under_get_rule(kSecClassGenericPassword.into())
-                                    .as_CFType(),
-                            ),
-                            (
-                                CFString::wrap_under_get_rule(kSecAttrService.into()).as_CFType(),
-                                CFString::from(service).as_CFType(),
-                            ),
-                            (
-                                CFString::wrap_under_get_rule(kSecAttrAccount.into()).as_CFType(),
-                                CFString::from(account).as_CFType(),
-                            ),
-                            (
-                                CFString::wrap_under_get_rule(kSecValueData.into()).as_CFType(),
-                                CFData::from_buffer(value.as_bytes()).as_CFType(),
-                            ),
-                        ])
-                    }
-                };
-                let status = if contained {
-                    let query = CFDictionary::from_CFType_pairs(&[
-                        (
-                            CFString::wrap_under_get_rule(kSecClass.into()).as_CFType(),
-                            CFString::wrap_under_get_rule(kSecClassGenericPassword.into())
-                                .as_CFType(),
-                        ),
-                        (
-                            CFString::wrap_under_get_rule(kSecAttrService.into()).as_CFType(),
-                            CFString::from(service).as_CFType(),
-                        ),
-                        (
-                            CFString::wrap_under_get_rule(kSecAttrAccount.into()).as_CFType(),
-                            CFString::from(account).as_CFType(),
-                        ),
-                        //                        (
-                        //                            CFString::wrap_under_get_rule(kSecMatchLimit.into()).as_CFType(),
-                        //                            CFString::wrap_under_get_rule(kSecMatchLimitOne.into()).as_CFType(),
-                        //                        ),
-                    ]);
-                    SecItemUpdate(query.as_concrete_TypeRef(), attrs.as_concrete_TypeRef())
-                } else {
-                    SecItemAdd(attrs.as_concrete_TypeRef(), &mut result)
-                };
-                if let Some(e) = Error::maybe_from_OSStatus(status) {
-                    Err(format!("{}", e))
-                } else {
-                    Ok(())
-                }
+        let status = unsafe { SecItemAdd(attrs.as_concrete_TypeRef(), &mut result) };
+        match Error::maybe_from_OSStatus(status).map(crate::Error::from) {
+            Some(ref e) if e.kind() == &Kind::DuplicateItem => {}
+            Some(e) => return Err(e),
+            None => return Ok(()),
+        }
+
+        let query = CFDictionary::from_CFType_pairs(&identity);
+        let attrs = CFDictionary::from_CFType_pairs(&[data.clone(), protection.clone()]);
+        let status =
+            unsafe { SecItemUpdate(query.as_concrete_TypeRef(), attrs.as_concrete_TypeRef()) };
+        match Error::maybe_from_OSStatus(status).map(crate::Error::from) {
+            // Deleted since the add failed, so add it again
+            Some(ref e) if e.kind() == &Kind::NotFound => {}
+            Some(e) => return Err(e),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// `kSecAttrAccessible*` value for an accessibility class.
+fn accessibility(accessibility: Accessibility) -> CFStringRef {
+    unsafe {
+        match accessibility {
+            Accessibility::WhenUnlockedThisDeviceOnly => {
+                kSecAttrAccessibleWhenUnlockedThisDeviceOnly
             }
-        })
+            Accessibility::WhenUnlocked => kSecAttrAccessibleWhenUnlocked,
+            Accessibility::AfterFirstUnlockThisDeviceOnly => {
+                kSecAttrAccessibleAfterFirstUnlockThisDeviceOnly
+            }
+            Accessibility::AfterFirstUnlock => kSecAttrAccessibleAfterFirstUnlock,
+            Accessibility::WhenPasscodeSetThisDeviceOnly => {
+                kSecAttrAccessibleWhenPasscodeSetThisDeviceOnly
+            }
+        }
+    }
+}
+
+/// `SecAccessControlCreateFlags` for the constraints of a policy.
+fn access_control_flags(policy: &AccessPolicy) -> CFOptionFlags {
+    let flags = policy.constraints().iter().fold(0, |flags, constraint| {
+        flags
+            | match constraint {
+                Constraint::BiometryCurrentSet => kSecAccessControlBiometryCurrentSet,
+                Constraint::BiometryAny => kSecAccessControlBiometryAny,
+                Constraint::UserPresence => kSecAccessControlUserPresence,
+                Constraint::DevicePasscode => kSecAccessControlDevicePasscode,
+                Constraint::ApplicationPassword => kSecAccessControlApplicationPassword,
+            }
+    });
+    if policy.constraints().len() > 1 {
+        flags
+            | match policy.combinator() {
+                Combinator::And => kSecAccessControlAnd,
+                Combinator::Or => kSecAccessControlOr,
+            }
+    } else {
+        flags
     }
 }
 
-pub fn get(service: &str, account: &str) -> Result<String, String> {
+pub fn get(service: &str, account: &str) -> Result<SecretBytes, crate::Error> {
     let query = unsafe {
         CFDictionary::from_CFType_pairs(&[
             (
@@ -178,30 +563,28 @@ pub fn get(service: &str, account: &str) -> Result<String, String> {
     let mut result: CFTypeRef = ptr::null_mut();
     let status = unsafe { SecItemCopyMatching(query.as_concrete_TypeRef(), &mut result) };
     if let Some(e) = Error::maybe_from_OSStatus(status) {
-        Err(format!("{}", e))
+        Err(e.into())
     } else {
+        // Owned, so the dictionary and the data holding the value are
+        // released once copied out
+        let found: CFDictionary =
+            unsafe { CFDictionary::wrap_under_create_rule(result as CFDictionaryRef) };
         unsafe {
-            (&*CFDictionary::from_void(result) as &CFDictionary)
-                .find(kSecValueData.to_void())
-                .map(|item| {
-                    let data: &CFData = &*CFData::from_void(*item);
-                    let length = CFDataGetLength(data.as_concrete_TypeRef());
-                    let bytes = CFDataGetBytePtr(data.as_concrete_TypeRef());
-                    CFString::wrap_under_get_rule(CFStringCreateWithBytes(
-                        kCFAllocatorDefault,
-                        bytes,
-                        length,
-                        kCFStringEncodingUTF8,
-                        0,
-                    ))
-                    .to_string()
-                })
+            found.find(kSecValueData.to_void()).map(|item| {
+                let data: &CFData = &*CFData::from_void(*item);
+                SecretBytes::from(data.bytes())
+            })
         }
-        .ok_or(format!("Couldn't find value for key: {}", account))
+        .ok_or_else(|| {
+            crate::Error::new(
+                Kind::NotFound,
+                &format!("Couldn't find value for key: {}", account),
+            )
+        })
     }
 }
 
-pub fn contains(service: &str, account: &str) -> Result<bool, String> {
+pub fn contains(service: &str, account: &str) -> Result<bool, crate::Error> {
     let query = unsafe {
         CFDictionary::from_CFType_pairs(&[
             (
@@ -229,11 +612,13 @@ pub fn contains(service: &str, account: &str) -> Result<bool, String> {
     } else if status == errSecItemNotFound {
         Ok(false)
     } else {
-        Error::maybe_from_OSStatus(status).map_or(Ok(true), |e| Err(format!("{}", e)))
+        Error::maybe_from_OSStatus(status).map_or(Ok(true), |e| Err(e.into()))
     }
 }
 
-pub fn delete(service: &str, account: &str) -> Result<(), String> {
+/// Attributes of an item, read without its data so the user is never asked to
+/// authenticate.
+pub fn attributes(service: &str, account: &str) -> Result<Attributes, crate::Error> {
     let query = unsafe {
         CFDictionary::from_CFType_pairs(&[
             (
@@ -248,11 +633,258 @@ pub fn delete(service: &str, account: &str) -> Result<(), String> {
                 CFString::wrap_under_get_rule(kSecAttrAccount.into()).as_CFType(),
                 CFString::from(account).as_CFType(),
             ),
+            (
+                CFString::wrap_under_get_rule(kSecMatchLimit.into()).as_CFType(),
+                CFString::wrap_under_get_rule(kSecMatchLimitOne.into()).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecReturnAttributes.into()).as_CFType(),
+                CFBoolean::from(true).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecUseAuthenticationUI.into()).as_CFType(),
+                CFString::wrap_under_get_rule(kSecUseAuthenticationUIFail.into()).as_CFType(),
+            ),
         ])
     };
-    let status = unsafe { SecItemDelete(query.as_concrete_TypeRef()) };
+    let mut result: CFTypeRef = ptr::null_mut();
+    let status = unsafe { SecItemCopyMatching(query.as_concrete_TypeRef(), &mut result) };
+    if let Some(e) = Error::maybe_from_OSStatus(status) {
+        return Err(e.into());
+    }
+    let found: CFDictionary =
+        unsafe { CFDictionary::wrap_under_create_rule(result as CFDictionaryRef) };
+    let string = |key: CFStringRef| unsafe {
+        found
+            .find(key.to_void())
+            .map(|value| CFString::from_void(*value).to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let time = |key: CFStringRef| unsafe {
+        found.find(key.to_void()).and_then(|value| {
+            // CFAbsoluteTime counts seconds from 2001-01-01
+            let since_2001 = CFDate::from_void(*value).abs_time();
+            let since_1970 = since_2001 + 978_307_200.0;
+            if since_1970 >= 0.0 {
+                UNIX_EPOCH.checked_add(Duration::from_secs_f64(since_1970))
+            } else {
+                None
+            }
+        })
+    };
+    let custom = match unsafe { found.find(kSecAttrGeneric.to_void()) } {
+        Some(value) => {
+            let data: &CFData = unsafe { &*CFData::from_void(*value) };
+            attributes::decode_custom(data.bytes())?
+        }
+        None => Default::default(),
+    };
+    Ok(Attributes {
+        label: string(unsafe { kSecAttrLabel }),
+        description: string(unsafe { kSecAttrDescription }),
+        created: time(unsafe { kSecAttrCreationDate }),
+        modified: time(unsafe { kSecAttrModificationDate }),
+        custom,
+    })
+}
+
+/// Replace the label, description and custom attributes of an item. The
+/// keychain keeps its times.
+pub fn set_attributes(
+    service: &str,
+    account: &str,
+    attributes: &Attributes,
+) -> Result<(), crate::Error> {
+    let query = unsafe {
+        CFDictionary::from_CFType_pairs(&[
+            (
+                CFString::wrap_under_get_rule(kSecClass.into()).as_CFType(),
+                CFString::wrap_under_get_rule(kSecClassGenericPassword.into()).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecAttrService.into()).as_CFType(),
+                CFString::from(service).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecAttrAccount.into()).as_CFType(),
+                CFString::from(account).as_CFType(),
+            ),
+        ])
+    };
+    // The keychain has no way to remove an attribute, so none is stored as
+    // empty
+    let attrs = unsafe {
+        CFDictionary::from_CFType_pairs(&[
+            (
+                CFString::wrap_under_get_rule(kSecAttrLabel.into()).as_CFType(),
+                CFString::from(attributes.label.as_deref().unwrap_or("")).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecAttrDescription.into()).as_CFType(),
+                CFString::from(attributes.description.as_deref().unwrap_or("")).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecAttrGeneric.into()).as_CFType(),
+                CFData::from_buffer(&attributes::encode_custom(&attributes.custom)).as_CFType(),
+            ),
+        ])
+    };
+    let status = unsafe { SecItemUpdate(query.as_concrete_TypeRef(), attrs.as_concrete_TypeRef()) };
+    if let Some(e) = Error::maybe_from_OSStatus(status) {
+        Err(e.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Accounts of every generic password stored under `service`, sorted.
+pub fn list(service: &str) -> Result<Vec<String>, crate::Error> {
+    Ok(items(Some(service))?
+        .into_iter()
+        .map(|item| item.account)
+        .collect())
+}
+
+/// Every generic password, or those stored under `service`, sorted.
+fn items(service: Option<&str>) -> Result<Vec<AccountInfo>, crate::Error> {
+    let mut pairs = vec![unsafe {
+        (
+            CFString::wrap_under_get_rule(kSecClass.into()).as_CFType(),
+            CFString::wrap_under_get_rule(kSecClassGenericPassword.into()).as_CFType(),
+        )
+    }];
+    if let Some(service) = service {
+        pairs.push(unsafe {
+            (
+                CFString::wrap_under_get_rule(kSecAttrService.into()).as_CFType(),
+                CFString::from(service).as_CFType(),
+            )
+        });
+    }
+    pairs.extend(unsafe {
+        vec![
+            (
+                CFString::wrap_under_get_rule(kSecMatchLimit.into()).as_CFType(),
+                CFString::wrap_under_get_rule(kSecMatchLimitAll.into()).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecReturnAttributes.into()).as_CFType(),
+                CFBoolean::from(true).as_CFType(),
+            ),
+        ]
+    });
+    let query = CFDictionary::from_CFType_pairs(&pairs);
+    let mut result: CFTypeRef = ptr::null_mut();
+    let status = unsafe { SecItemCopyMatching(query.as_concrete_TypeRef(), &mut result) };
+    if status == errSecItemNotFound {
+        return Ok(Vec::new());
+    }
     if let Some(e) = Error::maybe_from_OSStatus(status) {
-        Err(format!("{}", e))
+        return Err(e.into());
+    }
+    let found: CFArray = unsafe { CFArray::wrap_under_create_rule(result as CFArrayRef) };
+    let mut items: Vec<AccountInfo> = found
+        .iter()
+        .map(|item| unsafe {
+            let attributes = &*CFDictionary::from_void(*item) as &CFDictionary;
+            let attribute = |key: CFStringRef| {
+                attributes
+                    .find(key.to_void())
+                    .map(|value| CFString::from_void(*value).to_string())
+                    .unwrap_or_default()
+            };
+            AccountInfo {
+                service: attribute(kSecAttrService),
+                account: attribute(kSecAttrAccount),
+            }
+        })
+        .collect();
+    items.sort();
+    Ok(items)
+}
+
+/// Delete every generic password under `service`, returning the accounts of
+/// those which remain. Items the library keeps for itself are neither
+/// deleted nor reported.
+pub fn delete_all(service: &str) -> Result<Vec<String>, crate::Error> {
+    let accounts = list(service)?;
+    // A failed delete shows up in the listing
+    if accounts
+        .iter()
+        .any(|account| naming::is_internal(service, account))
+    {
+        for account in accounts
+            .iter()
+            .filter(|account| !naming::is_internal(service, account))
+        {
+            let _ = delete(service, account);
+        }
+    } else {
+        let _ = delete_matching(Some(service));
+    }
+    Ok(list(service)?
+        .into_iter()
+        .filter(|account| !naming::is_internal(service, account))
+        .collect())
+}
+
+/// Delete every generic password the app can reach, returning those which
+/// remain. This includes items written by anything else in the app.
+pub fn wipe() -> Result<Vec<AccountInfo>, crate::Error> {
+    // A failed delete shows up in the listing
+    let _ = delete_matching(None);
+    items(None)
+}
+
+/// Delete every generic password, or those stored under `service`.
+fn delete_matching(service: Option<&str>) -> Result<(), crate::Error> {
+    let mut pairs = vec![unsafe {
+        (
+            CFString::wrap_under_get_rule(kSecClass.into()).as_CFType(),
+            CFString::wrap_under_get_rule(kSecClassGenericPassword.into()).as_CFType(),
+        )
+    }];
+    if let Some(service) = service {
+        pairs.push(unsafe {
+            (
+                CFString::wrap_under_get_rule(kSecAttrService.into()).as_CFType(),
+                CFString::from(service).as_CFType(),
+            )
+        });
+    }
+    let query = CFDictionary::from_CFType_pairs(&pairs);
+    let status = unsafe { SecItemDelete(query.as_concrete_TypeRef()) };
+    if status == errSecItemNotFound {
+        Ok(())
+    } else if let Some(e) = Error::maybe_from_OSStatus(status) {
+        Err(e.into())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn delete(service: &str, account: &str) -> Result<(), crate::Error> {
+    let query = unsafe {
+        CFDictionary::from_CFType_pairs(&[
+            (
+                CFString::wrap_under_get_rule(kSecClass.into()).as_CFType(),
+                CFString::wrap_under_get_rule(kSecClassGenericPassword.into()).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecAttrService.into()).as_CFType(),
+                CFString::from(service).as_CFType(),
+            ),
+            (
+                CFString::wrap_under_get_rule(kSecAttrAccount.into()).as_CFType(),
+                CFString::from(account).as_CFType(),
+            ),
+        ])
+    };
+    let status = unsafe { SecItemDelete(query.as_concrete_TypeRef()) };
+    if status == errSecItemNotFound {
+        Ok(())
+    } else if let Some(e) = Error::maybe_from_OSStatus(status) {
+        Err(e.into())
     } else {
         Ok(())
     }
@@ -1,79 +1,242 @@
-use crate::Return;
-use libc::c_char;
-use std::cell::Cell;
+#[cfg(feature = "typed")]
+use crate::Json;
+use crate::{Error, ErrorKind, Return, SecretBytes, SecretString};
+use libc::{c_char, c_uint};
+use std::{
+    cell::Cell,
+    ffi::CString,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+use zeroize::Zeroize;
 
+/// Value returned by an export together with its failure, if any.
+///
+/// `error_code` is zero on success, otherwise the stable code of the
+/// `ErrorKind` that caused the failure (see `ErrorKind::code`), and
+/// `error_msg` holds its description.
 #[repr(C)]
 pub struct CResult<T> {
     pub value: T,
+    pub error_code: i32,
     pub error_msg: *mut c_char,
 }
 
+/// Pointer and length of a byte buffer crossing the C boundary.
+///
+/// Buffers returned by the library must be released with
+/// `secure_native_buffer_free`; buffers passed in remain owned by the caller.
+#[repr(C)]
+pub struct CBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+/// Buffers may hold secret values, so they are wiped before being released.
+#[no_mangle]
+pub extern "C" fn secure_native_buffer_free(buf: CBuffer) {
+    if !buf.data.is_null() {
+        let mut bytes = unsafe { Box::from_raw(std::slice::from_raw_parts_mut(buf.data, buf.len)) };
+        bytes.zeroize();
+    }
+}
+
+/// Wipe and release a string returned for a secret value.
+#[no_mangle]
+pub extern "C" fn secure_native_secret_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        unsafe { CString::from_raw(s) }
+            .into_bytes_with_nul()
+            .zeroize();
+    }
+}
+
+/// Pointer and length of an array of C strings returned by the library.
+///
+/// Release it with `secure_native_string_array_free`, which also frees the
+/// strings.
+#[repr(C)]
+pub struct CStringArray {
+    pub data: *mut *mut c_char,
+    pub len: usize,
+}
+
+#[no_mangle]
+pub extern "C" fn secure_native_string_array_free(array: CStringArray) {
+    if !array.data.is_null() {
+        unsafe {
+            let strings = Box::from_raw(std::slice::from_raw_parts_mut(array.data, array.len));
+            for s in strings.iter() {
+                ffi_support::destroy_c_string(*s);
+            }
+        }
+    }
+}
+
 impl Return<'static> for () {
     type Ext = *mut std::ffi::c_void;
     type Env = Cell<u32>;
-    fn convert(_: &Self::Env, val: Self) -> Self::Ext {
-        std::ptr::null_mut()
+    fn convert(_: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        Ok(ptr::null_mut())
     }
 }
 
 impl Return<'static> for bool {
     type Ext = *mut u8;
     type Env = Cell<u32>;
-    fn convert(_: &Self::Env, val: Self) -> Self::Ext {
-        Box::into_raw(Box::new(val as u8))
+    fn convert(_: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        Ok(Box::into_raw(Box::new(val as u8)))
     }
 }
 
 impl Return<'static> for String {
     type Ext = *mut c_char;
     type Env = Cell<u32>;
-    fn convert(_: &Self::Env, val: Self) -> Self::Ext {
-        ffi_support::rust_string_to_c(val)
+    fn convert(_: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        CString::new(val)
+            .map(CString::into_raw)
+            .map_err(|_| nul_byte())
     }
 }
 
-impl<Inner: Return<'static, Env = Cell<u32>> + Default> Return<'static> for Option<Inner> {
-    type Ext = Inner::Ext;
-    type Env = Inner::Env;
-    fn convert(env: &Self::Env, val: Self) -> Self::Ext {
-        let val = match val {
-            Some(inner) => inner,
-            None => {
-                env.set(1);
-                Inner::default()
-            }
-        };
-        Return::convert(env, val)
+/// Release the string with `secure_native_secret_string_free`.
+#[cfg(feature = "typed")]
+impl<T: serde::Serialize> Return<'static> for Json<T> {
+    type Ext = *mut c_char;
+    type Env = Cell<u32>;
+    fn convert(env: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        Return::convert(env, val.to_json()?)
     }
 }
 
-impl<Inner: Return<'static, Env = Cell<u32>> + Default> Return<'static> for Result<Inner, String> {
-    type Ext = Inner::Ext;
-    type Env = Inner::Env;
-    fn convert(env: &Self::Env, val: Self) -> Self::Ext {
-        let val = match val {
-            Ok(inner) => inner,
+/// Release the string with `secure_native_secret_string_free`.
+impl Return<'static> for SecretString {
+    type Ext = *mut c_char;
+    type Env = Cell<u32>;
+    fn convert(_: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        // Room for the terminator, so that `CString` doesn't reallocate
+        let mut bytes = Vec::with_capacity(val.len() + 1);
+        bytes.extend_from_slice(val.as_bytes());
+        match CString::new(bytes) {
+            Ok(s) => Ok(s.into_raw()),
             Err(e) => {
-                env.set(1);
-                Inner::default()
+                e.into_vec().zeroize();
+                Err(nul_byte())
             }
-        };
-        Return::convert(env, val)
+        }
     }
+}
 
-    fn convert_cresult(env: &Self::Env, val: Self) -> *mut CResult<Self::Ext> {
-        match val {
-            Ok(inner) => Box::into_raw(Box::new(CResult {
-                error_msg: Return::convert(env, String::default()),
-                value: Return::convert(env, inner),
-            })),
-            Err(e) => {
-                env.set(1);
-                Box::into_raw(Box::new(CResult {
-                    error_msg: Return::convert(env, e),
-                    value: Return::convert(env, Inner::default()),
-                }))
+impl Return<'static> for SecretBytes {
+    type Ext = CBuffer;
+    type Env = Cell<u32>;
+    fn convert(_: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        let len = val.len();
+        let data = Box::into_raw(val.to_vec().into_boxed_slice()) as *mut u8;
+        Ok(CBuffer { data, len })
+    }
+}
+
+impl Return<'static> for Vec<u8> {
+    type Ext = CBuffer;
+    type Env = Cell<u32>;
+    fn convert(_: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        let len = val.len();
+        let data = Box::into_raw(val.into_boxed_slice()) as *mut u8;
+        Ok(CBuffer { data, len })
+    }
+}
+
+impl Return<'static> for Vec<String> {
+    type Ext = CStringArray;
+    type Env = Cell<u32>;
+    fn convert(env: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        let mut strings: Vec<*mut c_char> = Vec::with_capacity(val.len());
+        for s in val {
+            match Return::convert(env, s) {
+                Ok(s) => strings.push(s),
+                Err(e) => {
+                    for s in strings {
+                        ffi_support::destroy_c_string(s);
+                    }
+                    return Err(e);
+                }
             }
         }
+        let len = strings.len();
+        let data = Box::into_raw(strings.into_boxed_slice()) as *mut *mut c_char;
+        Ok(CStringArray { data, len })
     }
 }
+
+/// Values returned by a failed export, alongside the error.
+pub trait Null {
+    fn null() -> Self;
+}
+
+impl<T> Null for *mut T {
+    fn null() -> Self {
+        ptr::null_mut()
+    }
+}
+
+impl Null for CBuffer {
+    fn null() -> Self {
+        CBuffer {
+            data: ptr::null_mut(),
+            len: 0,
+        }
+    }
+}
+
+impl Null for CStringArray {
+    fn null() -> Self {
+        CStringArray {
+            data: ptr::null_mut(),
+            len: 0,
+        }
+    }
+}
+
+/// Run the body of an export, reporting its error through the `CResult` and
+/// `err`, which may be NULL.
+///
+/// Panics are caught and reported as `ErrorKind::Other`, as unwinding into
+/// Swift would abort the application.
+pub fn guard<T: Null, F: FnOnce(&Cell<u32>) -> Result<T, Error>>(
+    err: *mut c_uint,
+    export: F,
+) -> *mut CResult<T> {
+    let env = Cell::new(0);
+    let result = match panic::catch_unwind(AssertUnwindSafe(|| export(&env))) {
+        Ok(result) => result,
+        Err(panic) => Err(Error::from_panic(panic)),
+    };
+    let ret = match result {
+        Ok(value) => CResult {
+            value,
+            error_code: 0,
+            error_msg: c_message(""),
+        },
+        Err(e) => CResult {
+            value: T::null(),
+            error_code: e.code(),
+            error_msg: c_message(&e.to_string()),
+        },
+    };
+    if !err.is_null() {
+        unsafe { *err = ret.error_code as c_uint };
+    }
+    Box::into_raw(Box::new(ret))
+}
+
+/// `message` as a C string, dropping any nul bytes.
+fn c_message(message: &str) -> *mut c_char {
+    CString::new(message.replace('\0', ""))
+        .unwrap_or_default()
+        .into_raw()
+}
+
+fn nul_byte() -> Error {
+    Error::new(ErrorKind::InvalidData, "String contained a nul byte")
+}
@@ -0,0 +1,368 @@
+//! Write-ahead journal of transactions
+//!
+//! Backends which can't commit several writes at once (currently iOS) store
+//! the writes of a transaction as one journal item before applying them, and
+//! replay a journal left behind by an interrupted transaction. Writes in the
+//! journal address items by the names they are stored under, so replaying
+//! doesn't depend on how the store was opened.
+//!
+//! The journal holds the values it stages, so it is protected like the most
+//! protected of them (see `policy`).
+//!
+//! ```text
+//! version: u8 | count: u32 | writes
+//! write: 1 | service | account | value | policy: u32
+//!      | 2 | service | account
+//! ```
+//!
+//! Integers are little-endian and fields are prefixed with their length as a
+//! `u32`. Parsing never panics on malformed input.
+
+use crate::{AccessPolicy, Accessibility, Error, ErrorKind, SecretBytes, Write};
+
+use std::convert::TryInto;
+
+/// Current journal format version.
+pub const VERSION: u8 = 1;
+
+const PUT: u8 = 1;
+const DELETE: u8 = 2;
+
+/// Serialize `writes` in the current format version.
+pub fn encode(writes: &[Write]) -> SecretBytes {
+    // Sized up front, counting tags, lengths and policies, so that growing it
+    // leaves no copy of the values behind
+    let len = writes.iter().fold(5, |len, write| match write {
+        Write::Put {
+            service,
+            account,
+            value,
+            ..
+        } => len + 17 + service.len() + account.len() + value.len(),
+        Write::Delete { service, account } => len + 9 + service.len() + account.len(),
+    });
+    let mut out = Vec::with_capacity(len);
+    out.push(VERSION);
+    out.extend_from_slice(&(writes.len() as u32).to_le_bytes());
+    for write in writes {
+        match write {
+            Write::Put {
+                service,
+                account,
+                value,
+                policy,
+            } => {
+                out.push(PUT);
+                for field in &[service.as_bytes(), account.as_bytes(), value.as_bytes()] {
+                    write_field(&mut out, field);
+                }
+                out.extend_from_slice(&policy.to_bits().to_le_bytes());
+            }
+            Write::Delete { service, account } => {
+                out.push(DELETE);
+                write_field(&mut out, service.as_bytes());
+                write_field(&mut out, account.as_bytes());
+            }
+        }
+    }
+    SecretBytes::from(out)
+}
+
+/// Parse a journal.
+///
+/// Fails with `ErrorKind::InvalidData` on unknown versions or writes and on
+/// truncated input.
+pub fn decode(mut bytes: &[u8]) -> Result<Vec<Write>, Error> {
+    match read_u8(&mut bytes)? {
+        VERSION => {}
+        version => {
+            return Err(invalid(&format!(
+                "Unsupported journal version: {}",
+                version
+            )))
+        }
+    }
+    let count = read_u32(&mut bytes)?;
+    let mut writes = Vec::new();
+    for _ in 0..count {
+        let tag = read_u8(&mut bytes)?;
+        let service = read_string(&mut bytes)?;
+        let account = read_string(&mut bytes)?;
+        writes.push(match tag {
+            PUT => {
+                let value = SecretBytes::from(read_field(&mut bytes)?);
+                let policy = AccessPolicy::from_bits(read_u32(&mut bytes)?)?;
+                Write::Put {
+                    service,
+                    account,
+                    value,
+                    policy,
+                }
+            }
+            DELETE => Write::Delete { service, account },
+            tag => return Err(invalid(&format!("Unknown journal write: {}", tag))),
+        });
+    }
+    if bytes.is_empty() {
+        Ok(writes)
+    } else {
+        Err(invalid("Trailing data in journal"))
+    }
+}
+
+/// Policy to store the journal of `writes` under: readable no sooner than
+/// any value in it, and never migrated to another device.
+///
+/// Fails with `ErrorKind::Unsupported` if a put requires user
+/// authentication, which the journal can't: recovering it must not depend on
+/// the user being there.
+pub fn policy(writes: &[Write]) -> Result<AccessPolicy, Error> {
+    let mut accessibility = Accessibility::AfterFirstUnlockThisDeviceOnly;
+    for write in writes {
+        if let Write::Put { policy, .. } = write {
+            if !policy.constraints().is_empty() {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "Values requiring user authentication can't be part of a transaction",
+                ));
+            }
+            let this_device = this_device_only(policy.accessibility());
+            if strictness(this_device) > strictness(accessibility) {
+                accessibility = this_device;
+            }
+        }
+    }
+    Ok(AccessPolicy::default().accessible(accessibility))
+}
+
+fn this_device_only(accessibility: Accessibility) -> Accessibility {
+    match accessibility {
+        Accessibility::WhenUnlocked => Accessibility::WhenUnlockedThisDeviceOnly,
+        Accessibility::AfterFirstUnlock => Accessibility::AfterFirstUnlockThisDeviceOnly,
+        other => other,
+    }
+}
+
+fn strictness(accessibility: Accessibility) -> u8 {
+    match accessibility {
+        Accessibility::AfterFirstUnlock | Accessibility::AfterFirstUnlockThisDeviceOnly => 0,
+        Accessibility::WhenUnlocked | Accessibility::WhenUnlockedThisDeviceOnly => 1,
+        Accessibility::WhenPasscodeSetThisDeviceOnly => 2,
+    }
+}
+
+/// Finish the transaction of a journal left behind, given its bytes: replay
+/// its writes, then discard it.
+///
+/// A journal which can't be parsed could never be replayed, so it is
+/// discarded too, and the error returned this once. The transaction it
+/// recorded may then be partly applied.
+pub fn recover<R, D>(stored: &[u8], replay: R, discard: D) -> Result<(), Error>
+where
+    R: FnOnce(&[Write]) -> Result<(), Error>,
+    D: FnOnce() -> Result<(), Error>,
+{
+    match decode(stored) {
+        Ok(writes) => {
+            replay(&writes)?;
+            discard()
+        }
+        Err(e) => {
+            discard()?;
+            Err(invalid(&format!(
+                "Discarded the unreadable journal of an interrupted transaction: {}",
+                e.description()
+            )))
+        }
+    }
+}
+
+fn write_field(out: &mut Vec<u8>, field: &[u8]) {
+    out.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    out.extend_from_slice(field);
+}
+
+fn read_u8(bytes: &mut &[u8]) -> Result<u8, Error> {
+    let (&head, rest) = bytes
+        .split_first()
+        .ok_or_else(|| invalid("Truncated journal"))?;
+    *bytes = rest;
+    Ok(head)
+}
+
+fn read_u32(bytes: &mut &[u8]) -> Result<u32, Error> {
+    if bytes.len() < 4 {
+        return Err(invalid("Truncated journal"));
+    }
+    let (head, rest) = bytes.split_at(4);
+    *bytes = rest;
+    Ok(u32::from_le_bytes(head.try_into().unwrap()))
+}
+
+fn read_field<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+    let len = read_u32(bytes)? as usize;
+    if bytes.len() < len {
+        return Err(invalid("Truncated journal"));
+    }
+    let (field, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(field)
+}
+
+fn read_string(bytes: &mut &[u8]) -> Result<String, Error> {
+    String::from_utf8(read_field(bytes)?.to_vec()).map_err(|e| invalid(&e))
+}
+
+fn invalid<D: ToString + ?Sized>(description: &D) -> Error {
+    Error::new(ErrorKind::InvalidData, description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::arbitrary_inputs;
+    use crate::Constraint;
+
+    use std::cell::Cell;
+
+    fn writes() -> Vec<Write> {
+        vec![
+            Write::Put {
+                service: "service".to_string(),
+                account: "token".to_string(),
+                value: SecretBytes::from(&b"secret"[..]),
+                policy: AccessPolicy::default().require(Constraint::BiometryAny),
+            },
+            Write::Delete {
+                service: "service".to_string(),
+                account: "old".to_string(),
+            },
+            Write::Put {
+                service: String::new(),
+                account: "é".to_string(),
+                value: SecretBytes::default(),
+                policy: AccessPolicy::default(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(decode(&encode(&writes())).unwrap(), writes());
+        assert_eq!(decode(&encode(&[])).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_truncated_input_is_rejected() {
+        let bytes = encode(&writes());
+        for len in 0..bytes.len() {
+            let error = decode(&bytes[..len]).unwrap_err();
+            assert_eq!(error.kind(), &ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_unknown_input_is_rejected() {
+        let bytes = encode(&writes()).to_vec();
+        // Version and first write's tag
+        for (index, value) in &[(0, 0), (0, 2), (5, 0), (5, 3)] {
+            let mut bytes = bytes.clone();
+            bytes[*index] = *value;
+            let error = decode(&bytes).unwrap_err();
+            assert_eq!(error.kind(), &ErrorKind::InvalidData);
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            decode(&trailing).unwrap_err().kind(),
+            &ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_policy() {
+        let put = |policy: AccessPolicy| Write::Put {
+            service: "service".to_string(),
+            account: "account".to_string(),
+            value: SecretBytes::default(),
+            policy,
+        };
+        let delete = || Write::Delete {
+            service: "service".to_string(),
+            account: "account".to_string(),
+        };
+        let accessible = |accessibility| AccessPolicy::default().accessible(accessibility);
+
+        assert_eq!(
+            policy(&[delete()]).unwrap(),
+            accessible(Accessibility::AfterFirstUnlockThisDeviceOnly)
+        );
+        assert_eq!(
+            policy(&[put(accessible(Accessibility::AfterFirstUnlock)), delete()]).unwrap(),
+            accessible(Accessibility::AfterFirstUnlockThisDeviceOnly)
+        );
+        assert_eq!(
+            policy(&[
+                put(accessible(Accessibility::WhenUnlocked)),
+                put(accessible(Accessibility::AfterFirstUnlock)),
+            ])
+            .unwrap(),
+            accessible(Accessibility::WhenUnlockedThisDeviceOnly)
+        );
+        assert_eq!(
+            policy(&[
+                put(accessible(Accessibility::WhenPasscodeSetThisDeviceOnly)),
+                put(AccessPolicy::default()),
+            ])
+            .unwrap(),
+            accessible(Accessibility::WhenPasscodeSetThisDeviceOnly)
+        );
+        let error = policy(&writes()).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_recover() {
+        let (replayed, discarded) = (Cell::new(None), Cell::new(0));
+        let replay = |writes: &[Write]| {
+            replayed.set(Some(writes.to_vec()));
+            Ok(())
+        };
+        let discard = || {
+            discarded.set(discarded.get() + 1);
+            Ok(())
+        };
+        recover(&encode(&writes()), replay, discard).unwrap();
+        assert_eq!(replayed.take(), Some(writes()));
+        assert_eq!(discarded.get(), 1);
+
+        // A failed replay keeps the journal for the next attempt
+        let failing = |_: &[Write]| Err(invalid("Replay failed"));
+        assert!(recover(&encode(&writes()), failing, discard).is_err());
+        assert_eq!(discarded.get(), 1);
+    }
+
+    #[test]
+    fn test_corrupt_journal_is_discarded() {
+        let discarded = Cell::new(false);
+        let replay = |_: &[Write]| -> Result<(), Error> { panic!("replayed a corrupt journal") };
+        let discard = || {
+            discarded.set(true);
+            Ok(())
+        };
+        let mut corrupt = encode(&writes()).to_vec();
+        corrupt.truncate(corrupt.len() - 1);
+        let error = recover(&corrupt, replay, discard).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::InvalidData);
+        assert!(discarded.get());
+    }
+
+    #[test]
+    fn test_arbitrary_input_does_not_panic() {
+        let mut header = vec![VERSION];
+        header.extend_from_slice(&1u32.to_le_bytes());
+        for bytes in arbitrary_inputs(&header) {
+            let _ = decode(&bytes);
+        }
+    }
+}
@@ -1,13 +1,52 @@
 #![feature(trace_macros, option_flattening)]
 #![allow(non_snake_case, non_upper_case_globals, dead_code)]
 
+// Lets the code generated by `export` name this crate from inside it too
+extern crate self as secure_native;
+
 #[cfg(target_os = "android")]
 pub mod android;
+mod attributes;
+pub mod conformance;
+pub mod envelope;
+mod error;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod guarded;
 #[cfg(target_os = "ios")]
 pub mod ios;
+mod journal;
+#[cfg(target_os = "linux")]
+pub mod linux;
+mod lock;
+pub mod memory;
+mod naming;
+mod policy;
+mod secret;
+mod store;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "typed")]
+pub mod typed;
+
+pub use attributes::{Attributes, MAX_CUSTOM_LEN};
+pub use error::{Error, ErrorKind};
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use guarded::GuardedBytes;
+pub use lock::{lock_item, lock_items, ItemGuard};
+pub use naming::NameMode;
+pub use policy::{AccessPolicy, Accessibility, Combinator, Constraint};
+pub use secret::{SecretBytes, SecretString};
+pub use store::{digest, AccountInfo, SecureStore, Transaction, Write};
 
 #[cfg(target_os = "ios")]
 pub use ffi_support;
+#[cfg(target_os = "android")]
+pub use jni;
+#[doc(hidden)]
+pub use secure_native_macros::__export_items;
+pub use secure_native_macros::export;
+#[cfg(feature = "typed")]
+pub use typed::Json;
 
 #[macro_export]
 macro_rules! define_cresult_destructor {
@@ -23,255 +62,332 @@ macro_rules! define_cresult_destructor {
 }
 
 /// Trait for converting Rust types into FFI return values
+///
+/// A failed conversion is reported by the export like any other error: as a
+/// Java exception on Android and through the `CResult` on iOS.
 pub trait Return<'a>: Sized {
     type Ext;
     type Env;
-    fn convert(env: &Self::Env, val: Self) -> Self::Ext;
-    fn convert_without_exception(env: &Self::Env, val: Self) -> Self::Ext {
-        Return::convert(env, val)
-    }
-    #[cfg(target_os = "ios")]
-    fn convert_cresult(env: &Self::Env, val: Self) -> *mut ios::CResult<Self::Ext> {
-        panic!()
-    }
+    fn convert(env: &Self::Env, val: Self) -> Result<Self::Ext, Error>;
 }
 
 /// Trait for converting FFI arguments into Rust types
-pub trait Argument<'a> {
+pub trait Argument<'a>: Sized {
     type Ext;
     type Env;
-    fn convert(env: &Self::Env, val: Self::Ext) -> Self;
+    fn convert(env: &Self::Env, val: Self::Ext) -> Result<Self, Error>;
 }
 
-#[macro_export]
-macro_rules! export_put {
-    ($( @$jname:ident fn $name:ident($s:ident : Result<(), String>$(, $a:ident : $t:ty )*) -> $ret:ty $code:block )*) => {
-        $(
-            pub fn $name($s: Result<(), String>, $( $a: $t ),*) -> $ret $code
-        )*
-
-        #[cfg(target_os = "android")]
-        pub mod android_put {
-            use $crate::android::*;
-            use $crate::{Return, Argument};
-
-            use jni::JNIEnv;
-            use jni::objects::{JClass, JObject, JString};
-            use jni::sys::jboolean;
-
-            $(
-                #[no_mangle]
-                pub extern fn $jname<'jni>(env: JNIEnv<'jni>, _: JClass, activity: JObject, app: JString, key: JString, value: JString, with_biometry: jboolean, $( $a: <$t as Argument<'jni>>::Ext ),*) -> <$ret as Return<'jni>>::Ext {
-                    let success = $crate::android::put(&env, activity, app, key, value, with_biometry);
-                    let ret = super::$name(success, $( Argument::convert(&env, $a) ),*);
-                    Return::convert(&env, ret)
-                }
-            )*
+/// `None` fails with `ErrorKind::Other`.
+impl<'a, Inner: Return<'a>> Return<'a> for Option<Inner> {
+    type Ext = Inner::Ext;
+    type Env = Inner::Env;
+    fn convert(env: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        match val {
+            Some(inner) => Return::convert(env, inner),
+            None => Err(Error::new(ErrorKind::Other, "No value to return")),
         }
+    }
+}
 
-        #[cfg(target_os = "ios")]
-        pub mod ios_put {
-            use $crate::ios::*;
-            use $crate::{Return, Argument};
-
-            use std::cell::Cell;
-            use libc::{c_uint, c_uchar};
-            use $crate::ffi_support::FfiStr;
-
-            $(
-                #[no_mangle]
-                pub extern fn $name(err: *mut c_uint, app: FfiStr, key: FfiStr, value: FfiStr, with_biometry: c_uchar, $( $a: <$t as Argument<'static>>::Ext ),*) -> *mut CResult<<$ret as Return<'static>>::Ext> {
-                    let error = Cell::new(0);
-                    let success = $crate::ios::put(app.as_str(), key.as_str(), value.as_str(), with_biometry != 0);
-                    let ret = super::$name(success, $(Argument::convert(&error, $a)),*);
-                    let ret = Return::convert_cresult(&error, ret);
-                    unsafe { *err |= error.get() as c_uint };
-                    ret
-                }
-            )*
-        }
+impl<'a, Inner: Return<'a>> Return<'a> for Result<Inner, Error> {
+    type Ext = Inner::Ext;
+    type Env = Inner::Env;
+    fn convert(env: &Self::Env, val: Self) -> Result<Self::Ext, Error> {
+        val.and_then(|inner| Return::convert(env, inner))
     }
 }
 
+// The `export_*!` macros export functions like `#[export]`, with the JNI
+// symbol of each spelt out after `@`:
+//
+// export_get! {
+//     @Java_io_parity_Seeds_getSeed
+//     fn get_seed(seed: Result<SecretString, Error>) -> Result<SecretString, Error> { seed }
+// }
+
+#[macro_export]
+macro_rules! export_put {
+    ($($items:tt)*) => {
+        $crate::__export_items! { put $($items)* }
+    };
+}
+
 #[macro_export]
 macro_rules! export_get {
-    ($( @$jname:ident fn $name:ident($s:ident : Result<String, String>$(, $a:ident : $t:ty )*) -> $ret:ty $code:block )*) => {
-        $(
-            pub fn $name($s: Result<String, String>, $( $a: $t ),*) -> $ret $code
-        )*
-
-        #[cfg(target_os = "android")]
-        pub mod android_get {
-            use $crate::android::*;
-            use $crate::{Return, Argument};
-
-            use jni::JNIEnv;
-            use jni::objects::{JClass, JObject, JString};
-
-            $(
-                #[no_mangle]
-                pub extern fn $jname<'jni>(env: JNIEnv<'jni>, _: JClass, activity: JObject, app: JString, key: JString, $( $a: <$t as Argument<'jni>>::Ext ),*) -> <$ret as Return<'jni>>::Ext {
-                    let res = $crate::android::get(&env, activity, app, key);
-                    let ret = super::$name(res, $( Argument::convert(&env, $a) ),*);
-                    Return::convert(&env, ret)
-                }
-            )*
-        }
+    ($($items:tt)*) => {
+        $crate::__export_items! { get $($items)* }
+    };
+}
 
-        #[cfg(target_os = "ios")]
-        pub mod ios_get {
-            use $crate::ios::*;
-            use $crate::{Return, Argument};
-
-            use std::cell::Cell;
-            use libc::c_uint;
-            use $crate::ffi_support::FfiStr;
-
-            $(
-                #[no_mangle]
-                pub extern fn $name(err: *mut c_uint, app: FfiStr, key: FfiStr, $( $a: <$t as Argument<'static>>::Ext ),*) -> *mut CResult<<$ret as Return<'static>>::Ext> {
-                    let error = Cell::new(0);
-                    let res = $crate::ios::get(app.as_str(), key.as_str());
-                    let ret = super::$name(res, $(Argument::convert(&error, $a)),*);
-                    let ret = Return::convert_cresult(&error, ret);
-                    unsafe { *err |= error.get() as c_uint };
-                    ret
-                }
-            )*
-        }
-    }
+#[macro_export]
+macro_rules! export_put_bytes {
+    ($($items:tt)*) => {
+        $crate::__export_items! { put_bytes $($items)* }
+    };
 }
 
 #[macro_export]
-macro_rules! export_contains {
-    ($( @$jname:ident fn $name:ident($s:ident : Result<bool, String>$(, $a:ident : $t:ty )*) -> $ret:ty $code:block )*) => {
-        $(
-            pub fn $name($s: Result<bool, String>, $( $a: $t ),*) -> $ret $code
-        )*
-
-        #[cfg(target_os = "android")]
-        pub mod android_contains {
-            use $crate::android::*;
-            use $crate::{Return, Argument};
-
-            use jni::JNIEnv;
-            use jni::objects::{JClass, JObject, JString};
-
-            $(
-                #[no_mangle]
-                pub extern fn $jname<'jni>(env: JNIEnv<'jni>, _: JClass, activity: JObject, app: JString, key: JString, $( $a: <$t as Argument<'jni>>::Ext ),*) -> <$ret as Return<'jni>>::Ext {
-                    let res = $crate::android::contains(&env, activity, app, key);
-                    let ret = super::$name(res, $( Argument::convert(&env, $a) ),*);
-                    Return::convert(&env, ret)
-                }
-            )*
-        }
+macro_rules! export_get_bytes {
+    ($($items:tt)*) => {
+        $crate::__export_items! { get_bytes $($items)* }
+    };
+}
 
-        #[cfg(target_os = "ios")]
-        pub mod ios_contains {
-            use $crate::ios::*;
-            use $crate::{Return, Argument};
-
-            use std::cell::Cell;
-            use libc::c_uint;
-            use $crate::ffi_support::FfiStr;
-
-            $(
-                #[no_mangle]
-                pub extern fn $name(err: *mut c_uint, app: FfiStr, key: FfiStr, $( $a: <$t as Argument<'static>>::Ext ),*) -> *mut CResult<<$ret as Return<'static>>::Ext> {
-                    let error = Cell::new(0);
-                    let res = $crate::ios::contains(app.as_str(), key.as_str());
-                    let ret = super::$name(res, $(Argument::convert(&error, $a)),*);
-                    let ret = Return::convert_cresult(&error, ret);
-                    unsafe { *err |= error.get() as c_uint };
-                    ret
-                }
-            )*
-        }
-    }
+#[macro_export]
+macro_rules! export_contains {
+    ($($items:tt)*) => {
+        $crate::__export_items! { contains $($items)* }
+    };
 }
 
 #[macro_export]
 macro_rules! export_delete {
-    ($( @$jname:ident fn $name:ident($s:ident : Result<(), String>$(, $a:ident : $t:ty )*) -> $ret:ty $code:block )*) => {
-        $(
-            pub fn $name($s: Result<(), String>, $( $a: $t ),*) -> $ret $code
-        )*
-
-        #[cfg(target_os = "android")]
-        pub mod android_delete {
-            use $crate::android::*;
-            use $crate::{Return, Argument};
-
-            use jni::JNIEnv;
-            use jni::objects::{JClass, JObject, JString};
-
-            $(
-                #[no_mangle]
-                pub extern fn $jname<'jni>(env: JNIEnv<'jni>, _: JClass, activity: JObject, app: JString, key: JString, $( $a: <$t as Argument<'jni>>::Ext ),*) -> <$ret as Return<'jni>>::Ext {
-                    let res = $crate::android::delete(&env, activity, app, key);
-                    let ret = super::$name(res, $( Argument::convert(&env, $a) ),*);
-                    Return::convert(&env, ret)
-                }
-            )*
-        }
+    ($($items:tt)*) => {
+        $crate::__export_items! { delete $($items)* }
+    };
+}
 
-        #[cfg(target_os = "ios")]
-        pub mod ios_delete {
-            use $crate::ios::*;
-            use $crate::{Return, Argument};
-
-            use std::cell::Cell;
-            use libc::c_uint;
-            use $crate::ffi_support::FfiStr;
-
-            $(
-                #[no_mangle]
-                pub extern fn $name(err: *mut c_uint, app: FfiStr, key: FfiStr, $( $a: <$t as Argument<'static>>::Ext ),*) -> *mut CResult<<$ret as Return<'static>>::Ext> {
-                    let error = Cell::new(0);
-                    let res = $crate::ios::delete(app.as_str(), key.as_str());
-                    let ret = super::$name(res, $(Argument::convert(&error, $a)),*);
-                    let ret = Return::convert_cresult(&error, ret);
-                    unsafe { *err |= error.get() as c_uint };
-                    ret
-                }
-            )*
-        }
-    }
+#[macro_export]
+macro_rules! export_list {
+    ($($items:tt)*) => {
+        $crate::__export_items! { list $($items)* }
+    };
+}
+
+#[macro_export]
+macro_rules! export_delete_all {
+    ($($items:tt)*) => {
+        $crate::__export_items! { delete_all $($items)* }
+    };
+}
+
+#[macro_export]
+macro_rules! export_wipe {
+    ($($items:tt)*) => {
+        $crate::__export_items! { wipe $($items)* }
+    };
+}
+
+#[macro_export]
+macro_rules! export_put_if_absent {
+    ($($items:tt)*) => {
+        $crate::__export_items! { put_if_absent $($items)* }
+    };
+}
+
+#[macro_export]
+macro_rules! export_replace_if_equals {
+    ($($items:tt)*) => {
+        $crate::__export_items! { replace_if_equals $($items)* }
+    };
+}
+
+#[macro_export]
+macro_rules! export_delete_if_equals {
+    ($($items:tt)*) => {
+        $crate::__export_items! { delete_if_equals $($items)* }
+    };
+}
+
+#[macro_export]
+macro_rules! export_get_attributes {
+    ($($items:tt)*) => {
+        $crate::__export_items! { get_attributes $($items)* }
+    };
 }
 
 #[cfg(test)]
 mod tests {
     //    trace_macros!(true);
+    use crate::{AccountInfo, Attributes, Error, SecretBytes, SecretString};
 
     export_put! {
         @Java_io_parity_secure_native_test_put
-        fn test_put(success: Result<(), String>, other: u32) -> Result<(), String> {
+        fn test_put(success: Result<(), Error>, _other: u32) -> Result<(), Error> {
             success
         }
     }
 
     export_get! {
         @Java_io_parity_secure_native_test_get
-        fn test_get(seed: Result<String, String>, other: u32) -> Result<String, String> {
+        fn test_get(seed: Result<SecretString, Error>, _other: u32) -> Result<SecretString, Error> {
+            seed
+        }
+    }
+
+    export_put_bytes! {
+        @Java_io_parity_secure_native_test_put_bytes
+        fn test_put_bytes(success: Result<(), Error>, _other: u32) -> Result<(), Error> {
+            success
+        }
+    }
+
+    export_get_bytes! {
+        @Java_io_parity_secure_native_test_get_bytes
+        fn test_get_bytes(seed: Result<SecretBytes, Error>, _other: u32) -> Result<SecretBytes, Error> {
             seed
         }
     }
 
     export_contains! {
         @Java_io_parity_secure_native_test_contains
-        fn test_contains(contained: Result<bool, String>, other: u32) -> Result<bool, String> {
+        fn test_contains(contained: Result<bool, Error>, _other: u32) -> Result<bool, Error> {
             contained
         }
     }
 
     export_delete! {
         @Java_io_parity_secure_native_test_delete
-        fn test_delete(success: Result<(), String>, other: u32) -> Result<(), String> {
+        fn test_delete(success: Result<(), Error>, _other: u32) -> Result<(), Error> {
             success
         }
     }
 
+    export_list! {
+        @Java_io_parity_secure_native_test_list
+        fn test_list(accounts: Result<Vec<AccountInfo>, Error>, _other: u32) -> Result<Vec<String>, Error> {
+            accounts.map(|accounts| accounts.into_iter().map(|info| info.account).collect())
+        }
+    }
+
+    export_delete_all! {
+        @Java_io_parity_secure_native_test_delete_all
+        fn test_delete_all(remaining: Result<Vec<AccountInfo>, Error>, _other: u32) -> Result<Vec<String>, Error> {
+            remaining.map(|remaining| remaining.into_iter().map(|info| info.account).collect())
+        }
+    }
+
+    export_wipe! {
+        @Java_io_parity_secure_native_test_wipe
+        fn test_wipe(remaining: Result<Vec<AccountInfo>, Error>, _other: u32) -> Result<Vec<String>, Error> {
+            remaining.map(|remaining| remaining.into_iter().map(|info| info.account).collect())
+        }
+    }
+
+    export_put_if_absent! {
+        @Java_io_parity_secure_native_test_put_if_absent
+        fn test_put_if_absent(stored: Result<bool, Error>, _other: u32) -> Result<bool, Error> {
+            stored
+        }
+    }
+
+    export_replace_if_equals! {
+        @Java_io_parity_secure_native_test_replace_if_equals
+        fn test_replace_if_equals(replaced: Result<bool, Error>, _other: u32) -> Result<bool, Error> {
+            replaced
+        }
+    }
+
+    export_get_attributes! {
+        @Java_io_parity_secure_native_test_get_attributes
+        fn test_get_attributes(attributes: Result<Attributes, Error>, _other: u32) -> Result<String, Error> {
+            attributes.map(|attributes| attributes.label.unwrap_or_default())
+        }
+    }
+
+    export_delete_if_equals! {
+        @Java_io_parity_secure_native_test_delete_if_equals
+        fn test_delete_if_equals(deleted: Result<bool, Error>, _other: u32) -> Result<bool, Error> {
+            deleted
+        }
+    }
+
+    #[secure_native::export(op = "get", java = "io.parity.securenative.Test")]
+    fn test_export_get(
+        seed: Result<SecretString, Error>,
+        _other: u32,
+    ) -> Result<SecretString, Error> {
+        seed
+    }
+
+    #[secure_native::export(op = "put", java = "io.parity.securenative.Test", method = "putSeed")]
+    fn test_export_put(success: Result<(), Error>) -> Result<(), Error> {
+        success
+    }
+
+    #[secure_native::export(op = "replace_if_equals", java = "io.parity.securenative.Test")]
+    fn test_export_replace_if_equals(
+        replaced: Result<bool, Error>,
+        _other: u32,
+        _flag: bool,
+    ) -> Result<bool, Error> {
+        replaced
+    }
+
+    #[secure_native::export(op = "wipe", java = "io.parity.securenative.Test")]
+    fn test_export_wipe(remaining: Result<Vec<AccountInfo>, Error>) -> Result<Vec<String>, Error> {
+        remaining.map(|remaining| remaining.into_iter().map(|info| info.account).collect())
+    }
+
+    #[cfg(feature = "typed")]
+    #[secure_native::export(
+        op = "put_typed",
+        java = "io.parity.securenative.Test",
+        value = "Vec<u32>"
+    )]
+    fn test_export_put_typed(stored: Result<(), Error>) -> Result<(), Error> {
+        stored
+    }
+
+    #[cfg(feature = "typed")]
+    #[secure_native::export(op = "get_typed", java = "io.parity.securenative.Test")]
+    fn test_export_get_typed(
+        value: Result<Vec<u32>, Error>,
+    ) -> Result<crate::Json<Vec<u32>>, Error> {
+        value.map(crate::Json)
+    }
+
+    #[cfg(feature = "typed")]
+    #[secure_native::export(op = "get_record", java = "io.parity.securenative.Test")]
+    fn test_export_get_record(
+        record: Result<crate::typed::Record, Error>,
+    ) -> Result<String, Error> {
+        record.map(|record| record.schema().to_string())
+    }
+
     #[test]
-    fn test_exports() {}
+    #[allow(clippy::type_complexity)]
+    fn test_exports() {
+        // The host entry points take the operation's inputs first
+        let _: fn(&str, &str, u32) -> Result<SecretString, Error> =
+            test_export_get::test_export_get;
+        let _: fn(&str, &str, &str, &crate::AccessPolicy) -> Result<(), Error> =
+            test_export_put::test_export_put;
+        let _: fn(
+            &str,
+            &str,
+            &[u8],
+            &[u8],
+            &crate::AccessPolicy,
+            u32,
+            bool,
+        ) -> Result<bool, Error> = test_export_replace_if_equals::test_export_replace_if_equals;
+        let _: fn() -> Result<Vec<String>, Error> = test_export_wipe::test_export_wipe;
+        // The `export_*!` macros go through the same generator
+        let _: fn(&str, &str, &str, &crate::AccessPolicy, u32) -> Result<(), Error> =
+            test_put::test_put;
+    }
+
+    #[cfg(feature = "typed")]
+    #[test]
+    #[allow(clippy::type_complexity)]
+    fn test_typed_exports() {
+        let _: fn(&str, &str, &Vec<u32>, u32, &crate::AccessPolicy) -> Result<(), Error> =
+            test_export_put_typed::test_export_put_typed;
+        let _: fn(&str, &str, u32) -> Result<crate::Json<Vec<u32>>, Error> =
+            test_export_get_typed::test_export_get_typed;
+        let _: fn(&str, &str) -> Result<String, Error> =
+            test_export_get_record::test_export_get_record;
+    }
+
+    #[test]
+    fn test_panics_become_errors() {
+        let panic = std::panic::catch_unwind(|| panic!("boom {}", 1)).unwrap_err();
+        let error = Error::from_panic(panic);
+        assert_eq!(error.kind(), &crate::ErrorKind::Other);
+        assert_eq!(error.description(), "Panicked: boom 1");
+        let panic = std::panic::catch_unwind(|| panic!("boom")).unwrap_err();
+        assert_eq!(Error::from_panic(panic).description(), "Panicked: boom");
+    }
 }
@@ -0,0 +1,264 @@
+//! On-disk format of the host store.
+//!
+//! The file is `MAGIC | version | nonce | AES-256-GCM(records)`, where the
+//! header bytes are authenticated as associated data. Records are a count
+//! followed by length-prefixed `service`, `account`, `value` and, since
+//! version 2, `attributes` fields, the last holding an attribute record (see
+//! `attributes`).
+//!
+//! Writers hold `flock(2)` on a `.lock` file next to the store, and replace
+//! the store by renaming a temporary file over it, so readers never need a
+//! lock.
+
+use crate::attributes;
+use crate::{Attributes, Error, ErrorKind, SecretBytes};
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    process,
+};
+
+const MAGIC: &[u8; 4] = b"SNLX";
+const VERSION: u8 = 2;
+/// Last version without attributes, still read
+const VERSION_WITHOUT_ATTRIBUTES: u8 = 1;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1;
+
+pub struct Item {
+    pub value: SecretBytes,
+    pub attributes: Attributes,
+}
+
+pub type Items = BTreeMap<(String, String), Item>;
+
+/// Exclusive lock on a store, released when dropped.
+pub struct Lock(File);
+
+/// Lock the store at `path` against writers in every process, blocking
+/// while another holds it.
+pub fn lock(path: &Path) -> Result<Lock, Error> {
+    create_parent(path)?;
+    let lock_path = sibling(path, ".lock");
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(&lock_path)
+        .map_err(|e| context(e, &format!("Opening {}", lock_path.display())))?;
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(Lock(file));
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(context(error, &format!("Locking {}", lock_path.display())));
+        }
+    }
+}
+
+/// Remove the store at `path` without reading it.
+pub fn remove(path: &Path) -> Result<(), Error> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result.map_err(|e| context(e, &format!("Removing {}", path.display()))),
+    }
+}
+
+/// Read and decrypt the store at `path`. A missing file is an empty store.
+pub fn load(path: &Path, key: &[u8; 32]) -> Result<Items, Error> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Items::new()),
+        Err(e) => return Err(context(e, &format!("Reading {}", path.display()))),
+    };
+    if bytes.len() < HEADER_LEN + NONCE_LEN || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(invalid(&format!(
+            "{} is not a secure-native store",
+            path.display()
+        )));
+    }
+    let version = bytes[MAGIC.len()];
+    if version != VERSION && version != VERSION_WITHOUT_ATTRIBUTES {
+        return Err(invalid(&format!("Unsupported store version {}", version)));
+    }
+    let (header, rest) = bytes.split_at(HEADER_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let plaintext: SecretBytes = cipher(key)
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::KeyInvalidated,
+                &format!("Decrypting {} failed - wrong master key?", path.display()),
+            )
+        })?
+        .into();
+    decode(&plaintext, version)
+}
+
+/// Encrypt `items` under a fresh nonce and atomically replace the store at `path`.
+pub fn save(path: &Path, key: &[u8; 32], items: &Items) -> Result<(), Error> {
+    let mut nonce = [0u8; NONCE_LEN];
+    super::random_bytes(&mut nonce)?;
+    let mut bytes = Vec::with_capacity(HEADER_LEN);
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(VERSION);
+    let ciphertext = cipher(key)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &encode(items),
+                aad: &bytes,
+            },
+        )
+        .map_err(|_| Error::new(ErrorKind::Other, "Encrypting store failed"))?;
+    bytes.extend_from_slice(&nonce);
+    bytes.extend_from_slice(&ciphertext);
+
+    create_parent(path)?;
+    // A name of our own, so a writer that doesn't hold the lock can't
+    // interleave with this one
+    let mut suffix = [0u8; 8];
+    super::random_bytes(&mut suffix)?;
+    let tmp = sibling(
+        path,
+        &format!(".{}.{:016x}.tmp", process::id(), u64::from_le_bytes(suffix)),
+    );
+    let write = || -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    };
+    write().map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        context(e, &format!("Writing {}", path.display()))
+    })
+}
+
+fn create_parent(path: &Path) -> Result<(), Error> {
+    match path.parent() {
+        Some(dir) => {
+            fs::create_dir_all(dir).map_err(|e| context(e, &format!("Creating {}", dir.display())))
+        }
+        None => Ok(()),
+    }
+}
+
+/// `path` with `suffix` appended to its file name.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn cipher(key: &[u8; 32]) -> Aes256Gcm {
+    Aes256Gcm::new(key.into())
+}
+
+/// Serialize `items`, sizing the buffer up front so no copy of the values is
+/// left behind by growing it.
+fn encode(items: &Items) -> SecretBytes {
+    let records: Vec<_> = items
+        .iter()
+        .map(|((service, account), item)| {
+            let attributes = attributes::encode(&item.attributes);
+            (service, account, &item.value, attributes)
+        })
+        .collect();
+    let len = records
+        .iter()
+        .fold(4, |len, (service, account, value, attributes)| {
+            len + 16 + service.len() + account.len() + value.len() + attributes.len()
+        });
+    let mut out = Vec::with_capacity(len);
+    out.extend_from_slice(&(items.len() as u32).to_le_bytes());
+    for (service, account, value, attributes) in &records {
+        for field in &[
+            service.as_bytes(),
+            account.as_bytes(),
+            value.as_bytes(),
+            attributes.as_slice(),
+        ] {
+            out.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            out.extend_from_slice(field);
+        }
+    }
+    SecretBytes::from(out)
+}
+
+fn decode(mut bytes: &[u8], version: u8) -> Result<Items, Error> {
+    let mut items = Items::new();
+    let count = read_u32(&mut bytes)?;
+    for _ in 0..count {
+        let service = read_string(&mut bytes)?;
+        let account = read_string(&mut bytes)?;
+        let value = SecretBytes::from(read_field(&mut bytes)?);
+        let attributes = if version == VERSION_WITHOUT_ATTRIBUTES {
+            Attributes::default()
+        } else {
+            attributes::decode(read_field(&mut bytes)?)?
+        };
+        items.insert((service, account), Item { value, attributes });
+    }
+    if bytes.is_empty() {
+        Ok(items)
+    } else {
+        Err(invalid("Trailing data in store"))
+    }
+}
+
+fn read_u32(bytes: &mut &[u8]) -> Result<u32, Error> {
+    if bytes.len() < 4 {
+        return Err(invalid("Truncated store"));
+    }
+    let (head, rest) = bytes.split_at(4);
+    *bytes = rest;
+    Ok(u32::from_le_bytes(head.try_into().unwrap()))
+}
+
+fn read_field<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+    let len = read_u32(bytes)? as usize;
+    if bytes.len() < len {
+        return Err(invalid("Truncated store"));
+    }
+    let (field, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(field)
+}
+
+fn read_string(bytes: &mut &[u8]) -> Result<String, Error> {
+    String::from_utf8(read_field(bytes)?.to_vec()).map_err(|e| invalid(&e))
+}
+
+fn invalid<D: ToString + ?Sized>(description: &D) -> Error {
+    Error::new(ErrorKind::InvalidData, description)
+}
+
+fn context(error: io::Error, context: &str) -> Error {
+    Error::new(
+        ErrorKind::Io { kind: error.kind() },
+        &format!("{}: {}", context, error),
+    )
+}
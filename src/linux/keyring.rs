@@ -0,0 +1,156 @@
+//! Access to the Linux kernel key retention service.
+//!
+//! See `keyctl(2)` and `add_key(2)`. The syscalls are made directly so no
+//! `libkeyutils` is needed at link time.
+//!
+//! Keyrings live in kernel memory only, so a master key never outlives the
+//! machine's uptime. New keys go to the user's persistent keyring, which the
+//! kernel drops once it has gone unused for
+//! `/proc/sys/kernel/keys/persistent_keyring_expiry` seconds (three days by
+//! default). Kernels without persistent keyrings get the user keyring
+//! instead, which is dropped when the user's last session ends. Once its key
+//! is gone a store can't be decrypted, and can only be wiped.
+
+use crate::{Error, ErrorKind};
+
+use libc::{c_long, syscall, SYS_add_key, SYS_keyctl};
+use std::{ffi::CString, io};
+use zeroize::Zeroizing;
+
+const KEY_SPEC_USER_KEYRING: c_long = -4;
+const KEYCTL_SEARCH: c_long = 10;
+const KEYCTL_READ: c_long = 11;
+const KEYCTL_GET_PERSISTENT: c_long = 22;
+
+const KEY_TYPE_USER: &[u8] = b"user\0";
+
+pub const MASTER_KEY_LEN: usize = 32;
+
+/// A master key, wiped when dropped.
+pub type Key = Zeroizing<[u8; MASTER_KEY_LEN]>;
+
+/// Fetch the master key stored under `description`, or `None` if there is
+/// none.
+pub fn find(description: &str) -> Result<Option<Key>, Error> {
+    // Links the persistent keyring into the user keyring, so it's searched
+    // below, and restarts its expiry timer
+    keyring();
+    match search(&c_description(description)?) {
+        Ok(serial) => read(serial).map(Some),
+        Err(ref e) if is_missing(e) => Ok(None),
+        Err(e) => Err(context(e, "Searching user keyring")),
+    }
+}
+
+/// Generate and store a fresh random master key under `description`,
+/// replacing any key stored under it before.
+pub fn create(description: &str) -> Result<Key, Error> {
+    let description = c_description(description)?;
+    let mut key = Key::default();
+    super::random_bytes(&mut *key)?;
+    let serial = unsafe {
+        syscall(
+            SYS_add_key,
+            KEY_TYPE_USER.as_ptr(),
+            description.as_ptr(),
+            key.as_ptr(),
+            key.len(),
+            keyring(),
+        )
+    };
+    if serial < 0 {
+        Err(context(io::Error::last_os_error(), "Storing master key"))
+    } else {
+        read(serial)
+    }
+}
+
+/// The keyring new keys are stored in.
+fn keyring() -> c_long {
+    let serial = unsafe {
+        syscall(
+            SYS_keyctl,
+            KEYCTL_GET_PERSISTENT,
+            -1 as c_long,
+            KEY_SPEC_USER_KEYRING,
+        )
+    };
+    if serial < 0 {
+        KEY_SPEC_USER_KEYRING
+    } else {
+        serial
+    }
+}
+
+fn c_description(description: &str) -> Result<CString, Error> {
+    CString::new(description).map_err(|_| {
+        Error::new(
+            ErrorKind::Io {
+                kind: io::ErrorKind::InvalidInput,
+            },
+            &format!("Invalid key description: {}", description),
+        )
+    })
+}
+
+fn is_missing(error: &io::Error) -> bool {
+    match error.raw_os_error() {
+        Some(code) => {
+            code == libc::ENOKEY || code == libc::EKEYEXPIRED || code == libc::EKEYREVOKED
+        }
+        None => false,
+    }
+}
+
+fn search(description: &CString) -> io::Result<c_long> {
+    let serial = unsafe {
+        syscall(
+            SYS_keyctl,
+            KEYCTL_SEARCH,
+            KEY_SPEC_USER_KEYRING,
+            KEY_TYPE_USER.as_ptr(),
+            description.as_ptr(),
+            0 as c_long,
+        )
+    };
+    if serial < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(serial)
+    }
+}
+
+fn read(serial: c_long) -> Result<Key, Error> {
+    let mut key = Key::default();
+    let len = unsafe { syscall(SYS_keyctl, KEYCTL_READ, serial, key.as_mut_ptr(), key.len()) };
+    if len < 0 {
+        Err(context(io::Error::last_os_error(), "Reading master key"))
+    } else if len as usize != MASTER_KEY_LEN {
+        Err(Error::new(
+            ErrorKind::KeyInvalidated,
+            &format!("Master key has unexpected length {}", len),
+        ))
+    } else {
+        Ok(key)
+    }
+}
+
+fn context(error: io::Error, context: &str) -> Error {
+    Error::new(
+        ErrorKind::Io { kind: error.kind() },
+        &format!("{}: {}", context, error),
+    )
+}
+
+/// Invalidate the key stored under `description`, as its keyring expiring
+/// would.
+#[cfg(test)]
+pub fn invalidate(description: &str) -> io::Result<()> {
+    const KEYCTL_INVALIDATE: c_long = 21;
+    let serial = search(&CString::new(description).unwrap())?;
+    if unsafe { syscall(SYS_keyctl, KEYCTL_INVALIDATE, serial) } < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
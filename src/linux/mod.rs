@@ -0,0 +1,463 @@
+//! Host backend for Linux.
+//!
+//! Secrets live in a single AES-GCM encrypted file under `$XDG_DATA_HOME`,
+//! encrypted with a master key held in the kernel user keyring. There is no
+//! user-presence check on the host, so the `AccessPolicy` is accepted and ignored.
+//!
+//! Writes are serialized per file across every `FileStore` of the process,
+//! and across processes, so stores may be opened freely. A store whose master
+//! key has left the keyring (see `keyring`) fails with `KeyInvalidated` until
+//! it is wiped.
+
+mod file;
+mod keyring;
+
+use crate::attributes;
+use crate::lock::Locks;
+use crate::{
+    lock_item, lock_items, AccessPolicy, AccountInfo, Attributes, Error, ErrorKind, ItemGuard,
+    SecretBytes, SecureStore, Write,
+};
+
+use std::{env, path::PathBuf, time::SystemTime};
+use zeroize::Zeroize;
+
+const DEFAULT_KEY_DESCRIPTION: &str = "secure-native:master";
+
+/// Source of the key protecting the store file.
+pub enum MasterKey {
+    /// A `user` key with this description in the user keyring, created
+    /// along with the store file.
+    Keyring(String),
+    /// A fixed key, for tests and environments without a keyring. Wiped
+    /// when dropped.
+    Fixed([u8; keyring::MASTER_KEY_LEN]),
+}
+
+impl Drop for MasterKey {
+    fn drop(&mut self) {
+        if let MasterKey::Fixed(key) = self {
+            key.zeroize();
+        }
+    }
+}
+
+/// Configuration for `FileStore`.
+pub struct Config {
+    /// Location of the encrypted store file.
+    pub path: PathBuf,
+    /// Key used to encrypt the file.
+    pub key: MasterKey,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let data_home = env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .unwrap_or_else(|| {
+                let home = env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+                home.join(".local").join("share")
+            });
+        Config {
+            path: data_home.join("secure-native").join("secrets"),
+            key: MasterKey::Keyring(DEFAULT_KEY_DESCRIPTION.to_string()),
+        }
+    }
+}
+
+fn random_bytes(buf: &mut [u8]) -> Result<(), Error> {
+    getrandom::getrandom(buf).map_err(|e| {
+        Error::new(
+            ErrorKind::Platform {
+                code: i64::from(e.code().get()),
+            },
+            &e,
+        )
+    })
+}
+
+/// Store `value` for the pair, keeping the attributes of a replaced item.
+fn insert(items: &mut file::Items, service: &str, account: &str, value: &[u8]) {
+    let key = (service.to_string(), account.to_string());
+    let attributes = items
+        .remove(&key)
+        .map(|item| item.attributes)
+        .unwrap_or_default()
+        .touched(SystemTime::now());
+    items.insert(
+        key,
+        file::Item {
+            value: SecretBytes::from(value),
+            attributes,
+        },
+    );
+}
+
+fn not_found(account: &str) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        &format!("Couldn't find value for key: {}", account),
+    )
+}
+
+/// Store files written by this process, locked by path
+static FILES: Locks = Locks::new();
+
+/// `SecureStore` backed by an encrypted file.
+pub struct FileStore {
+    config: Config,
+}
+
+impl FileStore {
+    /// The master key, generated first if `create` is set and there is none.
+    fn key(&self, create: bool) -> Result<keyring::Key, Error> {
+        let description = match self.config.key {
+            MasterKey::Keyring(ref description) => description,
+            MasterKey::Fixed(key) => return Ok(keyring::Key::new(key)),
+        };
+        match keyring::find(description)? {
+            Some(key) => Ok(key),
+            None if create => keyring::create(description),
+            None => Err(Error::new(
+                ErrorKind::KeyInvalidated,
+                &format!(
+                    "Master key {} is no longer in the keyring, so {} can't be decrypted; \
+                     wipe the store to start over",
+                    description,
+                    self.config.path.display()
+                ),
+            )),
+        }
+    }
+
+    /// Lock the file against writers in this and other processes.
+    fn lock(&self) -> Result<(ItemGuard<'static>, file::Lock), Error> {
+        let guard = FILES.lock(&self.config.path.to_string_lossy(), "");
+        Ok((guard, file::lock(&self.config.path)?))
+    }
+
+    /// Load the items, apply `f` and write them back if it succeeded.
+    fn update<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut file::Items) -> Result<T, Error>,
+    {
+        let _lock = self.lock()?;
+        // Only a new file gets a new key, as replacing the key of an
+        // existing one would lose its items
+        let key = self.key(!self.config.path.exists())?;
+        let mut items = file::load(&self.config.path, &key)?;
+        let ret = f(&mut items)?;
+        file::save(&self.config.path, &key, &items)?;
+        Ok(ret)
+    }
+
+    fn read(&self) -> Result<file::Items, Error> {
+        if !self.config.path.exists() {
+            return Ok(file::Items::new());
+        }
+        file::load(&self.config.path, &*self.key(false)?)
+    }
+}
+
+impl SecureStore for FileStore {
+    type Config = Config;
+
+    fn open(config: Self::Config) -> Self {
+        FileStore { config }
+    }
+
+    fn put_bytes(
+        &self,
+        service: &str,
+        account: &str,
+        value: &[u8],
+        _policy: &AccessPolicy,
+    ) -> Result<(), Error> {
+        let _guard = lock_item(service, account);
+        self.update(|items| {
+            insert(items, service, account, value);
+            Ok(())
+        })
+    }
+
+    fn get_bytes(&self, service: &str, account: &str) -> Result<SecretBytes, Error> {
+        self.read()?
+            .remove(&(service.to_string(), account.to_string()))
+            .map(|item| item.value)
+            .ok_or_else(|| not_found(account))
+    }
+
+    fn contains(&self, service: &str, account: &str) -> Result<bool, Error> {
+        Ok(self
+            .read()?
+            .contains_key(&(service.to_string(), account.to_string())))
+    }
+
+    fn delete(&self, service: &str, account: &str) -> Result<(), Error> {
+        let _guard = lock_item(service, account);
+        self.update(|items| {
+            items.remove(&(service.to_string(), account.to_string()));
+            Ok(())
+        })
+    }
+
+    /// The file is replaced in one step, so every write lands at once.
+    fn apply(&self, writes: &[Write]) -> Result<(), Error> {
+        let _guards = lock_items(writes.iter().map(|w| (w.service(), w.account())));
+        self.update(|items| {
+            for write in writes {
+                match write {
+                    Write::Put {
+                        service,
+                        account,
+                        value,
+                        ..
+                    } => insert(items, service, account, value),
+                    Write::Delete { service, account } => {
+                        items.remove(&(service.clone(), account.clone()));
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn get_attributes(&self, service: &str, account: &str) -> Result<Attributes, Error> {
+        self.read()?
+            .remove(&(service.to_string(), account.to_string()))
+            .map(|item| item.attributes)
+            .ok_or_else(|| not_found(account))
+    }
+
+    fn set_attributes(
+        &self,
+        service: &str,
+        account: &str,
+        attributes: &Attributes,
+    ) -> Result<(), Error> {
+        attributes::check(attributes)?;
+        let _guard = lock_item(service, account);
+        self.update(|items| {
+            let item = items
+                .get_mut(&(service.to_string(), account.to_string()))
+                .ok_or_else(|| not_found(account))?;
+            item.attributes = item
+                .attributes
+                .clone()
+                .replaced_by(attributes)
+                .touched(SystemTime::now());
+            Ok(())
+        })
+    }
+
+    /// The file is removed without being decrypted, so this also resets a
+    /// store whose master key was lost.
+    fn wipe(&self) -> Result<Vec<AccountInfo>, Error> {
+        let _lock = self.lock()?;
+        file::remove(&self.config.path)?;
+        Ok(Vec::new())
+    }
+
+    fn list(&self, service: &str) -> Result<Vec<AccountInfo>, Error> {
+        // Items are ordered by service, then account
+        Ok(self
+            .read()?
+            .into_iter()
+            .filter(|((s, _), _)| s == service)
+            .map(|((service, account), _)| AccountInfo { service, account })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance;
+
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Directory of its own for a test, removed with everything in it when
+    /// dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let count = COUNT.fetch_add(1, Ordering::Relaxed);
+            TempDir(env::temp_dir().join(format!("secure-native-{}-{}", std::process::id(), count)))
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn store(dir: &TempDir, name: &str, key: u8) -> FileStore {
+        FileStore::open(Config {
+            path: dir.path().join(name),
+            key: MasterKey::Fixed([key; keyring::MASTER_KEY_LEN]),
+        })
+    }
+
+    #[test]
+    fn test_round_trip_through_file() {
+        let dir = TempDir::new();
+        let store = store(&dir, "round-trip", 1);
+        store
+            .put("service", "account", "secret", &AccessPolicy::default())
+            .unwrap();
+        assert_eq!(&*store.get("service", "account").unwrap(), "secret");
+
+        let reopened = FileStore::open(Config {
+            path: store.config.path.clone(),
+            key: MasterKey::Fixed([1; keyring::MASTER_KEY_LEN]),
+        });
+        assert_eq!(&*reopened.get("service", "account").unwrap(), "secret");
+        let guarded = reopened.get_guarded("service", "account").unwrap();
+        guarded.read(|value| assert_eq!(value, b"secret")).unwrap();
+    }
+
+    #[test]
+    fn test_conformance() {
+        let dir = TempDir::new();
+        conformance::run(&store(&dir, "conformance", 1));
+        conformance::wipe_removes_everything(&store(&dir, "wipe", 1));
+    }
+
+    #[test]
+    fn test_wrong_key_is_rejected() {
+        let dir = TempDir::new();
+        let store = store(&dir, "wrong-key", 1);
+        store
+            .put("service", "account", "secret", &AccessPolicy::default())
+            .unwrap();
+
+        let other = FileStore::open(Config {
+            path: store.config.path.clone(),
+            key: MasterKey::Fixed([2; keyring::MASTER_KEY_LEN]),
+        });
+        assert!(other.get("service", "account").is_err());
+    }
+
+    #[test]
+    fn test_concurrent_stores_keep_every_write() {
+        let dir = TempDir::new();
+        let path = store(&dir, "concurrent", 1).config.path;
+        let threads: Vec<_> = (0..8)
+            .map(|thread| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    for account in 0..20 {
+                        // As every export does
+                        let store = FileStore::open(Config {
+                            path: path.clone(),
+                            key: MasterKey::Fixed([1; keyring::MASTER_KEY_LEN]),
+                        });
+                        store
+                            .put(
+                                "service",
+                                &format!("{}-{}", thread, account),
+                                "secret",
+                                &AccessPolicy::default(),
+                            )
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(
+            store(&dir, "concurrent", 1).list("service").unwrap().len(),
+            160
+        );
+        let leftovers: Vec<_> = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name.to_string_lossy().starts_with("concurrent."))
+            .filter(|name| name.to_string_lossy() != "concurrent.lock")
+            .collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+    }
+
+    #[test]
+    fn test_file_lock_blocks_other_processes() {
+        let dir = TempDir::new();
+        // Each `flock` is on its own open file, so another thread stands in
+        // for another process
+        let path = store(&dir, "file-lock", 1).config.path;
+        let lock = file::lock(&path).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let other = path.clone();
+        thread::spawn(move || {
+            let _lock = file::lock(&other).unwrap();
+            sender.send(()).unwrap();
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(lock);
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+
+    #[test]
+    fn test_lost_master_key() {
+        let dir = TempDir::new();
+        let description = format!("secure-native:test:{}", std::process::id());
+        if keyring::find(&description).is_err() {
+            // No keyring to test against
+            return;
+        }
+        let store = FileStore::open(Config {
+            path: store(&dir, "lost-key", 1).config.path,
+            key: MasterKey::Keyring(description.clone()),
+        });
+        store
+            .put("service", "account", "secret", &AccessPolicy::default())
+            .unwrap();
+        keyring::invalidate(&description).unwrap();
+
+        let error = store.contains("service", "account").unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::KeyInvalidated);
+        let error = store
+            .put("service", "other", "secret", &AccessPolicy::default())
+            .unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::KeyInvalidated);
+        assert!(keyring::find(&description).unwrap().is_none());
+
+        assert!(store.wipe().unwrap().is_empty());
+        store
+            .put("service", "account", "fresh", &AccessPolicy::default())
+            .unwrap();
+        assert_eq!(&*store.get("service", "account").unwrap(), "fresh");
+        keyring::invalidate(&description).unwrap();
+    }
+
+    #[test]
+    fn test_wipe_resets_undecryptable_store() {
+        let dir = TempDir::new();
+        let store = store(&dir, "undecryptable", 1);
+        store
+            .put("service", "account", "secret", &AccessPolicy::default())
+            .unwrap();
+
+        let other = FileStore::open(Config {
+            path: store.config.path.clone(),
+            key: MasterKey::Fixed([2; keyring::MASTER_KEY_LEN]),
+        });
+        assert!(other.contains("service", "account").is_err());
+        assert!(other.wipe().unwrap().is_empty());
+        assert!(!other.contains("service", "account").unwrap());
+    }
+}
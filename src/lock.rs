@@ -0,0 +1,368 @@
+//! Process-wide locks on items
+//!
+//! Backends take the lock of a service/account pair around every write, so
+//! a caller holding it can read, modify and write an item without another
+//! thread interleaving. Locks are reentrant: the thread holding one can call
+//! into the store, which takes it again.
+//!
+//! Writes also share a lock on the whole store, which maintenance spanning
+//! every item, such as deleting keys without an item, holds exclusively.
+
+use std::collections::BTreeMap;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+
+type Item = (String, String);
+
+static LOCKS: Locks = Locks::new();
+
+static STORE: StoreLock = StoreLock::new();
+
+/// Lock the item stored for the pair until the guard is dropped, blocking
+/// while another thread holds it.
+pub fn lock_item(service: &str, account: &str) -> ItemGuard<'static> {
+    LOCKS.lock(service, account)
+}
+
+/// Lock every item among `items`, in an order shared by every caller so that
+/// two threads locking overlapping sets can't deadlock.
+pub fn lock_items<'i, I>(items: I) -> Vec<ItemGuard<'static>>
+where
+    I: IntoIterator<Item = (&'i str, &'i str)>,
+{
+    let mut items: Vec<_> = items.into_iter().collect();
+    items.sort();
+    items.dedup();
+    items
+        .into_iter()
+        .map(|(service, account)| lock_item(service, account))
+        .collect()
+}
+
+/// Share the store lock until the guard is dropped, blocking while another
+/// thread holds it exclusively.
+pub fn share_store() -> StoreGuard<'static> {
+    STORE.share()
+}
+
+/// Hold the store lock exclusively until the guard is dropped, blocking
+/// until no other thread shares it.
+pub fn lock_store() -> StoreGuard<'static> {
+    STORE.lock()
+}
+
+/// A set of item locks.
+pub struct Locks {
+    /// Holder and depth of each held lock
+    held: Mutex<BTreeMap<Item, (ThreadId, usize)>>,
+    released: Condvar,
+}
+
+impl Locks {
+    pub const fn new() -> Self {
+        Locks {
+            held: Mutex::new(BTreeMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Lock the item stored for the pair until the guard is dropped.
+    pub fn lock(&self, service: &str, account: &str) -> ItemGuard<'_> {
+        let item = (service.to_string(), account.to_string());
+        let me = thread::current().id();
+        let mut held = self.held();
+        loop {
+            match held.get_mut(&item) {
+                None => {
+                    held.insert(item.clone(), (me, 1));
+                    break;
+                }
+                Some((holder, depth)) if *holder == me => {
+                    *depth += 1;
+                    break;
+                }
+                Some(_) => {}
+            }
+            held = self.released.wait(held).unwrap_or_else(|e| e.into_inner());
+        }
+        ItemGuard { locks: self, item }
+    }
+
+    fn held(&self) -> MutexGuard<'_, BTreeMap<Item, (ThreadId, usize)>> {
+        self.held.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Proof that the current thread holds an item's lock.
+#[must_use]
+pub struct ItemGuard<'a> {
+    locks: &'a Locks,
+    item: Item,
+}
+
+impl<'a> Drop for ItemGuard<'a> {
+    fn drop(&mut self) {
+        let mut held = self.locks.held();
+        let released = match held.get_mut(&self.item) {
+            Some((_, depth)) => {
+                *depth -= 1;
+                *depth == 0
+            }
+            None => false,
+        };
+        if released {
+            held.remove(&self.item);
+            self.locks.released.notify_all();
+        }
+    }
+}
+
+/// A lock shared by writers and held exclusively by operations on the whole
+/// store. It is reentrant both ways, and the thread holding it exclusively
+/// may also share it.
+pub struct StoreLock {
+    state: Mutex<StoreState>,
+    released: Condvar,
+}
+
+struct StoreState {
+    /// Depth of each thread's share
+    shared: Vec<(ThreadId, usize)>,
+    /// Holder and depth of the exclusive lock
+    exclusive: Option<(ThreadId, usize)>,
+}
+
+impl StoreLock {
+    pub const fn new() -> Self {
+        StoreLock {
+            state: Mutex::new(StoreState {
+                shared: Vec::new(),
+                exclusive: None,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Share the lock until the guard is dropped.
+    pub fn share(&self) -> StoreGuard<'_> {
+        let me = thread::current().id();
+        let mut state = self.state();
+        while state.exclusive.is_some_and(|(holder, _)| holder != me) {
+            state = self.released.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        match state.shared.iter_mut().find(|(holder, _)| *holder == me) {
+            Some((_, depth)) => *depth += 1,
+            None => state.shared.push((me, 1)),
+        }
+        StoreGuard {
+            lock: self,
+            exclusive: false,
+        }
+    }
+
+    /// Hold the lock exclusively until the guard is dropped.
+    pub fn lock(&self) -> StoreGuard<'_> {
+        let me = thread::current().id();
+        let mut state = self.state();
+        loop {
+            let shared_by_others = state.shared.iter().any(|(holder, _)| *holder != me);
+            match state.exclusive {
+                Some((holder, ref mut depth)) if holder == me => {
+                    *depth += 1;
+                    break;
+                }
+                None if !shared_by_others => {
+                    state.exclusive = Some((me, 1));
+                    break;
+                }
+                _ => {}
+            }
+            state = self.released.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        StoreGuard {
+            lock: self,
+            exclusive: true,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, StoreState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Proof that the current thread shares or exclusively holds the store lock.
+#[must_use]
+pub struct StoreGuard<'a> {
+    lock: &'a StoreLock,
+    exclusive: bool,
+}
+
+impl<'a> Drop for StoreGuard<'a> {
+    fn drop(&mut self) {
+        let me = thread::current().id();
+        let mut state = self.lock.state();
+        let released = if self.exclusive {
+            match state.exclusive {
+                Some((_, ref mut depth)) if *depth > 1 => {
+                    *depth -= 1;
+                    false
+                }
+                _ => {
+                    state.exclusive = None;
+                    true
+                }
+            }
+        } else {
+            match state.shared.iter().position(|(holder, _)| *holder == me) {
+                Some(index) if state.shared[index].1 > 1 => {
+                    state.shared[index].1 -= 1;
+                    false
+                }
+                Some(index) => {
+                    state.shared.swap_remove(index);
+                    true
+                }
+                None => false,
+            }
+        };
+        if released {
+            self.lock.released.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use crate::{AccessPolicy, SecureStore};
+
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    #[test]
+    fn test_read_modify_write_is_linearizable() {
+        let locks = Arc::new(Locks::new());
+        let store = Arc::new(MemoryStore::open(()));
+        store
+            .put("service", "counter", "0", &AccessPolicy::default())
+            .unwrap();
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (locks, store) = (locks.clone(), store.clone());
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        let _guard = locks.lock("service", "counter");
+                        let count: u32 = store.get("service", "counter").unwrap().parse().unwrap();
+                        thread::yield_now();
+                        store
+                            .put(
+                                "service",
+                                "counter",
+                                &(count + 1).to_string(),
+                                &AccessPolicy::default(),
+                            )
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(&*store.get("service", "counter").unwrap(), "800");
+    }
+
+    #[test]
+    fn test_lock_is_reentrant() {
+        let locks = Locks::new();
+        let outer = locks.lock("service", "account");
+        let inner = locks.lock("service", "account");
+        drop(inner);
+        assert!(locks
+            .held()
+            .contains_key(&("service".into(), "account".into())));
+        drop(outer);
+        assert!(locks.held().is_empty());
+    }
+
+    #[test]
+    fn test_other_items_do_not_block() {
+        let locks = Arc::new(Locks::new());
+        let _guard = locks.lock("service", "a");
+        let (sender, receiver) = mpsc::channel();
+        let other = locks.clone();
+        thread::spawn(move || {
+            let _guard = other.lock("service", "b");
+            let _guard = other.lock("other", "a");
+            sender.send(()).unwrap();
+        });
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+
+    #[test]
+    fn test_held_item_blocks_other_threads() {
+        let locks = Arc::new(Locks::new());
+        let guard = locks.lock("service", "account");
+        let (sender, receiver) = mpsc::channel();
+        let other = locks.clone();
+        thread::spawn(move || {
+            let _guard = other.lock("service", "account");
+            sender.send(()).unwrap();
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(guard);
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+
+    #[test]
+    fn test_store_lock_waits_for_writers() {
+        let lock = Arc::new(StoreLock::new());
+        let writer = lock.share();
+        let (sender, receiver) = mpsc::channel();
+        let other = lock.clone();
+        thread::spawn(move || {
+            let _writer = other.share();
+            sender.send("shared").unwrap();
+            let _maintenance = other.lock();
+            sender.send("locked").unwrap();
+        });
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(10)), Ok("shared"));
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(writer);
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(10)), Ok("locked"));
+    }
+
+    #[test]
+    fn test_store_lock_blocks_writers() {
+        let lock = Arc::new(StoreLock::new());
+        let maintenance = lock.lock();
+        let (sender, receiver) = mpsc::channel();
+        let other = lock.clone();
+        thread::spawn(move || {
+            let _writer = other.share();
+            sender.send(()).unwrap();
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(maintenance);
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+
+    #[test]
+    fn test_store_lock_is_reentrant() {
+        let lock = StoreLock::new();
+        {
+            let _outer = lock.lock();
+            let _inner = lock.lock();
+            let _writer = lock.share();
+            let _nested = lock.share();
+        }
+        {
+            let _outer = lock.share();
+            let _inner = lock.share();
+        }
+        let state = lock.state();
+        assert!(state.shared.is_empty());
+        assert!(state.exclusive.is_none());
+    }
+}
@@ -0,0 +1,173 @@
+//! In-memory reference backend
+//!
+//! Nothing is persisted or encrypted. This backend exists to pin down the
+//! semantics every platform backend must share (see `conformance`) and to
+//! run library code in host tests.
+
+use crate::attributes;
+use crate::{
+    lock_item, lock_items, AccessPolicy, AccountInfo, Attributes, Error, ErrorKind, SecretBytes,
+    SecureStore, Write,
+};
+
+use std::{collections::HashMap, sync::Mutex, time::SystemTime};
+
+struct Item {
+    value: SecretBytes,
+    attributes: Attributes,
+}
+
+type Items = HashMap<(String, String), Item>;
+
+/// `SecureStore` holding its items in a process-local map.
+#[derive(Default)]
+pub struct MemoryStore {
+    items: Mutex<Items>,
+}
+
+impl MemoryStore {
+    fn items(&self) -> std::sync::MutexGuard<'_, Items> {
+        self.items.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Store `value` for the pair, keeping the attributes of a replaced item.
+fn insert(items: &mut Items, service: &str, account: &str, value: &[u8]) {
+    let key = (service.to_string(), account.to_string());
+    let attributes = items
+        .remove(&key)
+        .map(|item| item.attributes)
+        .unwrap_or_default()
+        .touched(SystemTime::now());
+    items.insert(
+        key,
+        Item {
+            value: SecretBytes::from(value),
+            attributes,
+        },
+    );
+}
+
+fn not_found(account: &str) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        &format!("Couldn't find value for key: {}", account),
+    )
+}
+
+impl SecureStore for MemoryStore {
+    type Config = ();
+
+    fn open(_: Self::Config) -> Self {
+        MemoryStore::default()
+    }
+
+    fn put_bytes(
+        &self,
+        service: &str,
+        account: &str,
+        value: &[u8],
+        _policy: &AccessPolicy,
+    ) -> Result<(), Error> {
+        let _guard = lock_item(service, account);
+        insert(&mut self.items(), service, account, value);
+        Ok(())
+    }
+
+    fn get_bytes(&self, service: &str, account: &str) -> Result<SecretBytes, Error> {
+        self.items()
+            .get(&(service.to_string(), account.to_string()))
+            .map(|item| item.value.clone())
+            .ok_or_else(|| not_found(account))
+    }
+
+    fn contains(&self, service: &str, account: &str) -> Result<bool, Error> {
+        Ok(self
+            .items()
+            .contains_key(&(service.to_string(), account.to_string())))
+    }
+
+    fn delete(&self, service: &str, account: &str) -> Result<(), Error> {
+        let _guard = lock_item(service, account);
+        self.items()
+            .remove(&(service.to_string(), account.to_string()));
+        Ok(())
+    }
+
+    fn apply(&self, writes: &[Write]) -> Result<(), Error> {
+        let _guards = lock_items(writes.iter().map(|w| (w.service(), w.account())));
+        let mut items = self.items();
+        for write in writes {
+            match write {
+                Write::Put {
+                    service,
+                    account,
+                    value,
+                    ..
+                } => insert(&mut items, service, account, value),
+                Write::Delete { service, account } => {
+                    items.remove(&(service.clone(), account.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn get_attributes(&self, service: &str, account: &str) -> Result<Attributes, Error> {
+        self.items()
+            .get(&(service.to_string(), account.to_string()))
+            .map(|item| item.attributes.clone())
+            .ok_or_else(|| not_found(account))
+    }
+
+    fn set_attributes(
+        &self,
+        service: &str,
+        account: &str,
+        attributes: &Attributes,
+    ) -> Result<(), Error> {
+        attributes::check(attributes)?;
+        let _guard = lock_item(service, account);
+        let mut items = self.items();
+        let item = items
+            .get_mut(&(service.to_string(), account.to_string()))
+            .ok_or_else(|| not_found(account))?;
+        item.attributes = item
+            .attributes
+            .clone()
+            .replaced_by(attributes)
+            .touched(SystemTime::now());
+        Ok(())
+    }
+
+    fn wipe(&self) -> Result<Vec<AccountInfo>, Error> {
+        self.items().clear();
+        Ok(Vec::new())
+    }
+
+    fn list(&self, service: &str) -> Result<Vec<AccountInfo>, Error> {
+        let mut accounts: Vec<AccountInfo> = self
+            .items()
+            .keys()
+            .filter(|(s, _)| s == service)
+            .map(|(service, account)| AccountInfo {
+                service: service.clone(),
+                account: account.clone(),
+            })
+            .collect();
+        accounts.sort();
+        Ok(accounts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance;
+
+    #[test]
+    fn test_conformance() {
+        conformance::run(&MemoryStore::open(()));
+        conformance::wipe_removes_everything(&MemoryStore::open(()));
+    }
+}
@@ -0,0 +1,387 @@
+//! Names under which backends store items
+//!
+//! Android keeps every item in one SharedPreferences file, keyed by a
+//! length-prefixed encoding of the service and account, and encrypts it with
+//! a keystore key whose alias is derived the same way. Length prefixes make
+//! the encoding injective: no two pairs share a name, whatever characters
+//! they contain.
+//!
+//! The legacy scheme concatenated the strings, so service "ab" with account
+//! "c" collided with service "a" with account "bc", and account "fooiv"
+//! collided with the IV of account "foo". Its names are kept here so stored
+//! items can be found and migrated.
+//!
+//! With `NameMode::Hashed` the names themselves are replaced by keyed hashes
+//! of the same encodings, so stored items don't reveal which services and
+//! accounts an app uses. The key is a store-level secret held by the
+//! platform keystore.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// How a store names its items on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NameMode {
+    /// Service and account names are stored as given.
+    #[default]
+    Plain,
+    /// Service and account names are stored as keyed hashes. Items stored
+    /// with plain names are still found, and are moved to hashed names when
+    /// they are next written.
+    Hashed,
+}
+
+/// SharedPreferences file holding every item.
+pub const PREFERENCES: &str = "secure-native";
+
+/// SharedPreferences file recording the policy each keystore key was
+/// generated for, keyed by alias.
+pub const KEY_POLICIES: &str = "secure-native.keys";
+
+/// SharedPreferences file holding the attribute record of each item, keyed
+/// like `PREFERENCES`, or by `legacy_attributes_key` for legacy items.
+pub const ATTRIBUTES: &str = "secure-native.attributes";
+
+/// Whether `file` is one of the SharedPreferences files of the current
+/// scheme, rather than a legacy one or one of the app's own.
+pub fn is_library_file(file: &str) -> bool {
+    file == PREFERENCES || file == KEY_POLICIES || file == ATTRIBUTES
+}
+
+/// Prefix of every keystore alias.
+pub const ALIAS_PREFIX: &str = "secure-native:";
+
+/// Preference key for an item.
+pub fn pref_key(service: &str, account: &str) -> String {
+    format!("{}:{}{}:{}", service.len(), service, account.len(), account)
+}
+
+/// Keystore alias of the key encrypting an item.
+pub fn alias(service: &str, account: &str) -> String {
+    format!("{}{}", ALIAS_PREFIX, pref_key(service, account))
+}
+
+/// Recover the service and account from a preference key.
+pub fn parse_pref_key(key: &str) -> Option<(&str, &str)> {
+    let (service, rest) = parse_field(key)?;
+    let (account, rest) = parse_field(rest)?;
+    if rest.is_empty() {
+        Some((service, account))
+    } else {
+        None
+    }
+}
+
+fn parse_field(s: &str) -> Option<(&str, &str)> {
+    let colon = s.find(':')?;
+    let len: usize = s[..colon].parse().ok()?;
+    let rest = &s[colon + 1..];
+    if rest.len() < len || !rest.is_char_boundary(len) {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+/// Keystore alias of the key used to hash names.
+pub const NAME_KEY_ALIAS: &str = "secure-native:names";
+
+/// Keychain service of the secret used to hash names.
+pub const NAME_KEY_SERVICE: &str = "secure-native";
+
+/// Keychain account of the secret used to hash names.
+pub const NAME_KEY_ACCOUNT: &str = "secure-native:names";
+
+/// Length in bytes of the secret used to hash names.
+pub const NAME_KEY_LENGTH: usize = 32;
+
+/// Keychain service of the journal of an unfinished transaction.
+pub const JOURNAL_SERVICE: &str = "secure-native";
+
+/// Keychain account of the journal of an unfinished transaction.
+pub const JOURNAL_ACCOUNT: &str = "secure-native:journal";
+
+/// Whether the pair names an item the library keeps for itself, rather than
+/// one stored by the app.
+pub fn is_internal(service: &str, account: &str) -> bool {
+    (service == NAME_KEY_SERVICE && account == NAME_KEY_ACCOUNT)
+        || (service == JOURNAL_SERVICE && account == JOURNAL_ACCOUNT)
+}
+
+/// Message whose keyed hash names a service.
+pub fn hashed_service_input(service: &str) -> Vec<u8> {
+    format!("s{}:{}", service.len(), service).into_bytes()
+}
+
+/// Message whose keyed hash names an item. It covers the service too, so the
+/// same account under two services gets unrelated names.
+pub fn hashed_item_input(service: &str, account: &str) -> Vec<u8> {
+    format!("i{}", pref_key(service, account)).into_bytes()
+}
+
+/// Message whose MAC authenticates the attribute record stored under `key`
+/// in `ATTRIBUTES`. It covers the key, which names both service and account,
+/// so records can't be moved between items.
+pub fn attributes_mac_input(key: &str, record: &[u8]) -> Vec<u8> {
+    let mut input = format!("a{}:{}", key.len(), key).into_bytes();
+    input.extend_from_slice(record);
+    input
+}
+
+/// Keystore alias of the key encrypting an item stored under a hashed name.
+pub fn hashed_alias(name: &str) -> String {
+    format!("{}{}", ALIAS_PREFIX, name)
+}
+
+/// Hashed name of `input` under `secret`: hex encoded HMAC-SHA256.
+pub fn keyed_hash(secret: &[u8], input: &[u8]) -> String {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(input);
+    hex(&mac.finalize().into_bytes())
+}
+
+/// Lowercase hex encoding of a MAC computed elsewhere, e.g. by the keystore.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Keystore aliases without a preference key and preference keys without a
+/// keystore alias, among aliases and keys of the `PREFERENCES` file.
+/// Aliases outside the current scheme are ignored, as is the naming key.
+pub fn orphans<'a>(aliases: &'a [String], keys: &'a [String]) -> (Vec<&'a str>, Vec<&'a str>) {
+    let owned: Vec<(&str, &str)> = aliases
+        .iter()
+        .filter(|alias| alias.as_str() != NAME_KEY_ALIAS && alias.starts_with(ALIAS_PREFIX))
+        .map(|alias| (alias.as_str(), &alias[ALIAS_PREFIX.len()..]))
+        .collect();
+    let orphan_aliases = owned
+        .iter()
+        .filter(|(_, key)| !keys.iter().any(|k| k == key))
+        .map(|(alias, _)| *alias)
+        .collect();
+    let orphan_keys = keys
+        .iter()
+        .map(String::as_str)
+        .filter(|key| !owned.iter().any(|(_, k)| k == key))
+        .collect();
+    (orphan_aliases, orphan_keys)
+}
+
+/// Accounts of `service`, sorted, given the keys of the `PREFERENCES` file
+/// and of the service's legacy file, and every keystore alias. Only the
+/// legacy file's entries of legacy items count (see `legacy_accounts`).
+pub fn accounts<'a>(
+    service: &str,
+    keys: &'a [String],
+    legacy_keys: &'a [String],
+    aliases: &[String],
+) -> Vec<&'a str> {
+    let current = keys
+        .iter()
+        .filter_map(|key| parse_pref_key(key))
+        .filter(|(s, _)| *s == service)
+        .map(|(_, account)| account);
+    let legacy = legacy_accounts(service, legacy_keys, aliases);
+    let mut accounts: Vec<&str> = current.chain(legacy).collect();
+    accounts.sort();
+    accounts.dedup();
+    accounts
+}
+
+/// Accounts of the items stored under the legacy scheme in the
+/// SharedPreferences file of `service`, given the file's keys and every
+/// keystore alias. Legacy files may be shared with the app's own settings, so
+/// only entries with a legacy alias or an IV entry beside them count.
+pub fn legacy_accounts<'a>(service: &str, keys: &'a [String], aliases: &[String]) -> Vec<&'a str> {
+    keys.iter()
+        .map(String::as_str)
+        .filter(|key| {
+            aliases
+                .iter()
+                .any(|alias| *alias == legacy_alias(service, key))
+                || keys.iter().any(|k| *k == legacy_iv_key(key))
+        })
+        .collect()
+}
+
+/// SharedPreferences file of an item stored under the legacy scheme.
+pub fn legacy_preferences(service: &str) -> &str {
+    service
+}
+
+/// Preference key of an item stored under the legacy scheme.
+pub fn legacy_pref_key(account: &str) -> &str {
+    account
+}
+
+/// Preference key of the IV of an item stored under the legacy scheme.
+pub fn legacy_iv_key(account: &str) -> String {
+    format!("{}iv", account)
+}
+
+/// Key of the attribute record of an item stored under the legacy scheme.
+/// Its preference key is just the account, shared by every service, so the
+/// record is keyed by the pair, apart from the record of a current item.
+pub fn legacy_attributes_key(service: &str, account: &str) -> String {
+    format!("l{}", pref_key(service, account))
+}
+
+/// Keystore alias of an item stored under the legacy scheme.
+pub fn legacy_alias(service: &str, account: &str) -> String {
+    format!("{}{}", service, account)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_do_not_collide() {
+        let pairs = [
+            ("ab", "c"),
+            ("a", "bc"),
+            ("a", "account"),
+            ("a", "accountiv"),
+            ("", "aaccount"),
+            ("1:a", "b"),
+            ("1", ":ab"),
+        ];
+        for (i, a) in pairs.iter().enumerate() {
+            for b in &pairs[i + 1..] {
+                assert_ne!(pref_key(a.0, a.1), pref_key(b.0, b.1));
+                assert_ne!(alias(a.0, a.1), alias(b.0, b.1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_round_trip() {
+        for (service, account) in &[("ab", "c"), ("", ""), ("1:2", "3:"), ("sérvice", "ç")] {
+            assert_eq!(
+                parse_pref_key(&pref_key(service, account)),
+                Some((*service, *account))
+            );
+        }
+    }
+
+    #[test]
+    fn test_keyed_hash() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            keyed_hash(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_hashed_names_hide_and_separate() {
+        let secret = [7u8; NAME_KEY_LENGTH];
+        let item = keyed_hash(&secret, &hashed_item_input("ab", "c"));
+        assert_eq!(item.len(), 64);
+        assert!(!item.contains("ab"));
+        assert_eq!(parse_pref_key(&item), None);
+        assert_ne!(item, keyed_hash(&secret, &hashed_item_input("a", "bc")));
+        assert_ne!(
+            item,
+            keyed_hash(&[8u8; NAME_KEY_LENGTH], &hashed_item_input("ab", "c"))
+        );
+        assert_ne!(
+            keyed_hash(&secret, &hashed_item_input("s", "a")),
+            keyed_hash(&secret, &hashed_item_input("t", "a"))
+        );
+        assert_ne!(hashed_service_input("1:a1:b"), hashed_item_input("a", "b"));
+        assert_ne!(
+            attributes_mac_input("1:a1:b", b""),
+            hashed_item_input("a", "b")
+        );
+        assert_ne!(
+            attributes_mac_input("ab", b"c"),
+            attributes_mac_input("a", b"bc")
+        );
+        assert_ne!(hashed_alias(&item), NAME_KEY_ALIAS);
+    }
+
+    #[test]
+    fn test_orphans() {
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let aliases = strings(&[
+            &alias("s", "kept"),
+            &alias("s", "lost"),
+            &hashed_alias("00ff"),
+            NAME_KEY_ALIAS,
+            "someone else's key",
+            &legacy_alias("s", "legacy"),
+        ]);
+        let keys = strings(&[&pref_key("s", "kept"), &pref_key("s", "stray"), "00ff"]);
+        let (orphan_aliases, orphan_keys) = orphans(&aliases, &keys);
+        assert_eq!(orphan_aliases, vec![alias("s", "lost")]);
+        assert_eq!(orphan_keys, vec![pref_key("s", "stray")]);
+    }
+
+    #[test]
+    fn test_accounts() {
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let keys = strings(&[
+            &pref_key("s", "b"),
+            &pref_key("s", "a"),
+            &pref_key("t", "c"),
+            &pref_key("s", "both"),
+            "00ff",
+        ]);
+        // The legacy file is the app's too, so its settings aren't items
+        let legacy_keys = strings(&["old", "oldiv", "both", "bothiv", "keyed", "loneiv", "theme"]);
+        let aliases = strings(&[&legacy_alias("s", "keyed"), &legacy_alias("t", "theme")]);
+        assert_eq!(
+            accounts("s", &keys, &legacy_keys, &aliases),
+            vec!["a", "b", "both", "keyed", "old"]
+        );
+        assert_eq!(accounts("t", &keys, &[], &aliases), vec!["c"]);
+    }
+
+    #[test]
+    fn test_legacy_accounts() {
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let keys = strings(&["keyed", "with", "withiv", "theme", "alone"]);
+        let aliases = strings(&[
+            &legacy_alias("s", "keyed"),
+            &legacy_alias("t", "theme"),
+            &alias("s", "alone"),
+        ]);
+        assert_eq!(legacy_accounts("s", &keys, &aliases), vec!["keyed", "with"]);
+        assert!(!is_library_file("s"));
+        assert!(is_library_file(ATTRIBUTES));
+    }
+
+    #[test]
+    fn test_legacy_attribute_records_are_per_item() {
+        // Two services sharing an account share its legacy preference key
+        assert_eq!(legacy_pref_key("account"), "account");
+        let s = legacy_attributes_key("s", "account");
+        let t = legacy_attributes_key("t", "account");
+        assert_ne!(s, t);
+        assert_ne!(
+            attributes_mac_input(&s, b"record"),
+            attributes_mac_input(&t, b"record")
+        );
+        for key in &[&s, &t] {
+            assert_eq!(parse_pref_key(key), None);
+            assert_ne!(key.as_str(), legacy_pref_key("account"));
+        }
+        assert_ne!(s, pref_key("s", "account"));
+    }
+
+    #[test]
+    fn test_parse_rejects_other_keys() {
+        for key in &[
+            "",
+            "account",
+            "accountiv",
+            "2:a",
+            "1:a1:bc",
+            "x:a1:b",
+            "1:é0:",
+        ] {
+            assert_eq!(parse_pref_key(key), None);
+        }
+    }
+}
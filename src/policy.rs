@@ -0,0 +1,249 @@
+//! Access control for stored items
+
+use crate::{Error, ErrorKind};
+
+/// When an item can be read, independent of any user authentication.
+///
+/// Mirrors the iOS `kSecAttrAccessible*` classes. On Android the
+/// `WhenUnlocked*` and `WhenPasscodeSet*` classes require the device to be
+/// unlocked to use the key, and keys never leave the device.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Accessibility {
+    /// Readable while the device is unlocked; never migrates to a new device.
+    #[default]
+    WhenUnlockedThisDeviceOnly,
+    /// Readable while the device is unlocked.
+    WhenUnlocked,
+    /// Readable after the first unlock following a restart; never migrates.
+    AfterFirstUnlockThisDeviceOnly,
+    /// Readable after the first unlock following a restart.
+    AfterFirstUnlock,
+    /// Readable while the device is unlocked, and only while a passcode is
+    /// set. Removing the passcode deletes the item.
+    WhenPasscodeSetThisDeviceOnly,
+}
+
+/// User authentication required to read an item.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Constraint {
+    /// Biometry with the currently enrolled set. Enrolling or removing a
+    /// fingerprint or face invalidates the item.
+    BiometryCurrentSet,
+    /// Biometry with any enrolled set, including ones enrolled later.
+    BiometryAny,
+    /// Biometry or the device passcode, whichever is available.
+    UserPresence,
+    /// The device passcode.
+    DevicePasscode,
+    /// An application-provided password. Not available on Android.
+    ApplicationPassword,
+}
+
+/// How several constraints combine.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Combinator {
+    /// Every constraint must be satisfied.
+    And,
+    /// Any one constraint is enough.
+    Or,
+}
+
+/// Protection applied to an item when it is written.
+///
+/// The default policy requires no authentication and makes the item
+/// readable while the device is unlocked.
+///
+/// ```
+/// use secure_native::{AccessPolicy, Combinator, Constraint};
+///
+/// let policy = AccessPolicy::default()
+///     .require(Constraint::BiometryAny)
+///     .require(Constraint::DevicePasscode)
+///     .combine(Combinator::Or);
+/// assert_eq!(AccessPolicy::from_bits(policy.to_bits()).unwrap(), policy);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessPolicy {
+    accessibility: Accessibility,
+    constraints: Vec<Constraint>,
+    combinator: Combinator,
+}
+
+impl Default for AccessPolicy {
+    fn default() -> Self {
+        AccessPolicy {
+            accessibility: Accessibility::default(),
+            constraints: Vec::new(),
+            combinator: Combinator::And,
+        }
+    }
+}
+
+// FFI encoding. Bit 0 alone is what `with_biometry = true` used to mean, so
+// callers still passing 0 or 1 get the same protection as before.
+const BIOMETRY_CURRENT_SET: u32 = 1 << 0;
+const BIOMETRY_ANY: u32 = 1 << 1;
+const USER_PRESENCE: u32 = 1 << 2;
+const DEVICE_PASSCODE: u32 = 1 << 3;
+const APPLICATION_PASSWORD: u32 = 1 << 4;
+const CONSTRAINTS_MASK: u32 = 0x1f;
+const OR: u32 = 1 << 7;
+const ACCESSIBILITY_SHIFT: u32 = 8;
+const ACCESSIBILITY_MASK: u32 = 0xf << ACCESSIBILITY_SHIFT;
+
+const CONSTRAINTS: [(Constraint, u32); 5] = [
+    (Constraint::BiometryCurrentSet, BIOMETRY_CURRENT_SET),
+    (Constraint::BiometryAny, BIOMETRY_ANY),
+    (Constraint::UserPresence, USER_PRESENCE),
+    (Constraint::DevicePasscode, DEVICE_PASSCODE),
+    (Constraint::ApplicationPassword, APPLICATION_PASSWORD),
+];
+
+const ACCESSIBILITIES: [Accessibility; 5] = [
+    Accessibility::WhenUnlockedThisDeviceOnly,
+    Accessibility::WhenUnlocked,
+    Accessibility::AfterFirstUnlockThisDeviceOnly,
+    Accessibility::AfterFirstUnlock,
+    Accessibility::WhenPasscodeSetThisDeviceOnly,
+];
+
+impl AccessPolicy {
+    /// The policy `with_biometry = true` used to select: biometry with the
+    /// current enrollment, readable while unlocked.
+    pub fn biometry() -> Self {
+        AccessPolicy::default().require(Constraint::BiometryCurrentSet)
+    }
+
+    /// Set the accessibility class.
+    pub fn accessible(mut self, accessibility: Accessibility) -> Self {
+        self.accessibility = accessibility;
+        self
+    }
+
+    /// Add a constraint. Adding the same constraint twice has no effect.
+    pub fn require(mut self, constraint: Constraint) -> Self {
+        if !self.constraints.contains(&constraint) {
+            self.constraints.push(constraint);
+            self.constraints.sort_by_key(|c| bit(*c));
+        }
+        self
+    }
+
+    /// Set how the constraints combine. Defaults to `Combinator::And`.
+    pub fn combine(mut self, combinator: Combinator) -> Self {
+        self.combinator = combinator;
+        self
+    }
+
+    /// Get the accessibility class
+    pub fn accessibility(&self) -> Accessibility {
+        self.accessibility
+    }
+
+    /// Get the constraints, in a fixed order
+    pub fn constraints(&self) -> &[Constraint] {
+        &self.constraints
+    }
+
+    /// Get the combinator
+    pub fn combinator(&self) -> Combinator {
+        self.combinator
+    }
+
+    /// Encode the policy for passing across the FFI boundary.
+    pub fn to_bits(&self) -> u32 {
+        let constraints = self.constraints.iter().fold(0, |acc, c| acc | bit(*c));
+        let or = match self.combinator {
+            Combinator::And => 0,
+            Combinator::Or => OR,
+        };
+        let accessibility = ACCESSIBILITIES
+            .iter()
+            .position(|a| *a == self.accessibility)
+            .unwrap_or(0) as u32;
+        constraints | or | accessibility << ACCESSIBILITY_SHIFT
+    }
+
+    /// Decode a policy received across the FFI boundary.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if any unknown bit is set.
+    pub fn from_bits(bits: u32) -> Result<Self, Error> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                &format!("Invalid access policy: {:#x}", bits),
+            )
+        };
+        if bits & !(CONSTRAINTS_MASK | OR | ACCESSIBILITY_MASK) != 0 {
+            return Err(invalid());
+        }
+        let accessibility = *ACCESSIBILITIES
+            .get(((bits & ACCESSIBILITY_MASK) >> ACCESSIBILITY_SHIFT) as usize)
+            .ok_or_else(invalid)?;
+        let constraints = CONSTRAINTS
+            .iter()
+            .filter(|(_, b)| bits & b != 0)
+            .map(|(c, _)| *c)
+            .collect();
+        let combinator = if bits & OR != 0 {
+            Combinator::Or
+        } else {
+            Combinator::And
+        };
+        Ok(AccessPolicy {
+            accessibility,
+            constraints,
+            combinator,
+        })
+    }
+}
+
+fn bit(constraint: Constraint) -> u32 {
+    CONSTRAINTS
+        .iter()
+        .find(|(c, _)| *c == constraint)
+        .map(|(_, b)| *b)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_flags() {
+        assert_eq!(AccessPolicy::from_bits(0).unwrap(), AccessPolicy::default());
+        assert_eq!(
+            AccessPolicy::from_bits(1).unwrap(),
+            AccessPolicy::biometry()
+        );
+    }
+
+    #[test]
+    fn test_bits_round_trip() {
+        for accessibility in ACCESSIBILITIES.iter() {
+            for constraints in 0..=CONSTRAINTS_MASK {
+                for or in &[0, OR] {
+                    let bits = constraints
+                        | or
+                        | (ACCESSIBILITIES
+                            .iter()
+                            .position(|a| a == accessibility)
+                            .unwrap() as u32)
+                            << ACCESSIBILITY_SHIFT;
+                    let policy = AccessPolicy::from_bits(bits).unwrap();
+                    assert_eq!(policy.accessibility(), *accessibility);
+                    assert_eq!(policy.to_bits(), bits);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_bits_are_rejected() {
+        for bits in &[1 << 5, 1 << 12, 5 << ACCESSIBILITY_SHIFT, 0xffff_ffff] {
+            let error = AccessPolicy::from_bits(*bits).unwrap_err();
+            assert_eq!(error.kind(), &ErrorKind::InvalidData);
+        }
+    }
+}
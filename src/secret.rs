@@ -0,0 +1,206 @@
+//! Buffers holding secret values
+//!
+//! Values read from a store are handed out as `SecretBytes` or
+//! `SecretString`. They overwrite their memory with zeros when dropped and
+//! implement neither `Debug` nor `Display`, so a value can't end up in a log
+//! by accident:
+//!
+//! ```compile_fail
+//! let secret = secure_native::SecretString::from("hunter2");
+//! println!("{:?}", secret);
+//! ```
+//!
+//! Only memory owned by the library is wiped. Copies held by the platform,
+//! such as Java strings and the keychain's own buffers, are out of its reach.
+
+use crate::store::constant_time_eq;
+use crate::{Error, ErrorKind};
+
+use std::{cmp, io, mem, ops::Deref};
+use zeroize::Zeroize;
+
+/// Bytes wiped when dropped.
+#[derive(Clone, Default)]
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The bytes, which the caller becomes responsible for wiping.
+    pub(crate) fn into_vec(mut self) -> Vec<u8> {
+        mem::take(&mut self.0)
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        SecretBytes(bytes)
+    }
+}
+
+impl From<&[u8]> for SecretBytes {
+    fn from(bytes: &[u8]) -> Self {
+        SecretBytes(bytes.to_vec())
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for SecretBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Compares in time independent of where the values differ.
+impl PartialEq for SecretBytes {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SecretBytes {}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// UTF-8 text wiped when dropped.
+#[derive(Clone, Default)]
+pub struct SecretString(String);
+
+impl SecretString {
+    /// Interpret `bytes` as UTF-8 without copying them.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if they are not valid UTF-8.
+    pub fn from_utf8(bytes: SecretBytes) -> Result<Self, Error> {
+        String::from_utf8(bytes.into_vec())
+            .map(SecretString)
+            .map_err(|e| {
+                let error = Error::new(ErrorKind::InvalidData, &e.utf8_error());
+                drop(SecretBytes(e.into_bytes()));
+                error
+            })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_bytes(mut self) -> SecretBytes {
+        SecretBytes(mem::take(&mut self.0).into_bytes())
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(s: String) -> Self {
+        SecretString(s)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(s: &str) -> Self {
+        SecretString(s.to_string())
+    }
+}
+
+impl Deref for SecretString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for SecretString {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Compares in time independent of where the values differ.
+impl PartialEq for SecretString {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(self.0.as_bytes(), other.0.as_bytes())
+    }
+}
+
+impl Eq for SecretString {}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Buffer secrets are serialized into. Growing it wipes the memory it
+/// moves out of.
+#[derive(Default)]
+pub(crate) struct SecretWriter(SecretBytes);
+
+impl SecretWriter {
+    pub(crate) fn into_bytes(self) -> SecretBytes {
+        self.0
+    }
+}
+
+impl io::Write for SecretWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes = &mut (self.0).0;
+        if bytes.capacity() - bytes.len() < buf.len() {
+            // Moved by hand, as a reallocation would leave the old copy behind
+            let capacity = cmp::max(bytes.len() + buf.len(), 2 * bytes.capacity());
+            let mut grown = Vec::with_capacity(capacity);
+            grown.extend_from_slice(bytes);
+            bytes.zeroize();
+            *bytes = grown;
+        }
+        bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        let bytes = SecretBytes::from(&b"secret"[..]);
+        let s = SecretString::from_utf8(bytes.clone()).unwrap();
+        assert_eq!(s.as_str(), "secret");
+        assert!(s.into_bytes() == bytes);
+        let error = SecretString::from_utf8(SecretBytes::from(vec![0xff]))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), &ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_writer_grows() {
+        use std::io::Write;
+
+        let mut writer = SecretWriter::default();
+        let mut expected = Vec::new();
+        for i in 0..100u8 {
+            let chunk = vec![i; usize::from(i % 7)];
+            writer.write_all(&chunk).unwrap();
+            expected.extend_from_slice(&chunk);
+        }
+        assert_eq!(writer.into_bytes().as_bytes(), &expected[..]);
+    }
+}
@@ -0,0 +1,385 @@
+//! Platform-neutral storage interface
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::GuardedBytes;
+use crate::{lock_item, AccessPolicy, Attributes, Error, ErrorKind, SecretBytes, SecretString};
+
+#[cfg(feature = "typed")]
+use crate::typed::Record;
+#[cfg(feature = "typed")]
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// SHA-256 digest of a value, as expected by the conditional writes of
+/// `SecureStore`.
+pub fn digest(value: &[u8]) -> [u8; 32] {
+    Sha256::digest(value).into()
+}
+
+/// Whether `value` has the digest `expected`, in time independent of where
+/// they differ.
+fn digest_matches(value: &[u8], expected: &[u8]) -> bool {
+    constant_time_eq(&digest(value), expected)
+}
+
+/// Whether `a` equals `b`, in time independent of where they differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// An item found by `SecureStore::list`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AccountInfo {
+    pub service: String,
+    pub account: String,
+}
+
+/// A write staged in a `Transaction`.
+#[derive(Clone, PartialEq)]
+pub enum Write {
+    Put {
+        service: String,
+        account: String,
+        value: SecretBytes,
+        policy: AccessPolicy,
+    },
+    Delete {
+        service: String,
+        account: String,
+    },
+}
+
+/// Leaves out the value.
+impl fmt::Debug for Write {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Write::Put {
+                service,
+                account,
+                policy,
+                ..
+            } => f
+                .debug_struct("Put")
+                .field("service", service)
+                .field("account", account)
+                .field("policy", policy)
+                .finish(),
+            Write::Delete { service, account } => f
+                .debug_struct("Delete")
+                .field("service", service)
+                .field("account", account)
+                .finish(),
+        }
+    }
+}
+
+impl Write {
+    pub fn service(&self) -> &str {
+        match self {
+            Write::Put { service, .. } | Write::Delete { service, .. } => service,
+        }
+    }
+
+    pub fn account(&self) -> &str {
+        match self {
+            Write::Put { account, .. } | Write::Delete { account, .. } => account,
+        }
+    }
+}
+
+/// The last write to each item among `writes`, in the order they were staged.
+/// Applying them has the same effect as applying every write in turn.
+pub fn last_writes(writes: &[Write]) -> Vec<&Write> {
+    writes
+        .iter()
+        .enumerate()
+        .filter(|(i, write)| {
+            !writes[i + 1..]
+                .iter()
+                .any(|w| w.service() == write.service() && w.account() == write.account())
+        })
+        .map(|(_, write)| write)
+        .collect()
+}
+
+/// Writes to several items which are committed together: either all of them
+/// take effect or none do.
+///
+/// ```ignore
+/// store
+///     .transaction()
+///     .put("service", "token", &token, &policy)
+///     .put("service", "refresh", &refresh, &policy)
+///     .delete("service", "legacy-token")
+///     .commit()?;
+/// ```
+pub struct Transaction<'s, S: SecureStore> {
+    store: &'s S,
+    writes: Vec<Write>,
+}
+
+impl<'s, S: SecureStore> Transaction<'s, S> {
+    /// Stage storing `value` for the pair. Later writes to the same pair
+    /// replace earlier ones.
+    pub fn put_bytes(
+        mut self,
+        service: &str,
+        account: &str,
+        value: &[u8],
+        policy: &AccessPolicy,
+    ) -> Self {
+        self.writes.push(Write::Put {
+            service: service.to_string(),
+            account: account.to_string(),
+            value: SecretBytes::from(value),
+            policy: policy.clone(),
+        });
+        self
+    }
+
+    /// Stage storing a string for the pair.
+    pub fn put(self, service: &str, account: &str, value: &str, policy: &AccessPolicy) -> Self {
+        self.put_bytes(service, account, value.as_bytes(), policy)
+    }
+
+    /// Stage removing the item stored for the pair, if any.
+    pub fn delete(mut self, service: &str, account: &str) -> Self {
+        self.writes.push(Write::Delete {
+            service: service.to_string(),
+            account: account.to_string(),
+        });
+        self
+    }
+
+    /// The writes staged so far.
+    pub fn writes(&self) -> &[Write] {
+        &self.writes
+    }
+
+    /// Apply every staged write, or none of them. See `SecureStore::apply`.
+    pub fn commit(self) -> Result<(), Error> {
+        self.store.apply(&self.writes)
+    }
+}
+
+/// Common interface over the platform secure storage backends.
+///
+/// Items are addressed by a `service`/`account` pair. Code written against
+/// this trait runs unchanged on every backend, which lets shared logic be
+/// exercised on the host with a test implementation.
+pub trait SecureStore {
+    /// Backend-specific data needed to reach the underlying store.
+    type Config;
+
+    /// Open a handle to the store described by `config`.
+    fn open(config: Self::Config) -> Self
+    where
+        Self: Sized;
+
+    /// Store `value` protected by `policy`, replacing any existing item for
+    /// the same pair. A replaced item keeps its attributes.
+    fn put_bytes(
+        &self,
+        service: &str,
+        account: &str,
+        value: &[u8],
+        policy: &AccessPolicy,
+    ) -> Result<(), Error>;
+
+    /// Retrieve the value stored for the pair.
+    ///
+    /// Fails with `ErrorKind::NotFound` if there is none.
+    fn get_bytes(&self, service: &str, account: &str) -> Result<SecretBytes, Error>;
+
+    /// Store a UTF-8 `value`, replacing any existing item for the same pair.
+    fn put(
+        &self,
+        service: &str,
+        account: &str,
+        value: &str,
+        policy: &AccessPolicy,
+    ) -> Result<(), Error> {
+        self.put_bytes(service, account, value.as_bytes(), policy)
+    }
+
+    /// Retrieve the value stored for the pair, which must be valid UTF-8.
+    fn get(&self, service: &str, account: &str) -> Result<SecretString, Error> {
+        self.get_bytes(service, account)
+            .and_then(SecretString::from_utf8)
+    }
+
+    /// Retrieve the value stored for the pair into locked, guarded pages (see
+    /// `GuardedBytes`), wiping the buffer it was read into.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn get_guarded(&self, service: &str, account: &str) -> Result<GuardedBytes, Error> {
+        GuardedBytes::from_secret(self.get_bytes(service, account)?)
+    }
+
+    /// Check whether an item is stored for the pair, without reading it.
+    fn contains(&self, service: &str, account: &str) -> Result<bool, Error>;
+
+    /// Remove the item stored for the pair. Removing a missing item succeeds.
+    fn delete(&self, service: &str, account: &str) -> Result<(), Error>;
+
+    /// Remove every item stored under `service`, returning those which could
+    /// not be removed.
+    fn delete_all(&self, service: &str) -> Result<Vec<AccountInfo>, Error> {
+        let mut remaining = Vec::new();
+        for item in self.list(service)? {
+            if self.delete(&item.service, &item.account).is_err() {
+                remaining.push(item);
+            }
+        }
+        Ok(remaining)
+    }
+
+    /// Remove every item the backend has stored, returning those which could
+    /// not be removed.
+    fn wipe(&self) -> Result<Vec<AccountInfo>, Error>;
+
+    /// Store `value` unless an item is already stored for the pair. Returns
+    /// whether it was stored.
+    ///
+    /// Like the other conditional writes, this holds the pair's lock (see
+    /// `lock_item`), so no other write through the library interleaves.
+    fn put_if_absent(
+        &self,
+        service: &str,
+        account: &str,
+        value: &[u8],
+        policy: &AccessPolicy,
+    ) -> Result<bool, Error> {
+        let _guard = lock_item(service, account);
+        if self.contains(service, account)? {
+            return Ok(false);
+        }
+        self.put_bytes(service, account, value, policy)?;
+        Ok(true)
+    }
+
+    /// Replace the stored value with `value` if its `digest` is
+    /// `expected_digest`. Returns whether it was replaced; a missing item is
+    /// never replaced.
+    fn replace_if_equals(
+        &self,
+        service: &str,
+        account: &str,
+        expected_digest: &[u8],
+        value: &[u8],
+        policy: &AccessPolicy,
+    ) -> Result<bool, Error> {
+        let _guard = lock_item(service, account);
+        match self.get_bytes(service, account) {
+            Ok(ref current) if digest_matches(current, expected_digest) => {
+                self.put_bytes(service, account, value, policy)?;
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(ref e) if e.kind() == &ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Remove the item if the `digest` of its value is `expected_digest`.
+    /// Returns whether it was removed.
+    fn delete_if_equals(
+        &self,
+        service: &str,
+        account: &str,
+        expected_digest: &[u8],
+    ) -> Result<bool, Error> {
+        let _guard = lock_item(service, account);
+        match self.get_bytes(service, account) {
+            Ok(ref current) if digest_matches(current, expected_digest) => {
+                self.delete(service, account)?;
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(ref e) if e.kind() == &ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Metadata of the item stored for the pair. Reading it never decrypts
+    /// the value or asks the user to authenticate.
+    ///
+    /// Fails with `ErrorKind::NotFound` if there is none.
+    fn get_attributes(&self, service: &str, account: &str) -> Result<Attributes, Error>;
+
+    /// Replace the label, description and custom attributes of the item
+    /// stored for the pair. Its times are kept by the store, so those in
+    /// `attributes` are ignored.
+    ///
+    /// Fails with `ErrorKind::NotFound` if there is none, and with
+    /// `ErrorKind::InvalidData` if the custom attributes are larger than
+    /// `MAX_CUSTOM_LEN`.
+    fn set_attributes(
+        &self,
+        service: &str,
+        account: &str,
+        attributes: &Attributes,
+    ) -> Result<(), Error>;
+
+    /// Store `value` serialized as CBOR, tagged with the application's
+    /// `schema` (see `typed`).
+    #[cfg(feature = "typed")]
+    fn put_typed<T: Serialize>(
+        &self,
+        service: &str,
+        account: &str,
+        value: &T,
+        schema: u32,
+        policy: &AccessPolicy,
+    ) -> Result<(), Error>
+    where
+        Self: Sized,
+    {
+        let record = Record::new(value, schema)?;
+        self.put_bytes(service, account, &record.encode(), policy)
+    }
+
+    /// Retrieve a value stored by `put_typed`.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if it was stored with another
+    /// schema or doesn't fit `T`. Use `get_record` to migrate older records.
+    #[cfg(feature = "typed")]
+    fn get_typed<T: DeserializeOwned>(
+        &self,
+        service: &str,
+        account: &str,
+        schema: u32,
+    ) -> Result<T, Error>
+    where
+        Self: Sized,
+    {
+        self.get_record(service, account)?.value_of_schema(schema)
+    }
+
+    /// Retrieve a value stored by `put_typed` without deserializing it, to
+    /// look at its schema.
+    #[cfg(feature = "typed")]
+    fn get_record(&self, service: &str, account: &str) -> Result<Record, Error> {
+        Record::decode(&self.get_bytes(service, account)?)
+    }
+
+    /// Start staging writes to be committed together.
+    fn transaction(&self) -> Transaction<'_, Self>
+    where
+        Self: Sized,
+    {
+        Transaction {
+            store: self,
+            writes: Vec::new(),
+        }
+    }
+
+    /// Apply `writes` in order, so that either all of them take effect or
+    /// none do, even if the process dies part way. Holds the lock of every
+    /// item written (see `lock_item`) while applying them.
+    fn apply(&self, writes: &[Write]) -> Result<(), Error>;
+
+    /// Every item stored under `service`, sorted by account. Values are not
+    /// read, so listing never asks the user to authenticate.
+    fn list(&self, service: &str) -> Result<Vec<AccountInfo>, Error>;
+}
@@ -0,0 +1,22 @@
+//! Helpers shared by the unit tests
+
+/// Pseudo-random inputs of up to 64 bytes for decoders, the same on every
+/// run. About half of those long enough start with `header`, so they get
+/// past the first checks of the decoder.
+pub fn arbitrary_inputs(header: &[u8]) -> Vec<Vec<u8>> {
+    // xorshift, so the inputs are the same on every run
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut inputs = Vec::new();
+    for _ in 0..10_000 {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let len = (state % 64) as usize;
+        let mut bytes: Vec<u8> = (0..len).map(|i| (state >> (i % 8 * 8)) as u8).collect();
+        if len > header.len() && state & 1 == 0 {
+            bytes[..header.len()].copy_from_slice(header);
+        }
+        inputs.push(bytes);
+    }
+    inputs
+}
@@ -0,0 +1,185 @@
+//! Structured values
+//!
+//! `SecureStore::put_typed` serializes a value as CBOR and stores it behind a
+//! small header:
+//!
+//! ```text
+//! version: u8 | schema: u32 | CBOR
+//! ```
+//!
+//! The schema is chosen by the application and names the layout of the
+//! serialized type. Bump it whenever the layout changes incompatibly; records
+//! of an older schema are still readable through `SecureStore::get_record`,
+//! so they can be converted and stored again. Integers are little-endian.
+//!
+//! Values cross the FFI boundary as JSON, wrapped in `Json`.
+
+use crate::secret::SecretWriter;
+use crate::{Error, ErrorKind, SecretBytes, SecretString};
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::convert::TryInto;
+
+/// Current record format version.
+pub const VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 5;
+
+/// A stored structured value, not yet deserialized.
+#[derive(Clone, PartialEq)]
+pub struct Record {
+    schema: u32,
+    body: SecretBytes,
+}
+
+impl Record {
+    /// Serialize `value` with the application's `schema`.
+    pub fn new<T: Serialize>(value: &T, schema: u32) -> Result<Self, Error> {
+        let mut body = SecretWriter::default();
+        ciborium::ser::into_writer(value, &mut body).map_err(|e| invalid(&e))?;
+        Ok(Record {
+            schema,
+            body: body.into_bytes(),
+        })
+    }
+
+    /// Schema the value was stored with.
+    pub fn schema(&self) -> u32 {
+        self.schema
+    }
+
+    /// Deserialize the value, whatever its schema.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if it doesn't fit `T`.
+    pub fn value<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        ciborium::de::from_reader(&self.body[..]).map_err(|e| invalid(&e))
+    }
+
+    /// Deserialize the value, checking that it was stored with `schema`.
+    pub fn value_of_schema<T: DeserializeOwned>(&self, schema: u32) -> Result<T, Error> {
+        if self.schema != schema {
+            return Err(invalid(&format!(
+                "Item has schema {}, expected {}",
+                self.schema, schema
+            )));
+        }
+        self.value()
+    }
+
+    /// Serialize the record in the current format version.
+    pub fn encode(&self) -> SecretBytes {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.body.len());
+        out.push(VERSION);
+        out.extend_from_slice(&self.schema.to_le_bytes());
+        out.extend_from_slice(&self.body);
+        SecretBytes::from(out)
+    }
+
+    /// Parse a record.
+    ///
+    /// Fails with `ErrorKind::InvalidData` on unknown versions and on
+    /// truncated headers. The body is only checked when deserialized.
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < HEADER_LEN {
+            return Err(invalid("Truncated typed value"));
+        }
+        if bytes[0] != VERSION {
+            return Err(invalid(&format!(
+                "Unsupported typed value version: {}",
+                bytes[0]
+            )));
+        }
+        Ok(Record {
+            schema: u32::from_le_bytes(bytes[1..HEADER_LEN].try_into().unwrap()),
+            body: SecretBytes::from(&bytes[HEADER_LEN..]),
+        })
+    }
+}
+
+/// A value crossing the FFI boundary as a JSON string.
+///
+/// Usable as an argument or a return type of the exported functions. Like
+/// the other secret types, it doesn't implement `Debug`.
+#[derive(Clone, Default, PartialEq)]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> Json<T> {
+    /// The value as JSON text.
+    pub fn to_json(&self) -> Result<SecretString, Error> {
+        let mut json = SecretWriter::default();
+        serde_json::to_writer(&mut json, &self.0).map_err(|e| invalid(&e))?;
+        SecretString::from_utf8(json.into_bytes())
+    }
+}
+
+impl<T: DeserializeOwned> Json<T> {
+    /// Parse JSON text.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        serde_json::from_str(json)
+            .map(Json)
+            .map_err(|e| invalid(&e))
+    }
+}
+
+fn invalid<D: ToString + ?Sized>(description: &D) -> Error {
+    Error::new(ErrorKind::InvalidData, description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn value() -> BTreeMap<String, Vec<u32>> {
+        let mut value = BTreeMap::new();
+        value.insert("primes".to_string(), vec![2, 3, 5]);
+        value.insert("empty".to_string(), vec![]);
+        value
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let record = Record::new(&value(), 3).unwrap();
+        let decoded = Record::decode(&record.encode()).unwrap();
+        assert_eq!(decoded.schema(), 3);
+        assert_eq!(
+            decoded.value_of_schema::<BTreeMap<_, _>>(3).unwrap(),
+            value()
+        );
+    }
+
+    #[test]
+    fn test_mismatches_are_rejected() {
+        let bytes = Record::new(&value(), 3).unwrap().encode().to_vec();
+        for len in 0..HEADER_LEN {
+            let error = Record::decode(&bytes[..len]).err().unwrap();
+            assert_eq!(error.kind(), &ErrorKind::InvalidData);
+        }
+        let mut unknown = bytes.clone();
+        unknown[0] = 2;
+        assert_eq!(
+            Record::decode(&unknown).err().unwrap().kind(),
+            &ErrorKind::InvalidData
+        );
+        let record = Record::decode(&bytes).unwrap();
+        let error = record
+            .value_of_schema::<BTreeMap<String, Vec<u32>>>(4)
+            .unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::InvalidData);
+        assert_eq!(
+            record.value::<String>().unwrap_err().kind(),
+            &ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let json = Json(value()).to_json().unwrap();
+        assert_eq!(&*json, r#"{"empty":[],"primes":[2,3,5]}"#);
+        assert!(Json::from_json(&json).unwrap() == Json(value()));
+        assert_eq!(
+            Json::<u32>::from_json("[1").err().unwrap().kind(),
+            &ErrorKind::InvalidData
+        );
+    }
+}